@@ -1,7 +1,8 @@
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
 use rand::{thread_rng, Rng};
+use tempfile::TempDir;
 
-use std::{path::Path, str::FromStr};
+use std::{path::Path, str::FromStr, thread};
 
 use hobbes::engine::{bitcask, sled_engine, Engine};
 
@@ -14,6 +15,8 @@ const TEST_KEY_FORMAT: &str = "KEY_";
 const TEST_VALUE_FORMAT: &str = "VALUE_";
 const SET_RUN_COUNT: usize = 500;
 const GET_RUN_COUNT: usize = 500;
+const KEYDIR_KEY_COUNT: u64 = 1_000_000;
+const READ_THREAD_COUNTS: [usize; 5] = [1, 2, 4, 8, 16];
 
 fn randomise(run_count: usize) -> Vec<(String, String)> {
     let mut test_vals: Vec<(String, String)> = Vec::with_capacity(run_count);
@@ -103,6 +106,112 @@ fn bench_get(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bench_set, bench_get);
-criterion_main!(benches);
+fn bench_keydir(c: &mut Criterion) {
+    let temp_dir = TempDir::new().expect("failed to create a temporary directory");
+    let hobbes_eng =
+        bitcask::BitcaskEngine::open(temp_dir.path()).expect("failed to start the hobbes engine");
+    hobbes_eng
+        .set_many((0..KEYDIR_KEY_COUNT).map(|i| {
+            (
+                format!("{TEST_KEY_FORMAT}{i}"),
+                format!("{TEST_VALUE_FORMAT}{i}"),
+            )
+        }))
+        .expect("failed to set the values in the hobbes engine");
+
+    // The per-key overhead is the index size beyond the key bytes themselves
+    let stats = hobbes_eng
+        .stats()
+        .expect("failed to read the hobbes engine statistics");
+    let index_bytes = stats
+        .index_bytes
+        .expect("the hobbes engine should report its index size");
+    let key_bytes: u64 = (0..KEYDIR_KEY_COUNT)
+        .map(|i| format!("{TEST_KEY_FORMAT}{i}").len() as u64)
+        .sum();
+    println!(
+        "hobbes keydir: {} keys, {index_bytes} index bytes, {:.1} bytes of overhead per key",
+        stats.keys,
+        (index_bytes - key_bytes) as f64 / stats.keys as f64
+    );
+    drop(hobbes_eng);
+
+    let mut group = c.benchmark_group("hobbes keydir");
+    group.sample_size(10);
+    group.bench_function("hobbes keydir rebuild bench", |b| {
+        b.iter(|| {
+            bitcask::BitcaskEngine::open(temp_dir.path())
+                .expect("failed to start the hobbes engine")
+        })
+    });
+    group.finish();
+}
+
+// Each thread reads every pair, so reads scale with the thread count when they run in parallel.
+// The writer variant rewrites every pair meanwhile, which reads should not wait for.
+fn bench_concurrent_get(c: &mut Criterion) {
+    let temp_dir = TempDir::new().expect("failed to create a temporary directory");
+    // Small segments spread the pairs over many sealed, memory-mapped segments
+    let hobbes_eng = bitcask::BitcaskOptions::new()
+        .segment_size(64 * 1024)
+        .open(temp_dir.path())
+        .expect("failed to start the hobbes engine");
+    let rand_vals = randomise(GET_RUN_COUNT);
+    for (key, val) in &rand_vals {
+        hobbes_eng
+            .set(key.clone(), val.clone())
+            .expect("failed to set the value in the hobbes engine");
+    }
 
+    let mut group = c.benchmark_group("hobbes concurrent get bench");
+    for (threads, writer) in READ_THREAD_COUNTS
+        .into_iter()
+        .flat_map(|threads| [(threads, false), (threads, true)])
+    {
+        let parameter = if writer {
+            format!("{threads} with writer")
+        } else {
+            threads.to_string()
+        };
+        group.throughput(Throughput::Elements((threads * rand_vals.len()) as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(parameter),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    thread::scope(|scope| {
+                        if writer {
+                            scope.spawn(|| {
+                                for (key, val) in &rand_vals {
+                                    hobbes_eng
+                                        .set(key.clone(), val.clone())
+                                        .expect("failed to set the value in the hobbes engine");
+                                }
+                            });
+                        }
+                        for _ in 0..threads {
+                            scope.spawn(|| {
+                                for (key, _) in &rand_vals {
+                                    hobbes_eng
+                                        .get(key.clone())
+                                        .expect("failed to get the value in the hobbes engine")
+                                        .expect("no value present for the key in hobbes");
+                                }
+                            });
+                        }
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_set,
+    bench_get,
+    bench_keydir,
+    bench_concurrent_get
+);
+criterion_main!(benches);
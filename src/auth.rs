@@ -0,0 +1,192 @@
+//! Users and permissions of servers requiring authentication
+//!
+//! Users are defined in a TOML file, each holding an Argon2 hash of its password and optionally
+//! of an API token accepted in its place:
+//!
+//! ```toml
+//! [[users]]
+//! name = "alice"
+//! password_hash = "$argon2id$v=19$m=19456,t=2,p=1$..."
+//! role = "read-write"
+//! prefixes = ["orders:", "users:"]
+//! ```
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use blake2::{Blake2b512, Digest};
+use serde::Deserialize;
+use tracing::warn;
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::{HobbesError, Result};
+
+const MUTEX_ERROR: &str = "Failed to lock Mutex";
+
+/// Operations a user may perform, each role including the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Read keys and server statistics
+    ReadOnly,
+    /// Additionally set and remove keys
+    ReadWrite,
+    /// Additionally replicate from the server and take part in its cluster
+    Admin,
+}
+
+/// Access required by a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Admin,
+}
+
+/// A user defined in the users file
+#[derive(Debug, Clone, Deserialize)]
+pub struct User {
+    pub name: String,
+    /// Argon2 hash of the user's password, in PHC string format
+    #[serde(default)]
+    pub password_hash: Option<String>,
+    /// Argon2 hash of an API token accepted in place of the password
+    #[serde(default)]
+    pub token_hash: Option<String>,
+    pub role: Role,
+    /// Prefixes of the keys the user may access, granting every key if empty
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UsersFile {
+    #[serde(default)]
+    users: Vec<User>,
+}
+
+impl User {
+    /// Check whether the user may perform a command requiring the access. Key commands pass
+    /// their key, or the prefix of the keys scanned or watched, which must fall within the
+    /// user's prefixes.
+    pub fn permits(&self, access: Access, key: Option<&str>) -> bool {
+        let in_scope = match key {
+            Some(key) => {
+                self.prefixes.is_empty()
+                    || self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+            }
+            None => true,
+        };
+
+        match access {
+            Access::Read => in_scope,
+            Access::Write => self.role >= Role::ReadWrite && in_scope,
+            // Admin commands expose every key, so they are refused to users scoped to prefixes
+            Access::Admin => self.role == Role::Admin && self.prefixes.is_empty(),
+        }
+    }
+}
+
+/// Authenticator verifies the credentials presented by clients against the users file
+#[derive(Debug)]
+pub struct Authenticator {
+    users: HashMap<String, User>,
+    // Argon2 is deliberately slow, and every command opens a new connection, so digests of
+    // verified secrets are cached. The digests are salted with a per-process key and
+    // never leave memory.
+    verified: Mutex<HashMap<String, HashSet<Vec<u8>>>>,
+    cache_key: [u8; 32],
+}
+
+impl Authenticator {
+    /// Load the users from a TOML users file
+    pub fn load(path: &Path) -> Result<Authenticator> {
+        let contents = fs::read_to_string(path)?;
+        let users_file: UsersFile = toml::from_str(&contents)
+            .map_err(|e| HobbesError::AuthError(format!("invalid users file {path:?}, {e}")))?;
+
+        let mut users = HashMap::new();
+        for user in users_file.users {
+            for hash in [&user.password_hash, &user.token_hash]
+                .into_iter()
+                .flatten()
+            {
+                PasswordHash::new(hash).map_err(|e| {
+                    HobbesError::AuthError(format!(
+                        "invalid password hash for user {}, {e}",
+                        user.name
+                    ))
+                })?;
+            }
+            if user.password_hash.is_none() && user.token_hash.is_none() {
+                warn!(
+                    user = user.name,
+                    "User has no password or token and cannot log in"
+                );
+            }
+            if let Some(duplicate) = users.insert(user.name.clone(), user) {
+                Err(HobbesError::AuthError(format!(
+                    "user {} is defined more than once",
+                    duplicate.name
+                )))?
+            }
+        }
+
+        Ok(Authenticator {
+            users,
+            verified: Mutex::new(HashMap::new()),
+            cache_key: rand::random(),
+        })
+    }
+
+    /// Return the user if the secret matches its password or token
+    pub fn authenticate(&self, name: &str, secret: &str) -> Option<&User> {
+        let user = self.users.get(name)?;
+
+        let digest = Blake2b512::new()
+            .chain_update(self.cache_key)
+            .chain_update(secret.as_bytes())
+            .finalize()
+            .to_vec();
+        let mut verified = self.verified.lock().expect(MUTEX_ERROR);
+        if verified
+            .get(name)
+            .is_some_and(|digests| digests.contains(&digest))
+        {
+            return Some(user);
+        }
+        drop(verified);
+
+        let matches = [&user.password_hash, &user.token_hash]
+            .into_iter()
+            .flatten()
+            .any(|hash| verify_secret(secret, hash));
+        if !matches {
+            return None;
+        }
+
+        verified = self.verified.lock().expect(MUTEX_ERROR);
+        verified.entry(name.to_string()).or_default().insert(digest);
+        Some(user)
+    }
+}
+
+/// Hash a password or token for the users file
+pub fn hash_secret(secret: &str) -> Result<String> {
+    Argon2::default()
+        .hash_password(secret.as_bytes(), &SaltString::generate(&mut OsRng))
+        .map(|hash| hash.to_string())
+        .map_err(|e| HobbesError::AuthError(format!("failed to hash secret, {e}")))
+}
+
+fn verify_secret(secret: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(secret.as_bytes(), &hash)
+            .is_ok()
+    })
+}
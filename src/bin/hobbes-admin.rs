@@ -0,0 +1,348 @@
+use chrono::{DateTime, Local};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use hobbes::bulk::{Format, PairReader};
+use hobbes::config::{FileConfig, DEFAULT_CONFIG_PATH};
+use hobbes::engine::bitcask::{self, Keyring, SegmentReader};
+use hobbes::engine::{Engine, ServerConfig};
+use hobbes::{HobbesError, Result};
+
+// Offline imports append this many pairs to the log per write
+const IMPORT_BATCH_SIZE: usize = 100_000;
+
+fn main() -> Result<()> {
+    let cmd = cli().get_matches();
+
+    match cmd.subcommand() {
+        Some(("check", sub_matches)) => {
+            if let Err(err) = check(sub_matches) {
+                exit_with_error(err);
+            }
+        }
+        Some(("dump", sub_matches)) => {
+            if let Err(err) = dump(sub_matches) {
+                exit_with_error(err);
+            }
+        }
+        Some(("import", sub_matches)) => {
+            if let Err(err) = import(sub_matches) {
+                exit_with_error(err);
+            }
+        }
+        _ => eprintln!("Invalid command"),
+    }
+
+    Ok(())
+}
+
+fn check(args: &ArgMatches) -> Result<()> {
+    let dir = args
+        .get_one::<PathBuf>("dir")
+        .ok_or_else(|| HobbesError::CliError(String::from("failed to parse argument \"dir\"")))?;
+    let repair = args.get_flag("repair");
+    let keyring = keyring_arg(args, &load_config(args)?)?;
+
+    let report = bitcask::check(dir, keyring.as_ref(), repair)?;
+
+    for segment in &report.segments {
+        let name = segment
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| segment.log_id.to_string());
+        match &segment.problem {
+            None => println!(
+                "segment {name}: {} records, {} bytes",
+                segment.records, segment.size
+            ),
+            Some(problem) => println!(
+                "segment {name}: {} records, {} bytes, {} record at offset {} leaving {} bytes unreadable: {}",
+                segment.records,
+                segment.size,
+                if problem.truncated { "truncated" } else { "corrupt" },
+                problem.offset,
+                problem.lost_bytes,
+                problem.reason
+            ),
+        }
+    }
+    println!(
+        "records: {} (live: {}, dead: {}, tombstones: {})",
+        report.records(),
+        report.live,
+        report.dead,
+        report.tombstones
+    );
+    for error in &report.index_errors {
+        println!("index error: {error}");
+    }
+    println!(
+        "index: {} keys rebuilt, {} errors",
+        report.live,
+        report.index_errors.len()
+    );
+
+    if report.repaired {
+        println!("repaired: truncated segments at their first bad record");
+    } else if report.is_healthy() {
+        println!("store is healthy");
+    } else {
+        Err(HobbesError::CliError(String::from(
+            "store has unreadable records, rerun with --repair to truncate them",
+        )))?
+    }
+    Ok(())
+}
+
+fn dump(args: &ArgMatches) -> Result<()> {
+    let segment = args.get_one::<PathBuf>("segment").ok_or_else(|| {
+        HobbesError::CliError(String::from("failed to parse argument \"segment\""))
+    })?;
+    let json = args.get_flag("json");
+    let prefix = args.get_one::<String>("prefix");
+    let since = args
+        .get_one::<String>("since")
+        .map(|t| parse_time(t))
+        .transpose()?;
+    let until = args
+        .get_one::<String>("until")
+        .map(|t| parse_time(t))
+        .transpose()?;
+
+    let mut reader = SegmentReader::open(segment, keyring_arg(args, &load_config(args)?)?)?;
+    loop {
+        let record = match reader.next_record() {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(err) => Err(HobbesError::CliError(format!(
+                "unreadable record at offset {}, {err}",
+                reader.offset()
+            )))?,
+        };
+
+        if prefix.is_some_and(|prefix| !record.key.starts_with(prefix.as_str()))
+            || since.is_some_and(|since| record.timestamp < since)
+            || until.is_some_and(|until| record.timestamp > until)
+        {
+            continue;
+        }
+
+        if json {
+            println!("{}", serde_json::to_string(&record)?);
+        } else {
+            let value = match &record.value {
+                Some(value) => format!("value={value:?}"),
+                None => String::from("tombstone"),
+            };
+            println!(
+                "offset={} size={} timestamp={} key={:?} {value}",
+                record.offset,
+                record.size,
+                record.timestamp.to_rfc3339(),
+                record.key
+            );
+        }
+    }
+    Ok(())
+}
+
+fn import(args: &ArgMatches) -> Result<()> {
+    let dir = args
+        .get_one::<PathBuf>("dir")
+        .ok_or_else(|| HobbesError::CliError(String::from("failed to parse argument \"dir\"")))?;
+    let path = args
+        .get_one::<PathBuf>("file")
+        .ok_or_else(|| HobbesError::CliError(String::from("failed to parse argument \"file\"")))?;
+    let format = match args.get_one::<String>("format") {
+        Some(format) => format.parse()?,
+        None => Format::from_path(path),
+    };
+
+    // The store is opened with the settings the server would use, so that records are
+    // compressed and encrypted as the server expects to read them
+    let file_config = load_config(args)?;
+    let mut options = file_config.apply(ServerConfig::new())?.bitcask;
+    if let Some(keyring) = keyring_arg(args, &file_config)? {
+        options = options.keyring(keyring);
+    }
+    let store = options.open(dir)?;
+
+    let input: Box<dyn BufRead> = if path == Path::new("-") {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(path)?))
+    };
+    let mut pairs = PairReader::new(input, format)?.peekable();
+    let mut imported = 0;
+    while pairs.peek().is_some() {
+        let batch = pairs
+            .by_ref()
+            .take(IMPORT_BATCH_SIZE)
+            .collect::<Result<Vec<(String, String)>>>()?;
+        imported += store.set_many(batch)?;
+        eprintln!("imported {imported} pairs");
+    }
+    println!("imported {imported} pairs into {}", dir.display());
+    Ok(())
+}
+
+/// Read the server's config file, as `hobbes-server` does
+fn load_config(args: &ArgMatches) -> Result<FileConfig> {
+    match args.get_one::<PathBuf>("config") {
+        Some(path) => FileConfig::load(path),
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+            FileConfig::load(Path::new(DEFAULT_CONFIG_PATH))
+        }
+        None => Ok(FileConfig::default()),
+    }
+}
+
+/// Keys are taken from the flag, then the config file, then the environment variable
+fn keyring_arg(args: &ArgMatches, file_config: &FileConfig) -> Result<Option<Keyring>> {
+    match args.get_one::<PathBuf>("encryption-key-file") {
+        Some(key_file) => Ok(Some(Keyring::from_file(key_file)?)),
+        None => match &file_config.bitcask.encryption_key_file {
+            Some(key_file) => Ok(Some(Keyring::from_file(key_file)?)),
+            None => Keyring::from_env(),
+        },
+    }
+}
+
+fn parse_time(time: &str) -> Result<DateTime<Local>> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|time| time.with_timezone(&Local))
+        .map_err(|e| {
+            HobbesError::CliError(format!(
+                "invalid time {time}, expected RFC 3339 such as 2024-01-31T12:00:00Z, {e}"
+            ))
+        })
+}
+
+fn exit_with_error(err: HobbesError) -> ! {
+    eprintln!("{err}");
+    process::exit(1);
+}
+
+fn cli() -> Command {
+    Command::new("hobbes-admin")
+        .name(env!("CARGO_BIN_NAME"))
+        .about("Offline maintenance of hobbes stores")
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            Command::new("check")
+                .about("verify every record of a bitcask store which is not open for writes")
+                .arg_required_else_help(true)
+                .arg(
+                    Arg::new("dir")
+                        .help("data directory of the store")
+                        .value_name("DIR")
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("repair")
+                        .help("truncate segments at their first corrupt or truncated record")
+                        .long("repair")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(config_arg())
+                .arg(encryption_key_file_arg()),
+        )
+        .subcommand(
+            Command::new("dump")
+                .about("print the records of a bitcask segment")
+                .arg_required_else_help(true)
+                .arg(
+                    Arg::new("segment")
+                        .help("segment file, such as bitcask-store/logs/1.db")
+                        .value_name("SEGMENT")
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("json")
+                        .help("print each record as JSON")
+                        .long("json")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("prefix")
+                        .help("print only records of keys starting with this prefix")
+                        .long("prefix")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("since")
+                        .help("print only records written at or after this RFC 3339 time")
+                        .long("since")
+                        .value_name("TIME")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("until")
+                        .help("print only records written at or before this RFC 3339 time")
+                        .long("until")
+                        .value_name("TIME")
+                        .num_args(1),
+                )
+                .arg(config_arg())
+                .arg(encryption_key_file_arg()),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("store the key-value pairs of a file in a bitcask store which is not open")
+                .arg_required_else_help(true)
+                .arg(
+                    Arg::new("file")
+                        .help("file to read, reading from stdin if -")
+                        .value_name("FILE")
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("dir")
+                        .help("data directory of the store, created if missing")
+                        .long("dir")
+                        .value_name("DIR")
+                        .required(true)
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("format")
+                        .help("file format, inferred from the file extension if omitted [default: jsonl]")
+                        .long("format")
+                        .num_args(1)
+                        .value_parser(["jsonl", "csv"]),
+                )
+                .arg(config_arg())
+                .arg(encryption_key_file_arg()),
+        )
+}
+
+fn config_arg() -> Arg {
+    Arg::new("config")
+        .help("read store settings from this TOML file [default: hobbes.toml if present]")
+        .long("config")
+        .value_name("PATH")
+        .num_args(1)
+        .env("HOBBES_CONFIG")
+        .value_parser(clap::value_parser!(PathBuf))
+}
+
+fn encryption_key_file_arg() -> Arg {
+    Arg::new("encryption-key-file")
+        .help("decrypt records with the keys in this file [default: HOBBES_ENCRYPTION_KEY]")
+        .long("encryption-key-file")
+        .value_name("PATH")
+        .num_args(1)
+        .value_parser(clap::value_parser!(PathBuf))
+}
@@ -1,15 +1,26 @@
-use clap::{Arg, Command};
-use tracing::trace;
+use clap::{Arg, ArgAction, ArgMatches, Command};
 use tracing_subscriber::fmt::time;
 use tracing_subscriber::FmtSubscriber;
 
 use std::env;
-use std::io::{self, Read, Write};
-use std::net::TcpStream;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::process;
 
+use hobbes::auth;
+use hobbes::bulk::{Format, PairReader, PairWriter};
+use hobbes::client::Client;
+use hobbes::engine::{ReplicationInfo, ServerInfo};
+use hobbes::protocol::encode_command;
+use hobbes::tls::TlsConnector;
 use hobbes::{HobbesError, Result};
 
+// Imports report progress every this many pairs
+const IMPORT_PROGRESS_INTERVAL: usize = 10_000;
+// Exports fetch this many pairs per SCAN so the whole keyspace is never held in memory
+const EXPORT_PAGE_SIZE: usize = 1000;
+
 fn main() -> Result<()> {
     let logging_level = match env::var("LOG_LEVEL") {
         Ok(level) => match level.as_str() {
@@ -39,17 +50,36 @@ fn main() -> Result<()> {
         .ok_or_else(|| HobbesError::CliError(String::from("failed to parse argument \"addr\"")))?
         .to_string();
 
+    let mut client = Client::new(&addr);
+    if let Some(ca) = cmd.get_one::<PathBuf>("tls-ca") {
+        let identity = match (
+            cmd.get_one::<PathBuf>("tls-cert"),
+            cmd.get_one::<PathBuf>("tls-key"),
+        ) {
+            (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+            _ => None,
+        };
+        client = client.with_tls(TlsConnector::new(ca, identity)?);
+    }
+    if let Some(user) = cmd.get_one::<String>("user") {
+        let password = cmd.get_one::<String>("password").ok_or_else(|| {
+            HobbesError::CliError(String::from(
+                "missing password, set --password or HOBBES_PASSWORD",
+            ))
+        })?;
+        client = client.with_credentials(user, password);
+    }
+
     match cmd.subcommand() {
         Some(("get", sub_matches)) => {
             let key = sub_matches
                 .get_one::<String>("get")
                 .ok_or_else(|| HobbesError::CliError(String::from("Unable to parse arguments")))?;
 
-            let cmd = format!("GET\r\n{key}\r\n");
-            let resp = send_cmd(cmd, addr)?;
-            match resp.as_str() {
-                "Key not found" => println!("{resp}"),
-                resp => println!("{resp}"),
+            match client.get(key) {
+                Ok(Some(val)) => println!("{val}"),
+                Ok(None) => println!("Key not found"),
+                Err(err) => exit_with_error(err),
             }
         }
 
@@ -62,20 +92,96 @@ fn main() -> Result<()> {
                 "Missing value in SET command",
             )))?;
 
-            let cmd = format!("SET\r\n{key}\r\n{val}\r\n");
-            send_cmd(cmd, addr)?;
+            if let Err(err) = client.set(key, val) {
+                exit_with_error(err);
+            }
         }
 
         Some(("rm", sub_matches)) => {
             let key = sub_matches
                 .get_one::<String>("rm")
                 .ok_or_else(|| HobbesError::CliError(String::from("Unable to parse arguments")))?;
-            let cmd = format!("RM\r\n{key}\r\n");
-            let resp = send_cmd(cmd, addr)?;
-            if resp == "Key not found" {
-                eprintln!("{resp}");
-                process::exit(1);
+
+            if let Err(err) = client.remove(key) {
+                exit_with_error(err);
+            }
+        }
+
+        Some(("info", sub_matches)) => {
+            let server_info = client.info().unwrap_or_else(|err| exit_with_error(err));
+            if sub_matches.get_flag("json") {
+                println!("{}", serde_json::to_string(&server_info)?);
+            } else {
+                print_info(&server_info);
+            }
+        }
+
+        Some(("compact", _)) => {
+            if let Err(err) = client.compact() {
+                exit_with_error(err);
+            }
+        }
+
+        Some(("watch", sub_matches)) => {
+            let prefix = sub_matches
+                .get_one::<String>("prefix")
+                .map(|prefix| prefix.as_str())
+                .unwrap_or_default();
+            let json = sub_matches.get_flag("json");
+
+            let events = client
+                .watch(prefix)
+                .unwrap_or_else(|err| exit_with_error(err));
+            for event in events {
+                let event = event?;
+                if json {
+                    println!("{}", serde_json::to_string(&event)?);
+                } else {
+                    match event.val {
+                        Some(val) => println!("{} SET {} {val}", event.seq, event.key),
+                        None => println!("{} RM {}", event.seq, event.key),
+                    }
+                }
+            }
+        }
+
+        Some(("export", sub_matches)) => {
+            if let Err(err) = export(&client, sub_matches) {
+                exit_with_error(err);
+            }
+        }
+
+        Some(("import", sub_matches)) => {
+            if let Err(err) = import(&client, sub_matches) {
+                exit_with_error(err);
+            }
+        }
+
+        Some(("node", sub_matches)) => {
+            let cmd = match sub_matches.subcommand() {
+                Some(("ls", _)) => encode_command(&["NODES"]),
+                Some(("add", args)) => encode_command(&["ADDNODE", node_arg(args)?]),
+                Some(("rm", args)) => encode_command(&["RMNODE", node_arg(args)?]),
+                _ => Err(HobbesError::CliError(String::from("Invalid node command")))?,
+            };
+            match client.send_cmd(&cmd).and_then(|resp| resp.into_payload()) {
+                Ok(resp) => println!("{resp}"),
+                Err(err) => exit_with_error(err),
+            }
+        }
+
+        Some(("hash-password", _)) => {
+            // The secret is read from stdin rather than an argument, which would leave it in
+            // the shell history and the process list
+            let mut secret = String::new();
+            io::stdin().lock().read_line(&mut secret)?;
+            let secret = secret.trim_end_matches(['\r', '\n']);
+            if secret.is_empty() {
+                return Err(HobbesError::CliError(String::from(
+                    "expected a password or API token on stdin",
+                )));
             }
+            println!("{}", auth::hash_secret(secret)?);
         }
         _ => eprintln!("Invalid command"),
     }
@@ -83,6 +189,90 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+fn export(client: &Client, args: &ArgMatches) -> Result<()> {
+    let path = args
+        .get_one::<PathBuf>("file")
+        .filter(|path| *path != Path::new("-"));
+    let prefix = args
+        .get_one::<String>("prefix")
+        .map(|prefix| prefix.as_str())
+        .unwrap_or_default();
+    let format = match args.get_one::<String>("format") {
+        Some(format) => format.parse()?,
+        None => path.map_or(Format::Jsonl, |path| Format::from_path(path)),
+    };
+
+    let output: Box<dyn Write> = match path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    let mut writer = PairWriter::new(output, format, args.get_flag("base64"))?;
+    let mut exported = 0;
+    let mut after = None;
+    loop {
+        let page = client.scan_page(prefix, after.as_deref(), EXPORT_PAGE_SIZE)?;
+        let Some((last, _)) = page.last() else {
+            break;
+        };
+        after = Some(last.clone());
+        for (key, val) in &page {
+            writer.write(key, val)?;
+        }
+        exported += page.len();
+    }
+    writer.finish()?;
+    eprintln!("exported {exported} pairs");
+    Ok(())
+}
+
+fn import(client: &Client, args: &ArgMatches) -> Result<()> {
+    let path = args
+        .get_one::<PathBuf>("file")
+        .ok_or_else(|| HobbesError::CliError(String::from("failed to parse argument \"file\"")))?;
+    let batch_size = *args.get_one::<u64>("batch-size").ok_or_else(|| {
+        HobbesError::CliError(String::from("failed to parse argument \"batch-size\""))
+    })? as usize;
+    let format = match args.get_one::<String>("format") {
+        Some(format) => format.parse()?,
+        None => Format::from_path(path),
+    };
+
+    let input: Box<dyn BufRead> = if path == Path::new("-") {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(path)?))
+    };
+    let mut pairs = PairReader::new(input, format)?.peekable();
+    let mut imported = 0;
+    while pairs.peek().is_some() {
+        let batch = pairs
+            .by_ref()
+            .take(batch_size)
+            .collect::<Result<Vec<(String, String)>>>()?;
+        client.set_many(&batch)?;
+        imported += batch.len();
+        if imported % IMPORT_PROGRESS_INTERVAL < batch.len() {
+            eprintln!("imported {imported} pairs");
+        }
+    }
+    eprintln!("imported {imported} pairs in total");
+    Ok(())
+}
+
+fn node_arg(args: &ArgMatches) -> Result<&str> {
+    args.get_one::<String>("node")
+        .map(|node| node.as_str())
+        .ok_or_else(|| HobbesError::CliError(String::from("failed to parse argument \"node\"")))
+}
+
+fn exit_with_error(err: HobbesError) -> ! {
+    match err {
+        HobbesError::KeyNotFoundError => eprintln!("Key not found"),
+        err => eprintln!("{err}"),
+    }
+    process::exit(1);
+}
+
 fn cli() -> Command {
     Command::new("hobbes")
         .name(env!("CARGO_BIN_NAME"))
@@ -95,6 +285,43 @@ fn cli() -> Command {
                 .long("addr")
                 .default_value("127.0.0.1:4000"),
         )
+        .arg(
+            Arg::new("tls-ca")
+                .help("connect over TLS, verifying the server with the CA in this PEM file")
+                .long("tls-ca")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("tls-cert")
+                .help("present the certificate chain in this PEM file to servers verifying clients")
+                .long("tls-cert")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires_all(["tls-key", "tls-ca"]),
+        )
+        .arg(
+            Arg::new("tls-key")
+                .help("set the PEM file holding the private key of the client certificate")
+                .long("tls-key")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("tls-cert"),
+        )
+        .arg(
+            Arg::new("user")
+                .help("authenticate as this user with servers requiring authentication")
+                .long("user")
+                .value_name("USER"),
+        )
+        .arg(
+            Arg::new("password")
+                .help("set the password or API token of the user")
+                .long("password")
+                .value_name("PASSWORD")
+                .env("HOBBES_PASSWORD")
+                .hide_env_values(true),
+        )
         .subcommand_required(true)
         .subcommand(
             Command::new("get")
@@ -141,31 +368,200 @@ fn cli() -> Command {
                         .num_args(1),
                 ),
         )
+        .subcommand(
+            Command::new("info")
+                .about("display statistics about the server and storage engine")
+                .arg(
+                    Arg::new("json")
+                        .help("print the statistics as JSON")
+                        .long("json")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("compact").about("reclaim the space held by overwritten and removed keys"),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("stream changes to keys starting with a prefix")
+                .arg(
+                    Arg::new("prefix")
+                        .help("prefix of the keys to watch, watching every key if omitted")
+                        .value_name("PREFIX")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("json")
+                        .help("print each change as JSON")
+                        .long("json")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("node")
+                .about("manage the backends of a hobbes-proxy")
+                .subcommand_required(true)
+                .subcommand(Command::new("ls").about("list the backends of the proxy"))
+                .subcommand(
+                    Command::new("add")
+                        .about("add a backend, migrating the keys it now owns")
+                        .arg_required_else_help(true)
+                        .arg(
+                            Arg::new("node")
+                                .help("address of the backend")
+                                .value_name("HOST:PORT")
+                                .num_args(1),
+                        ),
+                )
+                .subcommand(
+                    Command::new("rm")
+                        .about("remove a backend, migrating its keys to the remaining backends")
+                        .arg_required_else_help(true)
+                        .arg(
+                            Arg::new("node")
+                                .help("address of the backend")
+                                .value_name("HOST:PORT")
+                                .num_args(1),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("write every key-value pair starting with a prefix to a file")
+                .arg(
+                    Arg::new("file")
+                        .help("file to write, writing to stdout if omitted or -")
+                        .value_name("FILE")
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("prefix")
+                        .help("export only keys starting with this prefix")
+                        .long("prefix")
+                        .num_args(1),
+                )
+                .arg(format_arg())
+                .arg(
+                    Arg::new("base64")
+                        .help("base64-encode values, which are always UTF-8 text")
+                        .long("base64")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("store the key-value pairs of a file")
+                .arg_required_else_help(true)
+                .arg(
+                    Arg::new("file")
+                        .help("file to read, reading from stdin if -")
+                        .value_name("FILE")
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(format_arg())
+                .arg(
+                    Arg::new("batch-size")
+                        .help("number of pairs sent per request")
+                        .long("batch-size")
+                        .num_args(1)
+                        .default_value("1000")
+                        .value_parser(clap::value_parser!(u64).range(1..)),
+                ),
+        )
+        .subcommand(
+            Command::new("hash-password")
+                .about("hash a password or API token read from stdin for the server's users file"),
+        )
+}
+
+fn print_info(server_info: &ServerInfo) {
+    let engine = &server_info.engine;
+
+    println!("# Server");
+    println!("version: {}", server_info.version);
+    println!("uptime_secs: {}", server_info.uptime_secs);
+    println!("connections_active: {}", server_info.connections_active);
+    println!("connections_total: {}", server_info.connections_total);
+    println!();
+    println!("# Engine");
+    println!("engine: {}", engine.engine);
+    println!("keys: {}", engine.keys);
+    println!("segments: {}", engine.segments);
+    println!("segments_size: {}", engine.segments_size);
+    println!("dead_bytes_ratio: {:.4}", engine.dead_bytes_ratio);
+    if let Some(index_bytes) = engine.index_bytes {
+        println!("index_bytes: {index_bytes}");
+    }
+    println!("compactions: {}", engine.compactions);
+    match engine.last_compaction_at {
+        Some(at) => println!("last_compaction_at: {}", at.to_rfc3339()),
+        None => println!("last_compaction_at: never"),
+    }
+    if let Some(ms) = engine.last_compaction_ms {
+        println!("last_compaction_ms: {ms}");
+    }
+    println!();
+    println!("# Replication");
+    match &server_info.replication {
+        ReplicationInfo::Leader { seq, followers } => {
+            println!("role: leader");
+            println!("seq: {seq}");
+            println!("followers: {followers}");
+        }
+        ReplicationInfo::Follower {
+            leader,
+            connected,
+            applied_seq,
+            leader_seq,
+            lag_entries,
+            lag_ms,
+        } => {
+            println!("role: follower");
+            println!("leader: {leader}");
+            println!("connected: {connected}");
+            println!("applied_seq: {applied_seq}");
+            println!("leader_seq: {leader_seq}");
+            println!("lag_entries: {lag_entries}");
+            println!("lag_ms: {lag_ms}");
+        }
+        ReplicationInfo::Cluster(cluster_info) => {
+            println!("role: cluster");
+            println!("node_id: {}", cluster_info.node_id);
+            println!("state: {}", cluster_info.state);
+            println!("term: {}", cluster_info.term);
+            match &cluster_info.leader {
+                Some(leader) => println!("leader: {leader}"),
+                None if cluster_info.state == "leader" => println!("leader: self"),
+                None => println!("leader: unknown"),
+            }
+            println!("nodes: {}", cluster_info.nodes);
+            println!("commit_index: {}", cluster_info.commit_index);
+            println!("last_applied: {}", cluster_info.last_applied);
+            println!("last_log_index: {}", cluster_info.last_log_index);
+            println!("snapshot_index: {}", cluster_info.snapshot_index);
+        }
+        ReplicationInfo::Proxy { backends } => {
+            println!("role: proxy");
+            println!("backends: {}", backends.len());
+            for (addr, backend) in backends {
+                println!(
+                    "backend {addr}: keys={} segments={} segments_size={} uptime_secs={}",
+                    backend.engine.keys,
+                    backend.engine.segments,
+                    backend.engine.segments_size,
+                    backend.uptime_secs
+                );
+            }
+        }
+    }
 }
 
-fn send_cmd(cmd_to_send: String, addr: String) -> Result<String> {
-    let mut tcp_client = TcpStream::connect(&addr)?;
-
-    // Prepending the command length and sending to server
-    let cmd = format!("{}\r\n{cmd_to_send}", cmd_to_send.len());
-    tcp_client.write_all(cmd.as_bytes())?;
-    trace!(
-        cmd = cmd,
-        cmd_bytes = cmd.len(),
-        server_addr = addr,
-        "Sent command to server"
-    );
-
-    // Reading the client response
-    let mut resp = String::new();
-    tcp_client.read_to_string(&mut resp)?;
-
-    trace!(
-        cmd = cmd,
-        server_addr = addr,
-        response = resp,
-        "Recieved response from server"
-    );
-
-    Ok(resp)
+fn format_arg() -> Arg {
+    Arg::new("format")
+        .help("file format, inferred from the file extension if omitted [default: jsonl]")
+        .long("format")
+        .num_args(1)
+        .value_parser(["jsonl", "csv"])
 }
@@ -0,0 +1,110 @@
+use clap::{Arg, Command};
+use tracing_subscriber::fmt::time;
+use tracing_subscriber::FmtSubscriber;
+
+use std::env;
+use std::io;
+use std::path::PathBuf;
+
+use hobbes::proxy::{self, ProxyConfig};
+use hobbes::{HobbesError, Result};
+
+fn main() -> Result<()> {
+    let logging_level = match env::var("LOG_LEVEL") {
+        Ok(level) => match level.as_str() {
+            "TRACE" => tracing::Level::TRACE,
+            "DEBUG" => tracing::Level::DEBUG,
+            "INFO" => tracing::Level::INFO,
+            "WARN" => tracing::Level::WARN,
+            "ERROR" => tracing::Level::ERROR,
+            _ => tracing::Level::INFO,
+        },
+        Err(_) => tracing::Level::INFO,
+    };
+
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(logging_level)
+        .with_timer(time::ChronoLocal::rfc_3339())
+        .with_target(true)
+        .with_writer(io::stderr)
+        .finish();
+
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    let command = Command::new("hobbes-proxy")
+        .version(env!("CARGO_PKG_VERSION"))
+        .arg(
+            Arg::new("addr")
+                .help("set the proxy endpoint")
+                .long("addr")
+                .default_value("127.0.0.1:5000")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("backends")
+                .help("set the hobbes servers the keys are sharded across")
+                .long("backends")
+                .value_name("HOST:PORT,...")
+                .required(true)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("virtual-nodes")
+                .help("set the number of points on the hash ring per backend")
+                .long("virtual-nodes")
+                .default_value("128")
+                .num_args(1)
+                .value_parser(clap::value_parser!(u32).range(1..)),
+        )
+        .arg(
+            Arg::new("state-file")
+                .help("persist the backends to this file, which takes precedence over --backends once it exists")
+                .long("state-file")
+                .value_name("PATH")
+                .num_args(1)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("admin-addr")
+                .help("accept `hobbes node add` and `hobbes node rm` on this endpoint only, which should not be reachable by untrusted clients")
+                .long("admin-addr")
+                .value_name("HOST:PORT")
+                .num_args(1),
+        )
+        .get_matches();
+
+    let addr = command
+        .get_one::<String>("addr")
+        .ok_or_else(|| HobbesError::CliError(String::from("failed to parse argument \"addr\"")))?;
+    let backends = command
+        .get_one::<String>("backends")
+        .ok_or_else(|| {
+            HobbesError::CliError(String::from("failed to parse argument \"backends\""))
+        })?
+        .split(',')
+        .map(|backend| backend.trim().to_string())
+        .filter(|backend| !backend.is_empty())
+        .collect::<Vec<String>>();
+    let virtual_nodes = *command.get_one::<u32>("virtual-nodes").ok_or_else(|| {
+        HobbesError::CliError(String::from("failed to parse argument \"virtual-nodes\""))
+    })?;
+
+    let state_file = command.get_one::<PathBuf>("state-file").cloned();
+    let admin_addr = command.get_one::<String>("admin-addr").cloned();
+
+    println!(
+        "Sharding across {} backends and serving at address {addr}",
+        backends.len()
+    );
+    println!("Version [{}]", env!("CARGO_PKG_VERSION"));
+
+    proxy::start_proxy(&ProxyConfig {
+        addr: addr.to_string(),
+        backends,
+        virtual_nodes,
+        state_file,
+        admin_addr,
+    })?;
+
+    Ok(())
+}
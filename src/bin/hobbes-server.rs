@@ -1,60 +1,341 @@
 use clap::{Arg, Command};
-use tracing_subscriber::fmt::time;
-use tracing_subscriber::FmtSubscriber;
 
+use std::collections::HashMap;
 use std::env;
-use std::io;
+use std::path::{Path, PathBuf};
 
-use hobbes::engine;
+use hobbes::client::Credentials;
+use hobbes::config::{self, FileConfig, DEFAULT_CONFIG_PATH};
+use hobbes::engine::bitcask::{CompactionWindow, Compression, FsyncPolicy, Keyring};
+use hobbes::engine::{self, ClusterConfig, ServerConfig};
+use hobbes::tls::ServerTlsConfig;
 use hobbes::{HobbesError, Result};
 
-fn main() -> Result<()> {
-    let logging_level = match env::var("LOG_LEVEL") {
-        Ok(level) => match level.as_str() {
-            "TRACE" => tracing::Level::TRACE,
-            "DEBUG" => tracing::Level::DEBUG,
-            "INFO" => tracing::Level::INFO,
-            "WARN" => tracing::Level::WARN,
-            "ERROR" => tracing::Level::ERROR,
-            _ => tracing::Level::INFO,
-        },
-        Err(_) => tracing::Level::INFO,
-    };
-
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(logging_level)
-        .with_timer(time::ChronoLocal::rfc_3339())
-        .with_target(true)
-        .with_writer(io::stderr)
-        .finish();
-
-    tracing::subscriber::set_global_default(subscriber)?;
+// Environment variable holding the password or API token of the peer user
+const PEER_PASSWORD_ENV: &str = "HOBBES_PEER_PASSWORD";
 
+fn main() -> Result<()> {
     let command = Command::new("hobbes-server")
         .version(env!("CARGO_PKG_VERSION"))
+        .arg(
+            Arg::new("config")
+                .help("read settings from this TOML file, overridden by flags and environment variables [default: hobbes.toml if present]")
+                .long("config")
+                .value_name("PATH")
+                .num_args(1)
+                .env("HOBBES_CONFIG")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
         .arg(
             Arg::new("addr")
-                .help("set the server endpoint")
+                .help("set the server endpoint [default: 127.0.0.1:4000]")
                 .long("addr")
-                .default_value("127.0.0.1:4000")
-                .num_args(1),
+                .num_args(1)
+                .env("HOBBES_ADDR"),
         )
         .arg(
             Arg::new("engine")
-                .help("set the storage engine")
+                .help("set the storage engine [default: bitcask]")
                 .long("engine")
-                .default_value("bitcask")
                 .num_args(1)
+                .env("HOBBES_ENGINE")
                 .value_parser(["bitcask", "sled"]),
         )
+        .arg(
+            Arg::new("data-dir")
+                .help("store data in this directory [default: .]")
+                .long("data-dir")
+                .value_name("PATH")
+                .num_args(1)
+                .env("HOBBES_DATA_DIR")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("threads")
+                .help("set the number of threads handling connections [default: number of CPUs]")
+                .long("threads")
+                .num_args(1)
+                .env("HOBBES_THREADS")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("log-level")
+                .help("set the maximum level of logs: trace, debug, info, warn or error [default: info]")
+                .long("log-level")
+                .value_name("LEVEL")
+                .num_args(1)
+                .env("LOG_LEVEL"),
+        )
+        .arg(
+            Arg::new("replica-of")
+                .help("replicate from the leader at the given address, serving read-only traffic")
+                .long("replica-of")
+                .value_name("HOST:PORT")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("cluster")
+                .help("run as a member of a raft cluster, listing the id and address of every node")
+                .long("cluster")
+                .value_name("ID=HOST:PORT,...")
+                .num_args(1)
+                .requires("node-id")
+                .conflicts_with("replica-of"),
+        )
+        .arg(
+            Arg::new("node-id")
+                .help("set the id of this node within the cluster")
+                .long("node-id")
+                .value_name("ID")
+                .num_args(1)
+                .value_parser(clap::value_parser!(u64))
+                .requires("cluster"),
+        )
+        .arg(
+            Arg::new("tls-cert")
+                .help("accept TLS connections, presenting the certificate chain in this PEM file")
+                .long("tls-cert")
+                .value_name("PATH")
+                .num_args(1)
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("tls-key"),
+        )
+        .arg(
+            Arg::new("tls-key")
+                .help("set the PEM file holding the private key of the TLS certificate")
+                .long("tls-key")
+                .value_name("PATH")
+                .num_args(1)
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("tls-cert"),
+        )
+        .arg(
+            Arg::new("tls-client-ca")
+                .help("require clients to present a certificate signed by the CA in this PEM file")
+                .long("tls-client-ca")
+                .value_name("PATH")
+                .num_args(1)
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("tls-cert"),
+        )
+        .arg(
+            Arg::new("tls-ca")
+                .help("connect to the leader or cluster peers over TLS, verifying them with the CA in this PEM file")
+                .long("tls-ca")
+                .value_name("PATH")
+                .num_args(1)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("users")
+                .help("require clients to authenticate as one of the users in this TOML file")
+                .long("users")
+                .value_name("PATH")
+                .num_args(1)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("peer-user")
+                .help("authenticate as this user with the leader or cluster peers")
+                .long("peer-user")
+                .value_name("USER")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("segment-size")
+                .help("seal the active bitcask log and start a new one once it reaches this size in bytes [default: 1000000]")
+                .long("segment-size")
+                .value_name("BYTES")
+                .num_args(1)
+                .env("HOBBES_SEGMENT_SIZE")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("compaction-threshold")
+                .help("merge sealed bitcask logs once they hold this many bytes of dead records [default: 1000000]")
+                .long("compaction-threshold")
+                .value_name("BYTES")
+                .num_args(1)
+                .env("HOBBES_COMPACTION_THRESHOLD")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("merge-dead-ratio")
+                .help("merge sealed bitcask logs once this fraction of them is dead records [default: 0.5]")
+                .long("merge-dead-ratio")
+                .value_name("RATIO")
+                .num_args(1)
+                .env("HOBBES_MERGE_DEAD_RATIO")
+                .value_parser(clap::value_parser!(f64)),
+        )
+        .arg(
+            Arg::new("max-merge-bytes")
+                .help("set the maximum size in bytes of the bitcask logs rewritten by a merge [default: 64000000]")
+                .long("max-merge-bytes")
+                .value_name("BYTES")
+                .num_args(1)
+                .env("HOBBES_MAX_MERGE_BYTES")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("merge-interval")
+                .help("wait at least this long between bitcask merges, such as 10s [default: 0s]")
+                .long("merge-interval")
+                .value_name("INTERVAL")
+                .num_args(1)
+                .env("HOBBES_MERGE_INTERVAL"),
+        )
+        .arg(
+            Arg::new("compaction-window")
+                .help("start bitcask merges only within this daily local time range, such as 01:00-05:00")
+                .long("compaction-window")
+                .value_name("HH:MM-HH:MM")
+                .num_args(1)
+                .env("HOBBES_COMPACTION_WINDOW"),
+        )
+        .arg(
+            Arg::new("compaction-rate-limit")
+                .help("write at most this many bytes per second when merging or compacting bitcask logs")
+                .long("compaction-rate-limit")
+                .value_name("BYTES")
+                .num_args(1)
+                .env("HOBBES_COMPACTION_RATE_LIMIT")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("fsync")
+                .help("flush bitcask writes to disk: never, always, or at an interval such as 100ms [default: never]")
+                .long("fsync")
+                .value_name("POLICY")
+                .num_args(1)
+                .env("HOBBES_FSYNC"),
+        )
+        .arg(
+            Arg::new("encryption-key-file")
+                .help("encrypt bitcask logs at rest with the keys in this file, the first key encrypting new records")
+                .long("encryption-key-file")
+                .value_name("PATH")
+                .num_args(1)
+                .env("HOBBES_ENCRYPTION_KEY_FILE")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("compression")
+                .help("compress bitcask records with this codec")
+                .long("compression")
+                .num_args(1)
+                .env("HOBBES_COMPRESSION")
+                .value_parser(["lz4", "zstd"]),
+        )
         .get_matches();
 
-    let addr = command
-        .get_one::<String>("addr")
-        .ok_or_else(|| HobbesError::CliError(String::from("failed to parse argument \"addr\"")))?;
-    let engine = command.get_one::<String>("engine").ok_or_else(|| {
-        HobbesError::CliError(String::from("failed to parse argument \"engine\""))
-    })?;
+    // Settings are taken from flags, then environment variables, then the config file
+    let file_config = match command.get_one::<PathBuf>("config") {
+        Some(path) => FileConfig::load(path)?,
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+            FileConfig::load(Path::new(DEFAULT_CONFIG_PATH))?
+        }
+        None => FileConfig::default(),
+    };
+    let mut config = file_config.apply(ServerConfig::new())?;
+
+    if let Some(addr) = command.get_one::<String>("addr") {
+        config = config.addr(addr);
+    }
+    if let Some(engine) = command.get_one::<String>("engine") {
+        config = config.engine(engine);
+    }
+    if let Some(data_dir) = command.get_one::<PathBuf>("data-dir") {
+        config = config.data_dir(data_dir);
+    }
+    if let Some(threads) = command.get_one::<usize>("threads") {
+        config = config.threads(*threads);
+    }
+    if let Some(level) = command.get_one::<String>("log-level") {
+        config = config.log_level(config::parse_log_level(level)?);
+    }
+    if let Some(segment_size) = command.get_one::<u64>("segment-size") {
+        config.bitcask = config.bitcask.segment_size(*segment_size);
+    }
+    if let Some(compaction_threshold) = command.get_one::<u64>("compaction-threshold") {
+        config.bitcask = config.bitcask.compaction_threshold(*compaction_threshold);
+    }
+    if let Some(merge_dead_ratio) = command.get_one::<f64>("merge-dead-ratio") {
+        config.bitcask = config.bitcask.merge_dead_ratio(*merge_dead_ratio);
+    }
+    if let Some(max_merge_bytes) = command.get_one::<u64>("max-merge-bytes") {
+        config.bitcask = config.bitcask.max_merge_bytes(*max_merge_bytes);
+    }
+    if let Some(merge_interval) = command.get_one::<String>("merge-interval") {
+        config.bitcask = config
+            .bitcask
+            .merge_interval(config::parse_interval(merge_interval)?);
+    }
+    if let Some(compaction_window) = command.get_one::<String>("compaction-window") {
+        config.bitcask = config
+            .bitcask
+            .compaction_window(compaction_window.parse::<CompactionWindow>()?);
+    }
+    if let Some(compaction_rate_limit) = command.get_one::<u64>("compaction-rate-limit") {
+        config.bitcask = config.bitcask.compaction_rate_limit(*compaction_rate_limit);
+    }
+    if let Some(fsync) = command.get_one::<String>("fsync") {
+        config.bitcask = config.bitcask.fsync(fsync.parse::<FsyncPolicy>()?);
+    }
+    if let Some(codec) = command.get_one::<String>("compression") {
+        config.bitcask = config
+            .bitcask
+            .compression(Compression::new(config::parse_codec(codec)?));
+    }
+    match command.get_one::<PathBuf>("encryption-key-file") {
+        Some(key_file) => config.bitcask = config.bitcask.keyring(Keyring::from_file(key_file)?),
+        None if file_config.bitcask.encryption_key_file.is_none() => {
+            if let Some(keyring) = Keyring::from_env()? {
+                config.bitcask = config.bitcask.keyring(keyring);
+            }
+        }
+        None => {}
+    }
+    if let Some(leader) = command.get_one::<String>("replica-of") {
+        config = config.replica_of(leader);
+    }
+    if let Some(peers) = command.get_one::<String>("cluster") {
+        config = config.cluster(ClusterConfig {
+            node_id: *command.get_one::<u64>("node-id").ok_or_else(|| {
+                HobbesError::CliError(String::from("failed to parse argument \"node-id\""))
+            })?,
+            peers: parse_cluster_peers(peers)?,
+        });
+    }
+    if let (Some(cert), Some(key)) = (
+        command.get_one::<PathBuf>("tls-cert"),
+        command.get_one::<PathBuf>("tls-key"),
+    ) {
+        config = config.tls(ServerTlsConfig {
+            cert: cert.clone(),
+            key: key.clone(),
+            client_ca: command.get_one::<PathBuf>("tls-client-ca").cloned(),
+        });
+    }
+    if let Some(ca) = command.get_one::<PathBuf>("tls-ca") {
+        config = config.peer_tls_ca(ca);
+    }
+    if let Some(users) = command.get_one::<PathBuf>("users") {
+        config = config.users(users);
+    }
+    // The peer password is only read from the environment, as arguments are visible to every
+    // user of the host
+    if let Some(user) = command.get_one::<String>("peer-user") {
+        let secret = env::var(PEER_PASSWORD_ENV).map_err(|_| {
+            HobbesError::CliError(format!(
+                "missing peer password, set {PEER_PASSWORD_ENV} along with --peer-user"
+            ))
+        })?;
+        config = config.peer_credentials(Credentials {
+            user: user.clone(),
+            secret,
+        });
+    }
 
     println!(
         r"
@@ -66,10 +347,47 @@ fn main() -> Result<()> {
 
     "
     );
-    println!("Using engine [{engine}] and serving at address {addr}");
+    println!(
+        "Using engine [{}] and serving at address {}",
+        config.engine, config.addr
+    );
+    println!("Storing data in {:?}", config.data_dir);
+    if let Some(leader) = &config.replica_of {
+        println!("Replicating from leader at address {leader}");
+    }
+    if let Some(cluster) = &config.cluster {
+        println!(
+            "Running as node {} of a {} node cluster",
+            cluster.node_id,
+            cluster.peers.len()
+        );
+    }
+    if let Some(tls) = &config.tls {
+        match tls.client_ca {
+            Some(_) => println!("Accepting TLS connections from verified clients"),
+            None => println!("Accepting TLS connections"),
+        }
+    }
+    if config.users.is_some() {
+        println!("Requiring clients to authenticate");
+    }
     println!("Version [{}]", env!("CARGO_PKG_VERSION"));
 
-    engine::start_server(addr, engine)?;
+    engine::start_server(&config)?;
 
     Ok(())
 }
+
+fn parse_cluster_peers(peers: &str) -> Result<HashMap<u64, String>> {
+    peers
+        .split(',')
+        .map(|peer| {
+            let (id, addr) = peer.split_once('=').ok_or_else(|| {
+                HobbesError::CliError(format!(
+                    "invalid cluster member {peer}, expected ID=HOST:PORT"
+                ))
+            })?;
+            Ok((id.trim().parse::<u64>()?, addr.trim().to_string()))
+        })
+        .collect()
+}
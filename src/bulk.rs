@@ -0,0 +1,257 @@
+//! Import and export files of key-value pairs
+//!
+//! JSON lines files hold an object per line, and CSV files a header row followed by a row per
+//! pair:
+//!
+//! ```text
+//! {"key":"user:1","value":"alice"}
+//! {"key":"user:2","value_base64":"Ym9i"}
+//!
+//! key,value
+//! user:1,alice
+//! ```
+//!
+//! Values are base64-encoded in the `value_base64` field or column, so that values holding
+//! control characters or delimiters survive the round trip. Values are stored as strings, so
+//! base64 only escapes UTF-8 text and decoded values which are not valid UTF-8 are rejected.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::io::{self, BufRead, Lines, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{HobbesError, Result};
+
+const VALUE_FIELD: &str = "value";
+const BASE64_VALUE_FIELD: &str = "value_base64";
+
+/// Layout of an import or export file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Jsonl,
+    Csv,
+}
+
+impl Format {
+    /// Infer the format from the extension of a file, defaulting to JSON lines
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => Format::Csv,
+            _ => Format::Jsonl,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = HobbesError;
+
+    fn from_str(format: &str) -> Result<Format> {
+        match format {
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            _ => Err(HobbesError::CliError(format!(
+                "invalid format {format}, expected jsonl or csv"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Jsonl => write!(f, "jsonl"),
+            Format::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonPair {
+    key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value_base64: Option<String>,
+}
+
+/// PairWriter writes key-value pairs to an export file
+pub struct PairWriter<W: Write> {
+    inner: PairWriterInner<W>,
+    base64: bool,
+}
+
+enum PairWriterInner<W: Write> {
+    Jsonl(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> PairWriter<W> {
+    /// Create a writer, base64-encoding values if set
+    pub fn new(writer: W, format: Format, base64: bool) -> Result<PairWriter<W>> {
+        let inner = match format {
+            Format::Jsonl => PairWriterInner::Jsonl(writer),
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(writer);
+                let value_field = if base64 {
+                    BASE64_VALUE_FIELD
+                } else {
+                    VALUE_FIELD
+                };
+                writer
+                    .write_record(["key", value_field])
+                    .map_err(io::Error::from)?;
+                PairWriterInner::Csv(Box::new(writer))
+            }
+        };
+        Ok(PairWriter { inner, base64 })
+    }
+
+    pub fn write(&mut self, key: &str, value: &str) -> Result<()> {
+        let encoded;
+        let value = if self.base64 {
+            encoded = BASE64.encode(value);
+            encoded.as_str()
+        } else {
+            value
+        };
+
+        match &mut self.inner {
+            PairWriterInner::Jsonl(writer) => {
+                let pair = JsonPair {
+                    key: key.to_string(),
+                    value: (!self.base64).then(|| value.to_string()),
+                    value_base64: self.base64.then(|| value.to_string()),
+                };
+                serde_json::to_writer(&mut *writer, &pair)?;
+                writer.write_all(b"\n")?;
+            }
+            PairWriterInner::Csv(writer) => {
+                writer.write_record([key, value]).map_err(io::Error::from)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush buffered pairs
+    pub fn finish(self) -> Result<()> {
+        match self.inner {
+            PairWriterInner::Jsonl(mut writer) => writer.flush()?,
+            PairWriterInner::Csv(mut writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// PairReader reads key-value pairs from an import file, decoding base64 values
+///
+/// Keys are trimmed as the server trims them in SET, while values are kept as written
+pub struct PairReader<R: BufRead> {
+    inner: PairReaderInner<R>,
+    // Line of the last JSON pair read
+    line: u64,
+}
+
+enum PairReaderInner<R: BufRead> {
+    Jsonl(Lines<R>),
+    Csv {
+        records: csv::StringRecordsIntoIter<R>,
+        base64: bool,
+    },
+}
+
+impl<R: BufRead> PairReader<R> {
+    pub fn new(reader: R, format: Format) -> Result<PairReader<R>> {
+        let inner = match format {
+            Format::Jsonl => PairReaderInner::Jsonl(reader.lines()),
+            Format::Csv => {
+                let mut reader = csv::Reader::from_reader(reader);
+                let headers = reader.headers().map_err(|e| import_error(1, e))?;
+                let base64 = match (headers.get(0), headers.get(1)) {
+                    (Some("key"), Some(VALUE_FIELD)) => false,
+                    (Some("key"), Some(BASE64_VALUE_FIELD)) => true,
+                    _ => Err(import_error(
+                        1,
+                        "expected a header row of key,value or key,value_base64",
+                    ))?,
+                };
+                PairReaderInner::Csv {
+                    records: reader.into_records(),
+                    base64,
+                }
+            }
+        };
+        Ok(PairReader { inner, line: 0 })
+    }
+
+    fn next_pair(&mut self) -> Option<Result<(String, String)>> {
+        match &mut self.inner {
+            PairReaderInner::Jsonl(lines) => loop {
+                self.line += 1;
+                let line = match lines.next()? {
+                    Ok(line) => line,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                return Some(parse_json_pair(&line).map_err(|e| import_error(self.line, e)));
+            },
+            PairReaderInner::Csv { records, base64 } => {
+                let record = match records.next()? {
+                    Ok(record) => record,
+                    Err(e) => {
+                        let line = e.position().map_or(0, |pos| pos.line());
+                        return Some(Err(import_error(line, e)));
+                    }
+                };
+                let line = record.position().map_or(0, |pos| pos.line());
+                let pair = match (record.get(0), record.get(1), record.len()) {
+                    (Some(key), Some(value), 2) if *base64 => {
+                        decode_base64(value).map(|value| (key.to_string(), value))
+                    }
+                    (Some(key), Some(value), 2) => Ok((key.to_string(), value.to_string())),
+                    _ => Err(String::from("expected a key and a value")),
+                };
+                Some(pair.map_err(|e| import_error(line, e)))
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for PairReader<R> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_pair()
+            .map(|pair| pair.map(|(key, val)| (key.trim().to_string(), val)))
+    }
+}
+
+fn parse_json_pair(line: &str) -> std::result::Result<(String, String), String> {
+    let pair: JsonPair = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    match (pair.value, pair.value_base64) {
+        (Some(value), None) => Ok((pair.key, value)),
+        (None, Some(encoded)) => Ok((pair.key, decode_base64(&encoded)?)),
+        _ => Err(String::from(
+            "expected exactly one of the value and value_base64 fields",
+        )),
+    }
+}
+
+fn decode_base64(encoded: &str) -> std::result::Result<String, String> {
+    let bytes = BASE64
+        .decode(encoded)
+        .map_err(|e| format!("invalid base64 value, {e}"))?;
+    String::from_utf8(bytes).map_err(|_| {
+        String::from("base64 values must decode to UTF-8 text, binary values are not supported")
+    })
+}
+
+fn import_error(line: u64, err: impl fmt::Display) -> HobbesError {
+    HobbesError::ImportError(format!("line {line}: {err}"))
+}
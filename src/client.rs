@@ -0,0 +1,287 @@
+//! Client for communicating with a hobbes server
+
+use tracing::trace;
+
+use std::io::{BufRead, BufReader, Read};
+use std::time::Duration;
+
+use crate::engine::{ServerInfo, WatchEvent};
+use crate::protocol::{encode_command, encode_pairs, write_frame, Response, Status, CRLF};
+use crate::tls::{Stream, TlsConnector};
+use crate::{HobbesError, Result};
+
+// Maximum number of redirects followed when a cluster node forwards the client to its leader
+const MAX_REDIRECTS: usize = 3;
+
+/// Credentials presented to servers requiring authentication
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub user: String,
+    /// Password or API token of the user
+    pub secret: String,
+}
+
+/// Opens connections to servers, over TLS and authenticating when configured
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Connector {
+    pub(crate) tls: Option<TlsConnector>,
+    pub(crate) credentials: Option<Credentials>,
+}
+
+impl Connector {
+    /// Connect to the address, failing if the connection is not established within the timeout
+    pub(crate) fn connect(&self, addr: &str, timeout: Option<Duration>) -> Result<Stream> {
+        let mut stream = match timeout {
+            Some(timeout) => Stream::connect_timeout(addr, self.tls.as_ref(), timeout)?,
+            None => Stream::connect(addr, self.tls.as_ref())?,
+        };
+        if let Some(credentials) = &self.credentials {
+            authenticate(&mut stream, credentials)?;
+        }
+        Ok(stream)
+    }
+}
+
+/// Client sends commands to a hobbes server, opening a connection per command
+#[derive(Debug, Clone)]
+pub struct Client {
+    addr: String,
+    connector: Connector,
+}
+
+impl Client {
+    /// Create a client for the server at the specified address
+    pub fn new(addr: &str) -> Client {
+        Client {
+            addr: addr.to_string(),
+            connector: Connector::default(),
+        }
+    }
+
+    /// Connect to the server over TLS
+    pub fn with_tls(mut self, tls: TlsConnector) -> Client {
+        self.connector.tls = Some(tls);
+        self
+    }
+
+    /// Authenticate as the user with its password or API token
+    pub fn with_credentials(mut self, user: &str, secret: &str) -> Client {
+        self.connector.credentials = Some(Credentials {
+            user: user.to_string(),
+            secret: secret.to_string(),
+        });
+        self
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Retrieve the value associated with a key
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        let resp = self.send_cmd(&encode_command(&["GET", key]))?;
+        match resp.status {
+            Status::NotFound => Ok(None),
+            _ => resp.into_payload().map(Some),
+        }
+    }
+
+    /// Store a key-value pair
+    pub fn set(&self, key: &str, val: &str) -> Result<()> {
+        self.send_cmd(&encode_command(&["SET", key, val]))?
+            .into_payload()?;
+        Ok(())
+    }
+
+    /// Store many key-value pairs over a single connection, applied as one write
+    pub fn set_many(&self, pairs: &[(String, String)]) -> Result<()> {
+        self.send_cmd(&encode_command(&["MSET", &encode_pairs(pairs)?]))?
+            .into_payload()?;
+        Ok(())
+    }
+
+    /// Delete a key-value pair, failing with KeyNotFoundError if the key is absent
+    pub fn remove(&self, key: &str) -> Result<()> {
+        self.send_cmd(&encode_command(&["RM", key]))?
+            .into_payload()?;
+        Ok(())
+    }
+
+    /// Retrieve every key-value pair whose key starts with the prefix, sorted by key
+    pub fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let resp = self
+            .send_cmd(&encode_command(&["SCAN", prefix]))?
+            .into_payload()?;
+        Ok(serde_json::from_str(&resp)?)
+    }
+
+    /// Retrieve the first `limit` key-value pairs whose key starts with the prefix and sorts
+    /// after `after`, sorted by key. Passing the last key of each page as `after` visits every
+    /// pair, until an empty page is returned.
+    pub fn scan_page(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let limit = limit.to_string();
+        let cmd = encode_command(&["SCAN", prefix, &limit, after.unwrap_or_default()]);
+        let resp = self.send_cmd(&cmd)?.into_payload()?;
+        Ok(serde_json::from_str(&resp)?)
+    }
+
+    /// Retrieve statistics about the server and its storage engine
+    pub fn info(&self) -> Result<ServerInfo> {
+        let resp = self.send_cmd(&encode_command(&["INFO"]))?.into_payload()?;
+        Ok(serde_json::from_str(&resp)?)
+    }
+
+    /// Reclaim the space held by overwritten and removed entries in the server's store,
+    /// returning once compaction completes
+    pub fn compact(&self) -> Result<()> {
+        self.send_cmd(&encode_command(&["COMPACT"]))?
+            .into_payload()?;
+        Ok(())
+    }
+
+    /// Subscribe to every subsequent change to keys starting with the prefix. The subscription
+    /// is active once this returns.
+    pub fn watch(&self, prefix: &str) -> Result<WatchStream> {
+        let mut tcp_client = self.connector.connect(&self.addr, None)?;
+        write_frame(&mut tcp_client, &encode_command(&["WATCH", prefix]))?;
+
+        // The server acknowledges the subscription with an OK status line, and rejects it with
+        // a response which ends the connection
+        let mut reader = BufReader::new(tcp_client);
+        let mut ack = String::new();
+        reader.read_line(&mut ack)?;
+        if ack != format!("{}{CRLF}", Status::Ok) {
+            reader.read_to_string(&mut ack)?;
+            Response::decode(&ack)?.into_payload()?;
+            return Err(HobbesError::WatchError(format!(
+                "server did not accept the subscription, response = {ack}"
+            )));
+        }
+        trace!(
+            server_addr = self.addr,
+            prefix = prefix,
+            "Subscribed to changes"
+        );
+
+        Ok(WatchStream { reader })
+    }
+
+    /// Send a raw command, following redirects to the cluster leader, and return the response
+    pub fn send_cmd(&self, cmd: &str) -> Result<Response> {
+        let mut addr = self.addr.clone();
+        for _ in 0..MAX_REDIRECTS {
+            let resp = send_cmd_to(cmd, &addr, &self.connector)?;
+            match resp.status {
+                Status::Redirect => {
+                    trace!(
+                        server_addr = addr,
+                        leader_addr = resp.payload,
+                        "Redirected to leader"
+                    );
+                    addr = resp.payload;
+                }
+                Status::AuthError => return Err(HobbesError::AuthError(resp.payload)),
+                _ => return Ok(resp),
+            }
+        }
+
+        Err(HobbesError::NetworkError(format!(
+            "exceeded {MAX_REDIRECTS} redirects while locating the cluster leader"
+        )))
+    }
+}
+
+/// WatchStream yields the changes streamed by the server, ending when the connection closes
+#[derive(Debug)]
+pub struct WatchStream {
+    reader: BufReader<Stream>,
+}
+
+impl WatchStream {
+    /// Read the next line, returning Ok(None) for the heartbeats sent while no keys change
+    pub(crate) fn next_line(&mut self) -> Option<Result<Option<WatchEvent>>> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) if line.trim().is_empty() => Some(Ok(None)),
+            Ok(_) => Some(
+                serde_json::from_str(&line)
+                    .map(Some)
+                    .map_err(HobbesError::from),
+            ),
+            Err(e) => Some(Err(HobbesError::from(e))),
+        }
+    }
+}
+
+impl Iterator for WatchStream {
+    type Item = Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Result<WatchEvent>> {
+        loop {
+            match self.next_line()? {
+                Ok(None) => continue,
+                Ok(Some(event)) => return Some(Ok(event)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+fn send_cmd_to(cmd: &str, addr: &str, connector: &Connector) -> Result<Response> {
+    let mut tcp_client = connector.connect(addr, None)?;
+
+    // Prepending the command length and sending to server
+    write_frame(&mut tcp_client, cmd)?;
+    trace!(
+        cmd = cmd,
+        cmd_bytes = cmd.len(),
+        server_addr = addr,
+        "Sent command to server"
+    );
+
+    // Reading the server response
+    let mut resp = String::new();
+    tcp_client.read_to_string(&mut resp)?;
+
+    trace!(
+        cmd = cmd,
+        server_addr = addr,
+        response = resp,
+        "Recieved response from server"
+    );
+
+    Response::decode(&resp)
+}
+
+fn authenticate(stream: &mut Stream, credentials: &Credentials) -> Result<()> {
+    write_frame(
+        stream,
+        &encode_command(&["AUTH", &credentials.user, &credentials.secret]),
+    )?;
+
+    // The server accepts the credentials with an OK status line, then reads the command.
+    // Nothing else is sent before the command, so the buffered reader does not consume any
+    // response. Rejected credentials end the connection after the response.
+    let mut reader = BufReader::new(stream);
+    let mut resp = String::new();
+    reader.read_line(&mut resp)?;
+    if resp == format!("{}{CRLF}", Status::Ok) {
+        return Ok(());
+    }
+    reader.read_to_string(&mut resp)?;
+    match Response::decode(&resp)? {
+        Response {
+            status: Status::AuthError,
+            payload,
+        } => Err(HobbesError::AuthError(payload)),
+        _ => Err(HobbesError::AuthError(format!(
+            "unexpected response to AUTH command, response = {resp}"
+        ))),
+    }
+}
@@ -0,0 +1,185 @@
+//! Server configuration file
+//!
+//! Every setting is optional, falling back to the defaults of ServerConfig and BitcaskOptions:
+//!
+//! ```toml
+//! addr = "127.0.0.1:4000"
+//! engine = "bitcask"
+//! data_dir = "/var/lib/hobbes"
+//! threads = 8
+//! log_level = "info"
+//!
+//! [bitcask]
+//! segment_size = 4194304
+//! compaction_threshold = 4194304
+//! merge_dead_ratio = 0.5
+//! max_merge_bytes = 64000000
+//! merge_interval = "10s"
+//! compaction_window = "01:00-05:00"
+//! compaction_rate_limit = 50000000
+//! fsync = "100ms"
+//! compression = "lz4"
+//! encryption_key_file = "/etc/hobbes/keys"
+//! ```
+
+use serde::Deserialize;
+use tracing::Level;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::engine::bitcask::{Codec, CompactionWindow, Compression, FsyncPolicy, Keyring};
+use crate::engine::ServerConfig;
+use crate::{HobbesError, Result};
+
+/// Name of the configuration file servers read from the working directory by default
+pub const DEFAULT_CONFIG_PATH: &str = "hobbes.toml";
+
+/// Settings read from a configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub addr: Option<String>,
+    pub engine: Option<String>,
+    pub data_dir: Option<PathBuf>,
+    pub threads: Option<usize>,
+    pub log_level: Option<String>,
+    pub replica_of: Option<String>,
+    pub users: Option<PathBuf>,
+    #[serde(default)]
+    pub bitcask: BitcaskFileConfig,
+}
+
+/// Settings of the `[bitcask]` table
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BitcaskFileConfig {
+    pub segment_size: Option<u64>,
+    pub compaction_threshold: Option<u64>,
+    pub merge_dead_ratio: Option<f64>,
+    pub max_merge_bytes: Option<u64>,
+    pub merge_interval: Option<String>,
+    pub compaction_window: Option<String>,
+    pub compaction_rate_limit: Option<u64>,
+    pub fsync: Option<String>,
+    pub compression: Option<String>,
+    pub encryption_key_file: Option<PathBuf>,
+}
+
+impl FileConfig {
+    /// Read a configuration file
+    pub fn load(path: &Path) -> Result<FileConfig> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            HobbesError::ConfigError(format!("failed to read config file {path:?}, {e}"))
+        })?;
+        toml::from_str(&contents)
+            .map_err(|e| HobbesError::ConfigError(format!("invalid config file {path:?}, {e}")))
+    }
+
+    /// Override the settings of the server config with those set in the file
+    pub fn apply(&self, mut config: ServerConfig) -> Result<ServerConfig> {
+        if let Some(addr) = &self.addr {
+            config = config.addr(addr);
+        }
+        if let Some(engine) = &self.engine {
+            config = config.engine(engine);
+        }
+        if let Some(data_dir) = &self.data_dir {
+            config = config.data_dir(data_dir);
+        }
+        if let Some(threads) = self.threads {
+            config = config.threads(threads);
+        }
+        if let Some(log_level) = &self.log_level {
+            config = config.log_level(parse_log_level(log_level)?);
+        }
+        if let Some(leader) = &self.replica_of {
+            config = config.replica_of(leader);
+        }
+        if let Some(users) = &self.users {
+            config = config.users(users);
+        }
+
+        let bitcask = &self.bitcask;
+        if let Some(segment_size) = bitcask.segment_size {
+            config.bitcask = config.bitcask.segment_size(segment_size);
+        }
+        if let Some(compaction_threshold) = bitcask.compaction_threshold {
+            config.bitcask = config.bitcask.compaction_threshold(compaction_threshold);
+        }
+        if let Some(merge_dead_ratio) = bitcask.merge_dead_ratio {
+            config.bitcask = config.bitcask.merge_dead_ratio(merge_dead_ratio);
+        }
+        if let Some(max_merge_bytes) = bitcask.max_merge_bytes {
+            config.bitcask = config.bitcask.max_merge_bytes(max_merge_bytes);
+        }
+        if let Some(merge_interval) = &bitcask.merge_interval {
+            config.bitcask = config
+                .bitcask
+                .merge_interval(parse_interval(merge_interval)?);
+        }
+        if let Some(compaction_window) = &bitcask.compaction_window {
+            config.bitcask = config
+                .bitcask
+                .compaction_window(compaction_window.parse::<CompactionWindow>()?);
+        }
+        if let Some(compaction_rate_limit) = bitcask.compaction_rate_limit {
+            config.bitcask = config.bitcask.compaction_rate_limit(compaction_rate_limit);
+        }
+        if let Some(fsync) = &bitcask.fsync {
+            config.bitcask = config.bitcask.fsync(fsync.parse::<FsyncPolicy>()?);
+        }
+        if let Some(codec) = &bitcask.compression {
+            config.bitcask = config
+                .bitcask
+                .compression(Compression::new(parse_codec(codec)?));
+        }
+        if let Some(key_file) = &bitcask.encryption_key_file {
+            config.bitcask = config.bitcask.keyring(Keyring::from_file(key_file)?);
+        }
+
+        Ok(config)
+    }
+}
+
+/// Parse a log level such as `info` or `DEBUG`
+pub fn parse_log_level(level: &str) -> Result<Level> {
+    level.parse::<Level>().map_err(|_| {
+        HobbesError::ConfigError(format!(
+            "invalid log level {level}, expected trace, debug, info, warn or error"
+        ))
+    })
+}
+
+/// Parse a compression codec, either `lz4` or `zstd`
+pub fn parse_codec(codec: &str) -> Result<Codec> {
+    match codec {
+        "lz4" => Ok(Codec::Lz4),
+        "zstd" => Ok(Codec::Zstd),
+        _ => Err(HobbesError::ConfigError(format!(
+            "invalid compression codec {codec}, expected lz4 or zstd"
+        ))),
+    }
+}
+
+/// Parse an interval such as `100ms` or `10s`
+pub fn parse_interval(interval: &str) -> Result<Duration> {
+    let invalid_interval = || {
+        HobbesError::ConfigError(format!(
+            "invalid interval {interval}, expected milliseconds or seconds such as 100ms or 10s"
+        ))
+    };
+
+    if let Some(ms) = interval.strip_suffix("ms") {
+        Ok(Duration::from_millis(
+            ms.parse().map_err(|_| invalid_interval())?,
+        ))
+    } else if let Some(secs) = interval.strip_suffix('s') {
+        Ok(Duration::from_secs(
+            secs.parse().map_err(|_| invalid_interval())?,
+        ))
+    } else {
+        Err(invalid_interval())
+    }
+}
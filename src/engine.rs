@@ -1,34 +1,269 @@
-use bitcask::BitcaskEngine;
+use bitcask::{BitcaskEngine, BitcaskOptions};
+use chrono::{DateTime, Local};
+use lock::DirLock;
+use raft::RaftNode;
+use replication::Replication;
+use serde::{Deserialize, Serialize};
 use sled_engine::SledEngine;
-use tracing::{debug, error, info, trace, warn};
-
-use std::io::{BufRead, BufReader, BufWriter, Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::path::Path;
-
+use tracing::{debug, error, info, trace, warn, Level};
+use tracing_subscriber::fmt::time;
+use tracing_subscriber::FmtSubscriber;
+
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use crate::auth::{Access, Authenticator, User};
+use crate::client::{Connector, Credentials};
+use crate::protocol::{decode_pairs, read_frame, Response, Status, CRLF};
 use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use crate::tls::{ServerTlsConfig, Stream, TlsAcceptor, TlsConnector};
 
 use super::{HobbesError, Result};
 
 pub mod bitcask;
+mod lock;
+mod raft;
+mod replication;
 pub mod sled_engine;
+mod watch;
+
+pub use raft::{ClusterConfig, ClusterInfo};
+pub use replication::ReplicationInfo;
+pub use watch::{WatchEvent, Watcher};
 
-const DB_PARENT_PATH: &str = "";
+/// Address servers listen on by default
+pub const DEFAULT_ADDR: &str = "127.0.0.1:4000";
 // Public as constants are accessed in benchmark.rs
 pub const BITCASK_DB_PATH: &str = "bitcask-store/";
 pub const SLED_DB_PATH: &str = "sled-store";
 const BITCASK_LOGS_PATH: &str = "bitcask-store/logs";
-const BITCASK_COMPACTED_LOGS_SUBPATH: &str = "compacted-logs/";
+const RAFT_LOG_PATH: &str = "raft-log";
+// Held by a running server, preventing a second server from opening its data directory
+const SERVER_LOCK_PATH: &str = "hobbes.lock";
+
+/// Response sent to clients attempting to write to a follower
+pub const READ_ONLY_REPLICA_RESPONSE: &str = "Read-only replica";
+/// Response sent by cluster nodes when no leader has been elected
+pub const NO_LEADER_RESPONSE: &str = "No leader elected";
+/// Response sent to clients presenting invalid credentials
+pub const AUTH_FAILED_RESPONSE: &str = "Authentication failed";
+/// Response sent to unauthenticated clients of servers requiring authentication
+pub const AUTH_REQUIRED_RESPONSE: &str = "Authentication required";
+/// Response sent to clients whose user is not permitted to perform the command
+pub const PERMISSION_DENIED_RESPONSE: &str = "Permission denied";
+
+const AUTH_COMMAND_PREFIX: &str = "AUTH\r\n";
+const KEY_NOT_FOUND_RESPONSE: &str = "Key not found";
+const SET_SUCCESS_RESPONSE: &str = "set successful";
+const RM_SUCCESS_RESPONSE: &str = "Success";
+const COMPACT_SUCCESS_RESPONSE: &str = "compaction successful";
 
 pub struct Server<P: ThreadPool> {
     store: EngineType,
     pool: P,
+    stats: Arc<ServerStats>,
+    replication: Arc<Replication>,
+}
+
+/// Options used to start a server
+///
+/// ```no_run
+/// use hobbes::engine::bitcask::{BitcaskOptions, FsyncPolicy};
+/// use hobbes::engine::{self, ServerConfig};
+///
+/// let config = ServerConfig::new()
+///     .addr("127.0.0.1:4000")
+///     .data_dir("/var/lib/hobbes")
+///     .threads(8)
+///     .bitcask(BitcaskOptions::new().fsync(FsyncPolicy::Always));
+/// engine::start_server(&config).expect("server failed");
+/// ```
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Address to listen on
+    pub addr: String,
+    /// Storage engine, either "bitcask" or "sled"
+    pub engine: String,
+    /// Directory holding the storage engine's files and the raft log
+    pub data_dir: PathBuf,
+    /// Number of threads handling connections
+    pub threads: usize,
+    /// Maximum level of the logs written to stderr
+    pub log_level: Level,
+    /// Options used to open the bitcask engine
+    pub bitcask: BitcaskOptions,
+    /// Address of the leader to replicate from, making the server a read-only follower
+    pub replica_of: Option<String>,
+    /// Membership of the Raft cluster the server belongs to, if running in cluster mode
+    pub cluster: Option<ClusterConfig>,
+    /// Certificate and key used to accept TLS connections, if enabled
+    pub tls: Option<ServerTlsConfig>,
+    /// CA verifying the leader or cluster peers, connecting to them over TLS when set. The
+    /// server presents its own certificate to peers which verify clients.
+    pub peer_tls_ca: Option<PathBuf>,
+    /// Users file listing the users permitted to connect. When set, clients must authenticate.
+    pub users: Option<PathBuf>,
+    /// Credentials presented to the leader or cluster peers requiring authentication
+    pub peer_credentials: Option<Credentials>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            addr: String::from(DEFAULT_ADDR),
+            engine: String::from("bitcask"),
+            data_dir: PathBuf::from("."),
+            threads: num_cpus::get(),
+            log_level: Level::INFO,
+            bitcask: BitcaskOptions::default(),
+            replica_of: None,
+            cluster: None,
+            tls: None,
+            peer_tls_ca: None,
+            users: None,
+            peer_credentials: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn new() -> ServerConfig {
+        ServerConfig::default()
+    }
+
+    pub fn addr(mut self, addr: &str) -> ServerConfig {
+        self.addr = addr.to_string();
+        self
+    }
+
+    pub fn engine(mut self, engine: &str) -> ServerConfig {
+        self.engine = engine.to_string();
+        self
+    }
+
+    pub fn data_dir(mut self, data_dir: impl Into<PathBuf>) -> ServerConfig {
+        self.data_dir = data_dir.into();
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> ServerConfig {
+        self.threads = threads;
+        self
+    }
+
+    pub fn log_level(mut self, log_level: Level) -> ServerConfig {
+        self.log_level = log_level;
+        self
+    }
+
+    pub fn bitcask(mut self, bitcask: BitcaskOptions) -> ServerConfig {
+        self.bitcask = bitcask;
+        self
+    }
+
+    pub fn replica_of(mut self, leader: &str) -> ServerConfig {
+        self.replica_of = Some(leader.to_string());
+        self
+    }
+
+    pub fn cluster(mut self, cluster: ClusterConfig) -> ServerConfig {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    pub fn tls(mut self, tls: ServerTlsConfig) -> ServerConfig {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn peer_tls_ca(mut self, ca: impl Into<PathBuf>) -> ServerConfig {
+        self.peer_tls_ca = Some(ca.into());
+        self
+    }
+
+    pub fn users(mut self, users: impl Into<PathBuf>) -> ServerConfig {
+        self.users = Some(users.into());
+        self
+    }
+
+    pub fn peer_credentials(mut self, credentials: Credentials) -> ServerConfig {
+        self.peer_credentials = Some(credentials);
+        self
+    }
 }
 
 pub trait Engine: Clone + Send + 'static {
     fn set(&self, key: String, value: String) -> Result<()>;
+    /// Store many key-value pairs in a single write, returning the number of pairs stored
+    fn set_many(&self, pairs: impl IntoIterator<Item = (String, String)>) -> Result<u64>;
     fn get(&self, key: String) -> Result<Option<String>>;
     fn remove(&self, key: String) -> Result<()>;
+    fn stats(&self) -> Result<EngineStats>;
+    /// Return every key-value pair whose key starts with the prefix, sorted by key
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>>;
+    /// Return the first `limit` key-value pairs whose key starts with the prefix and sorts after
+    /// `after`, sorted by key. Every pair is visited by passing the last key of each page as
+    /// `after` until a page is empty, holding a single page in memory at a time.
+    fn scan_page(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>>;
+    /// Subscribe to every subsequent change to keys starting with the prefix
+    fn watch(&self, prefix: String) -> Result<Watcher>;
+    /// Reclaim the space held by overwritten and removed entries
+    fn compact(&self) -> Result<()>;
+    /// Flush every write to disk, whatever the configured fsync policy
+    fn sync(&self) -> Result<()>;
+}
+
+/// Statistics reported by a storage engine
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// Name of the storage engine
+    pub engine: String,
+    /// Number of live keys in the store
+    pub keys: u64,
+    /// Number of log segments on disk
+    pub segments: u64,
+    /// Total size of the log segments in bytes
+    pub segments_size: u64,
+    /// Fraction of the on-disk bytes occupied by overwritten or deleted entries
+    pub dead_bytes_ratio: f64,
+    /// Memory held by the in-memory index in bytes, if the engine keeps one
+    pub index_bytes: Option<u64>,
+    /// Number of compactions performed since the engine was opened
+    pub compactions: u64,
+    /// Time at which the last compaction finished
+    pub last_compaction_at: Option<DateTime<Local>>,
+    /// Duration of the last compaction in milliseconds
+    pub last_compaction_ms: Option<u64>,
+}
+
+/// Response to the INFO command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub version: String,
+    pub uptime_secs: u64,
+    pub connections_active: u64,
+    pub connections_total: u64,
+    pub engine: EngineStats,
+    pub replication: ReplicationInfo,
+}
+
+/// Counters maintained by a running server
+#[derive(Debug)]
+struct ServerStats {
+    started_at: Instant,
+    connections_active: AtomicU64,
+    connections_total: AtomicU64,
 }
 
 #[derive(Clone)]
@@ -38,6 +273,12 @@ enum EngineType {
 }
 
 impl Engine for EngineType {
+    fn set_many(&self, pairs: impl IntoIterator<Item = (String, String)>) -> Result<u64> {
+        match self {
+            EngineType::Bitcask(bitcask_engine) => bitcask_engine.set_many(pairs),
+            EngineType::Sled(sled_engine) => sled_engine.set_many(pairs),
+        }
+    }
     fn set(&self, key: String, value: String) -> Result<()> {
         match self {
             EngineType::Bitcask(bitcask_engine) => bitcask_engine.set(key, value),
@@ -56,21 +297,131 @@ impl Engine for EngineType {
             EngineType::Sled(sled_engine) => sled_engine.remove(key),
         }
     }
+    fn stats(&self) -> Result<EngineStats> {
+        match self {
+            EngineType::Bitcask(bitcask_engine) => bitcask_engine.stats(),
+            EngineType::Sled(sled_engine) => sled_engine.stats(),
+        }
+    }
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        match self {
+            EngineType::Bitcask(bitcask_engine) => bitcask_engine.scan(prefix),
+            EngineType::Sled(sled_engine) => sled_engine.scan(prefix),
+        }
+    }
+    fn scan_page(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        match self {
+            EngineType::Bitcask(bitcask_engine) => bitcask_engine.scan_page(prefix, after, limit),
+            EngineType::Sled(sled_engine) => sled_engine.scan_page(prefix, after, limit),
+        }
+    }
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        match self {
+            EngineType::Bitcask(bitcask_engine) => bitcask_engine.watch(prefix),
+            EngineType::Sled(sled_engine) => sled_engine.watch(prefix),
+        }
+    }
+    fn compact(&self) -> Result<()> {
+        match self {
+            EngineType::Bitcask(bitcask_engine) => bitcask_engine.compact(),
+            EngineType::Sled(sled_engine) => sled_engine.compact(),
+        }
+    }
+
+    fn sync(&self) -> Result<()> {
+        match self {
+            EngineType::Bitcask(bitcask_engine) => bitcask_engine.sync(),
+            EngineType::Sled(sled_engine) => sled_engine.sync(),
+        }
+    }
 }
 
-pub fn start_server(addr: &str, engine: &str) -> Result<()> {
+pub fn start_server(config: &ServerConfig) -> Result<()> {
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(config.log_level)
+        .with_timer(time::ChronoLocal::rfc_3339())
+        .with_target(true)
+        .with_writer(io::stderr)
+        .finish();
+    // Ignoring error as the embedding application may have set its own subscriber
+    let _ = tracing::subscriber::set_global_default(subscriber);
+
     trace!("Server starting");
+    let addr = config.addr.as_str();
+    if config.threads == 0 {
+        Err(HobbesError::CliError(String::from(
+            "the server requires at least one thread",
+        )))?
+    }
+
+    fs::create_dir_all(&config.data_dir)?;
+    let _lock = DirLock::exclusive(&config.data_dir.join(SERVER_LOCK_PATH))?;
+    let store = match config.engine.as_str() {
+        "bitcask" => EngineType::Bitcask(config.bitcask.open(&config.data_dir)?),
+        "sled" if config.bitcask.keyring.is_some() => Err(HobbesError::CliError(String::from(
+            "encryption at rest is only supported by the bitcask engine",
+        )))?,
+        "sled" if config.bitcask.compression.is_some() => Err(HobbesError::CliError(
+            String::from("compression is only supported by the bitcask engine"),
+        ))?,
+        "sled" => EngineType::Sled(sled_engine::SledEngine::open(&config.data_dir)?),
+        _ => Err(HobbesError::CliError(String::from("invalid engine")))?,
+    };
+    let acceptor = config.tls.as_ref().map(TlsAcceptor::new).transpose()?;
+    let peer_tls = match &config.peer_tls_ca {
+        Some(ca) => Some(TlsConnector::new(
+            ca,
+            config
+                .tls
+                .as_ref()
+                .map(|tls| (tls.cert.as_path(), tls.key.as_path())),
+        )?),
+        None => None,
+    };
+    let peer_connector = Connector {
+        tls: peer_tls,
+        credentials: config.peer_credentials.clone(),
+    };
+    let auth = match &config.users {
+        Some(users) => Some(Arc::new(Authenticator::load(users)?)),
+        None => None,
+    };
+    let replication = match (&config.cluster, &config.replica_of) {
+        (Some(_), Some(_)) => Err(HobbesError::CliError(String::from(
+            "a cluster node cannot replicate from another server",
+        )))?,
+        (Some(cluster), None) => Replication::cluster(RaftNode::start(
+            cluster,
+            store.clone(),
+            &config.data_dir.join(RAFT_LOG_PATH),
+            peer_connector.clone(),
+        )?),
+        (None, Some(leader)) => Replication::follower(leader.clone(), peer_connector),
+        (None, None) => Replication::leader(),
+    };
+
     let server = Server {
-        store: match engine {
-            "bitcask" => {
-                EngineType::Bitcask(bitcask::BitcaskEngine::open(Path::new(&DB_PARENT_PATH))?)
-            }
-            "sled" => EngineType::Sled(sled_engine::SledEngine::open(Path::new(&DB_PARENT_PATH))?),
-            _ => Err(HobbesError::CliError(String::from("invalid engine")))?,
-        },
-        pool: SharedQueueThreadPool::new(num_cpus::get() as u32)?,
+        store,
+        pool: SharedQueueThreadPool::new(config.threads as u32)?,
+        stats: Arc::new(ServerStats {
+            started_at: Instant::now(),
+            connections_active: AtomicU64::new(0),
+            connections_total: AtomicU64::new(0),
+        }),
+        replication: Arc::new(replication),
     };
 
+    if server.replication.is_follower() {
+        let store_clone = server.store.clone();
+        let replication_clone = server.replication.clone();
+        thread::spawn(move || replication::follow(store_clone, replication_clone));
+    }
+
     trace!("Listener starting");
     let listener = TcpListener::bind(addr)?;
     trace!("Listener started");
@@ -78,16 +429,56 @@ pub fn start_server(addr: &str, engine: &str) -> Result<()> {
     for tcp_stream in listener.incoming().flatten() {
         let addr_clone = addr.to_owned();
         let store_clone = server.store.clone();
+        let stats_clone = server.stats.clone();
+        let replication_clone = server.replication.clone();
+        let acceptor_clone = acceptor.clone();
+        let auth_clone = auth.clone();
 
         server.pool.spawn(move || {
-            req_handler(store_clone, tcp_stream, addr_clone);
+            let stream = match acceptor_clone {
+                Some(acceptor) => match acceptor.accept(tcp_stream) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("Error while accepting TLS connection -> {e}");
+                        return;
+                    }
+                },
+                None => Stream::Plain(tcp_stream),
+            };
+
+            stats_clone
+                .connections_active
+                .fetch_add(1, Ordering::Relaxed);
+            stats_clone
+                .connections_total
+                .fetch_add(1, Ordering::Relaxed);
+
+            req_handler(
+                store_clone,
+                stream,
+                addr_clone,
+                &stats_clone,
+                replication_clone,
+                auth_clone.as_deref(),
+            );
+
+            stats_clone
+                .connections_active
+                .fetch_sub(1, Ordering::Relaxed);
         });
     }
 
     Ok(())
 }
 
-fn req_handler(store: EngineType, mut tcp_stream: TcpStream, addr: String) {
+fn req_handler(
+    store: EngineType,
+    mut tcp_stream: Stream,
+    addr: String,
+    stats: &ServerStats,
+    replication: Arc<Replication>,
+    auth: Option<&Authenticator>,
+) {
     let peer_addr = match tcp_stream.peer_addr() {
         Ok(addr) => addr,
         Err(e) => {
@@ -101,52 +492,51 @@ fn req_handler(store: EngineType, mut tcp_stream: TcpStream, addr: String) {
     info!("==============================================");
     info!(client_addr = %peer_addr, msg = "client connected");
 
-    // Extracting the command length from the client request
-    let mut cmd_prefix = String::new();
-    if let Err(e) = reader.read_line(&mut cmd_prefix) {
-        error!("Error while reading line from TCP stream -> {e}");
-        return;
-    }
-    let cmd_prefix_str = match cmd_prefix.strip_suffix("\r\n") {
-        Some(val) => val,
-        None => {
-            error!("network command prefix not appended with \r\n, command = {cmd_prefix}");
-            return;
-        }
-    };
-
-    debug!(
-        server_addr = addr,
-        client_addr = %peer_addr,
-        cmd_prefix = cmd_prefix,
-        cmd_prefix_stripped = cmd_prefix_str,
-        "Extracted command length from client request"
-    );
-    let cmd_len = match cmd_prefix_str.parse::<usize>() {
+    let mut cmd_str = match read_frame(&mut reader) {
         Ok(val) => val,
-        Err(err) => {
-            error!(err = %err, "failed to parse the command length");
+        Err(e) => {
+            error!("Error while reading command from client -> {e}");
             return;
         }
     };
 
-    // Reading the command from the server
-    let mut cmd_bytes = vec![0u8; cmd_len];
-    if let Err(e) = reader.read_exact(&mut cmd_bytes) {
-        error!("Error while reading exact bytes from command -> {e}");
-        return;
-    }
+    // Clients holding credentials authenticate before sending their command
+    let mut user = None;
+    if cmd_str.starts_with(AUTH_COMMAND_PREFIX) {
+        let mut args = cmd_str.split(CRLF).skip(1);
+        let name = args.next().unwrap_or_default();
+        let secret = args.next().unwrap_or_default();
+
+        // Servers without a users file accept any credentials
+        if let Some(auth) = auth {
+            match auth.authenticate(name, secret) {
+                Some(authenticated) => {
+                    debug!(client_addr = %peer_addr, user = name, "Client authenticated");
+                    user = Some(authenticated);
+                }
+                None => {
+                    warn!(client_addr = %peer_addr, user = name, "Authentication failed");
+                    let resp = Response::new(Status::AuthError, AUTH_FAILED_RESPONSE);
+                    if let Err(e) = write_response(reader.get_mut(), &resp) {
+                        error!("Error while writing response to client -> {e}");
+                    }
+                    return;
+                }
+            }
+        }
 
-    let cmd_str = match String::from_utf8(cmd_bytes.clone()) {
-        Ok(val) => val,
-        Err(err) => {
-            error!(
-                err = %err,
-                "failed to parse command from client, command_bytes = {:?}", cmd_bytes
-            );
+        if let Err(e) = write_response(reader.get_mut(), &Response::ok("")) {
+            error!("Error while writing response to client -> {e}");
             return;
         }
-    };
+        cmd_str = match read_frame(&mut reader) {
+            Ok(val) => val,
+            Err(e) => {
+                error!("Error while reading command from client -> {e}");
+                return;
+            }
+        };
+    }
 
     debug!(
         server_addr = addr,
@@ -164,53 +554,152 @@ fn req_handler(store: EngineType, mut tcp_stream: TcpStream, addr: String) {
         return;
     }
 
+    if let Some(auth_denial) = auth.and_then(|_| authorize(user, cmd, msg.clone())) {
+        warn!(
+            client_addr = %peer_addr,
+            user = user.map(|user| user.name.as_str()),
+            cmd = cmd,
+            response = auth_denial,
+            "Rejected command"
+        );
+        let resp = Response::new(Status::AuthError, auth_denial);
+        if let Err(e) = write_response(reader.get_mut(), &resp) {
+            error!("Error while writing response to client -> {e}");
+        }
+        return;
+    }
+
     // let mut resp = String::from("Success");
-    let resp;
-    match cmd {
-        "GET" => match handle_get(store, msg) {
-            Ok(res) => resp = res,
-            Err(e) => {
-                error!("Failed to handle get command for request = {cmd_str}, error = {e}");
-                return;
-            }
-        },
-        "SET" => {
-            if let Err(e) = handle_set(store, msg) {
-                error!("Failed to handle set command for request = {cmd_str}, error = {e}");
-                return;
-            } else {
-                resp = String::from("set successful");
-            }
+    let resp = match cmd {
+        "GET" => handle_get(store, msg, &replication),
+        "SET" | "RM" | "MSET" if replication.is_follower() => {
+            Ok(Response::new(Status::ReadOnly, READ_ONLY_REPLICA_RESPONSE))
         }
-        "RM" => match handle_rm(store, msg) {
-            Ok(res) => resp = res,
-            Err(e) => {
-                error!("Failed to handle rm command for request = {cmd_str}, error = {e}");
-                return;
+        "SET" => handle_set(store, msg, &replication),
+        "MSET" => handle_mset(store, msg, &replication),
+        "RM" => handle_rm(store, msg, &replication),
+        "SCAN" => handle_scan(store, msg, &replication),
+        "INFO" => handle_info(store, stats, &replication),
+        "COMPACT" => handle_compact(store),
+        "SYNC" => {
+            info!(client_addr = %peer_addr, "Received replication request");
+            // Followers hold the connection open indefinitely, so they are served on a
+            // dedicated thread instead of occupying a worker from the pool
+            thread::spawn(move || {
+                if let Err(e) = replication::serve_follower(store, replication, tcp_stream) {
+                    warn!(follower_addr = %peer_addr, "Stopped replicating to follower -> {e}");
+                }
+            });
+            return;
+        }
+        "WATCH" => {
+            // A missing prefix watches every key
+            let prefix = msg.next().unwrap_or_default().trim().to_string();
+            info!(client_addr = %peer_addr, cmd = "WATCH", prefix = prefix, "Received command");
+            // Watchers hold the connection open, so they are served on a dedicated thread
+            thread::spawn(move || {
+                if let Err(e) = watch::serve_watcher(store, prefix, tcp_stream) {
+                    debug!(client_addr = %peer_addr, "Stopped streaming to watcher -> {e}");
+                }
+            });
+            return;
+        }
+        "RAFT" => {
+            match replication.cluster_node() {
+                Some(node) => {
+                    if let Err(e) = node.handle_rpc(&mut reader) {
+                        warn!(peer_addr = %peer_addr, "Failed to handle raft request -> {e}");
+                    }
+                }
+                None => error!("Received raft request while not running in cluster mode"),
             }
-        },
+            return;
+        }
         _ => {
             error!(cmd = cmd, "Invalid command");
-            resp = String::from("Invalid command");
+            Ok(Response::error("Invalid command"))
         }
-    }
+    };
 
-    let mut writer = BufWriter::new(&tcp_stream);
-    debug!(bytes = resp.len(), msg = "server response");
-    if let Err(e) = writer.write_all(resp.as_bytes()) {
+    // Failures are reported with their own status, so that clients never read an error as a
+    // value
+    let resp = match resp {
+        Ok(resp) => resp,
+        Err(HobbesError::NotLeaderError(Some(leader))) => Response::new(Status::Redirect, leader),
+        Err(HobbesError::NotLeaderError(None)) => {
+            Response::new(Status::NoLeader, NO_LEADER_RESPONSE)
+        }
+        Err(e) => {
+            error!("Failed to handle {cmd} command for request = {cmd_str}, error = {e}");
+            Response::error(e.to_string())
+        }
+    };
+
+    let mut writer = BufWriter::new(&mut tcp_stream);
+    debug!(bytes = resp.payload.len(), msg = "server response");
+    if let Err(e) = write_response(&mut writer, &resp) {
         error!("Error while writing to response to client -> {e}");
         return;
     }
 
-    if let Err(e) = writer.flush() {
-        error!("Error while flushing to response to client -> {e}");
-        return;
+    debug!(
+        cmd = cmd,
+        status = %resp.status,
+        response = resp.payload,
+        "Sent response to client"
+    );
+}
+
+fn write_response(writer: &mut impl Write, resp: &Response) -> Result<()> {
+    writer.write_all(resp.encode().as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Return the response rejecting the command if the user is not permitted to perform it
+fn authorize<'a>(
+    user: Option<&User>,
+    cmd: &str,
+    mut args: impl Iterator<Item = &'a str>,
+) -> Option<&'static str> {
+    let Some(user) = user else {
+        return Some(AUTH_REQUIRED_RESPONSE);
+    };
+    let arg = args.next();
+
+    // Every key of a batch must be writable. Malformed batches are rejected by the handler.
+    if cmd == "MSET" {
+        let Ok(pairs) = decode_pairs(arg.unwrap_or_default()) else {
+            return None;
+        };
+        let permitted = pairs
+            .iter()
+            .all(|(key, _)| user.permits(Access::Write, Some(key.trim())));
+        return (!permitted).then_some(PERMISSION_DENIED_RESPONSE);
     }
 
-    debug!(cmd = cmd, response = resp, "Sent response to client");
+    let (access, key) = match cmd {
+        // A missing prefix scans or watches every key
+        "GET" | "SCAN" | "WATCH" => (Access::Read, Some(arg.unwrap_or_default().trim())),
+        "INFO" => (Access::Read, None),
+        "SET" | "RM" => (Access::Write, Some(arg.unwrap_or_default().trim())),
+        "COMPACT" | "SYNC" | "RAFT" => (Access::Admin, None),
+        // Invalid commands are rejected by the handler
+        _ => return None,
+    };
+
+    if user.permits(access, key) {
+        None
+    } else {
+        Some(PERMISSION_DENIED_RESPONSE)
+    }
 }
 
-fn handle_get<'a>(store: EngineType, mut msg: impl Iterator<Item = &'a str>) -> Result<String> {
+fn handle_get<'a>(
+    store: EngineType,
+    mut msg: impl Iterator<Item = &'a str>,
+    replication: &Replication,
+) -> Result<Response> {
     let key = msg
         .next()
         .ok_or(HobbesError::CliError(String::from(
@@ -219,37 +708,76 @@ fn handle_get<'a>(store: EngineType, mut msg: impl Iterator<Item = &'a str>) ->
         .trim();
     info!(cmd = "GET", key = key, "Received command");
 
+    // Reads in cluster mode are served by the leader, which holds every committed write
+    if let Some(node) = replication.cluster_node() {
+        node.check_leader()?;
+    }
+
     if let Some(val) = store.get(key.to_string())? {
         info!(cmd = "GET", key = key, val = val, "Successful query");
-        Ok(val)
+        Ok(Response::ok(val))
     } else {
         warn!(cmd = "GET", key = key, "Key not found");
-        Ok(String::from("Key not found"))
+        Ok(Response::new(Status::NotFound, KEY_NOT_FOUND_RESPONSE))
     }
 }
 
-fn handle_set<'a>(store: EngineType, mut msg: impl Iterator<Item = &'a str>) -> Result<()> {
+fn handle_set<'a>(
+    store: EngineType,
+    mut msg: impl Iterator<Item = &'a str>,
+    replication: &Replication,
+) -> Result<Response> {
     let key = msg
         .next()
         .ok_or(HobbesError::CliError(String::from(
             "Missing key in SET command",
         )))?
         .trim();
-    let val = msg
-        .next()
-        .ok_or(HobbesError::CliError(String::from(
-            "Missing value in SET command",
-        )))?
-        .trim();
+    let val = msg.next().ok_or(HobbesError::CliError(String::from(
+        "Missing value in SET command",
+    )))?;
     info!(cmd = "SET", key = key, val = val, "Received command");
 
-    store.set(key.to_string(), val.to_string())?;
+    replication.set(&store, key.to_string(), val.to_string())?;
     info!(cmd = "SET", key = key, val = val, "Successful query");
 
-    Ok(())
+    Ok(Response::ok(SET_SUCCESS_RESPONSE))
 }
 
-fn handle_rm<'a>(store: EngineType, mut msg: impl Iterator<Item = &'a str>) -> Result<String> {
+fn handle_mset<'a>(
+    store: EngineType,
+    mut msg: impl Iterator<Item = &'a str>,
+    replication: &Replication,
+) -> Result<Response> {
+    // Keys are trimmed as in SET, while values are stored exactly as sent
+    let pairs: Vec<(String, String)> = decode_pairs(msg.next().unwrap_or_default())
+        .map_err(|e| {
+            HobbesError::CliError(format!(
+                "MSET command expects a JSON array of key-value pairs, {e}"
+            ))
+        })?
+        .into_iter()
+        .map(|(key, val)| (key.trim().to_string(), val))
+        .collect();
+    if pairs.is_empty() {
+        Err(HobbesError::CliError(String::from(
+            "MSET command expects at least one key-value pair",
+        )))?
+    }
+    info!(cmd = "MSET", pairs = pairs.len(), "Received command");
+
+    let count = pairs.len();
+    replication.set_many(&store, pairs)?;
+    info!(cmd = "MSET", pairs = count, "Successful query");
+
+    Ok(Response::ok(SET_SUCCESS_RESPONSE))
+}
+
+fn handle_rm<'a>(
+    store: EngineType,
+    mut msg: impl Iterator<Item = &'a str>,
+    replication: &Replication,
+) -> Result<Response> {
     let key = msg
         .next()
         .ok_or(HobbesError::CliError(String::from(
@@ -258,17 +786,102 @@ fn handle_rm<'a>(store: EngineType, mut msg: impl Iterator<Item = &'a str>) -> R
         .trim();
     info!(cmd = "RM", key = key, "Received command");
 
-    match store.remove(key.to_string()) {
+    match replication.remove(&store, key.to_string()) {
         Ok(_) => {
             info!(cmd = "RM", key = key, "Successful query");
-            Ok(String::from("Success"))
+            Ok(Response::ok(RM_SUCCESS_RESPONSE))
         }
         Err(err) => match err {
             HobbesError::KeyNotFoundError => {
                 info!(cmd = "RM", key = key, "Key not found");
-                Ok(String::from("Key not found"))
+                Ok(Response::new(Status::NotFound, KEY_NOT_FOUND_RESPONSE))
             }
             _ => Err(err),
         },
     }
 }
+
+fn handle_scan<'a>(
+    store: EngineType,
+    mut msg: impl Iterator<Item = &'a str>,
+    replication: &Replication,
+) -> Result<Response> {
+    // A missing prefix scans every key, and a missing limit returns every pair at once
+    let prefix = msg.next().unwrap_or_default().trim();
+    let (limit, after) = parse_scan_page(msg)?;
+    info!(
+        cmd = "SCAN",
+        prefix = prefix,
+        limit = limit,
+        "Received command"
+    );
+
+    if let Some(node) = replication.cluster_node() {
+        node.check_leader()?;
+    }
+
+    let pairs = match limit {
+        Some(limit) => store.scan_page(prefix.to_string(), after, limit)?,
+        None => store.scan(prefix.to_string())?,
+    };
+    info!(
+        cmd = "SCAN",
+        prefix = prefix,
+        count = pairs.len(),
+        "Successful query"
+    );
+    Ok(Response::ok(serde_json::to_string(&pairs)?))
+}
+
+/// Parse the optional limit of a SCAN command and the key its page starts after, which is
+/// passed as sent rather than trimmed
+pub(crate) fn parse_scan_page<'a>(
+    mut args: impl Iterator<Item = &'a str>,
+) -> Result<(Option<usize>, Option<String>)> {
+    let limit = match args.next().map(str::trim).filter(|limit| !limit.is_empty()) {
+        Some(limit) => match limit.parse::<usize>() {
+            Ok(limit) if limit > 0 => Some(limit),
+            _ => Err(HobbesError::CliError(format!(
+                "SCAN limit must be a positive integer, limit = {limit}"
+            )))?,
+        },
+        None => None,
+    };
+    let after = args
+        .next()
+        .filter(|after| limit.is_some() && !after.is_empty())
+        .map(String::from);
+    Ok((limit, after))
+}
+
+fn handle_info(
+    store: EngineType,
+    stats: &ServerStats,
+    replication: &Replication,
+) -> Result<Response> {
+    info!(cmd = "INFO", "Received command");
+
+    let server_info = ServerInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        uptime_secs: stats.started_at.elapsed().as_secs(),
+        connections_active: stats.connections_active.load(Ordering::Relaxed),
+        connections_total: stats.connections_total.load(Ordering::Relaxed),
+        engine: store.stats()?,
+        replication: replication.info(),
+    };
+
+    Ok(Response::ok(serde_json::to_string(&server_info)?))
+}
+
+// Followers and cluster nodes compact their own store, as compaction leaves its contents unchanged
+fn handle_compact(store: EngineType) -> Result<Response> {
+    info!(cmd = "COMPACT", "Received command");
+    let compaction_start = Instant::now();
+    store.compact()?;
+    info!(
+        cmd = "COMPACT",
+        duration_ms = compaction_start.elapsed().as_millis() as u64,
+        "Successful compaction"
+    );
+    Ok(Response::ok(COMPACT_SUCCESS_RESPONSE))
+}
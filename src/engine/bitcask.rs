@@ -1,63 +1,412 @@
 use chrono::{DateTime, Local};
+use memmap2::Mmap;
+use rmp::decode::ValueReadError;
 use rmp_serde::{self, decode};
-use tracing::{error, trace};
+use tracing::{debug, error, info, trace};
 use tracing_subscriber::fmt::time;
 use tracing_subscriber::FmtSubscriber;
 
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BinaryHeap, HashMap};
 use std::env;
 use std::fs::{self, File, OpenOptions};
-use std::io::{BufReader, Seek, SeekFrom, Write};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
 
 use crate::engine::BITCASK_DB_PATH;
 use crate::RWLOCK_ERROR;
 
-use super::{Engine, HobbesError, Result, BITCASK_LOGS_PATH, SLED_DB_PATH};
+use super::lock::DirLock;
+use super::watch::{WatchHub, Watcher};
+use super::{Engine, EngineStats, HobbesError, Result, BITCASK_LOGS_PATH, SLED_DB_PATH};
 
+mod check;
 mod compaction;
+mod compression;
+mod encryption;
+mod header;
+mod hint;
+mod keydir;
+mod loader;
+mod metadata;
+mod options;
+mod segment;
+
+pub use check::{check, CheckReport, RecordProblem, SegmentReport};
+pub use compression::{Codec, Compression};
+pub use encryption::{Keyring, ENCRYPTION_KEY_ENV};
+pub use header::SegmentInfo;
+pub use loader::{BulkLoadReport, BulkLoader};
+pub use options::{BitcaskOptions, CompactionWindow, FsyncPolicy};
+pub use segment::{SegmentReader, SegmentRecord};
+
+use compression::COMPRESSED_RECORD_EXT_TYPE;
+use encryption::ENCRYPTED_RECORD_EXT_TYPE;
+use header::{read_header, write_header, FORMAT_VERSION, LEGACY_FORMAT_VERSION};
+use hint::{hint_path, read_hint, HINT_EXTENSION};
+use keydir::{ConcurrentKeyDir, KeyDir};
+use metadata::StoreMetadata;
+
+/// Whether a record sets its key or removes it
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum RecordType {
+    Put,
+    Delete,
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct LogEntry {
+    record_type: RecordType,
+    key: String,
+    // Empty for deletes
+    val: String,
+    timestamp: DateTime<Local>,
+}
+
+impl LogEntry {
+    fn put(key: String, val: String, timestamp: DateTime<Local>) -> LogEntry {
+        LogEntry {
+            record_type: RecordType::Put,
+            key,
+            val,
+            timestamp,
+        }
+    }
+
+    fn delete(key: String, timestamp: DateTime<Local>) -> LogEntry {
+        LogEntry {
+            record_type: RecordType::Delete,
+            key,
+            val: String::new(),
+            timestamp,
+        }
+    }
+
+    fn is_delete(&self) -> bool {
+        self.record_type == RecordType::Delete
+    }
+}
+
+/// Record of segments without a header, removing its key if the value is LEGACY_TOMBSTONE
+#[derive(Debug, Deserialize)]
+struct LegacyLogEntry {
     key: String,
     val: String,
     timestamp: DateTime<Local>,
 }
 
-/// KvStore holds the in-memory index with keys and log pointers
+impl From<LegacyLogEntry> for LogEntry {
+    fn from(entry: LegacyLogEntry) -> LogEntry {
+        if entry.val == LEGACY_TOMBSTONE {
+            LogEntry::delete(entry.key, entry.timestamp)
+        } else {
+            LogEntry::put(entry.key, entry.val, entry.timestamp)
+        }
+    }
+}
+
+/// Reader of a segment, along with the format version of its records
+#[derive(Debug)]
+struct LogReader {
+    reader: BufReader<File>,
+    version: u16,
+    // Offset of the first record
+    header_len: u64,
+}
+
+impl LogReader {
+    fn open(path: &Path) -> Result<LogReader> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let version = read_header(&mut reader)?.version;
+        Ok(LogReader {
+            header_len: reader.stream_position()?,
+            reader,
+            version,
+        })
+    }
+}
+
+/// Random access to the records of a segment. Sealed segments are memory-mapped, while the active
+/// log, which keeps growing, is read with positional reads, so that reads need neither a seek nor
+/// exclusive access.
+///
+/// Truncating a mapped segment would fault reads of the bytes cut off with SIGBUS. Sealed segments
+/// are never written again, merges remove them rather than truncate them, and repairs truncate
+/// them while holding the store lock exclusively, which no open store, read-only or not, lets
+/// them take. Maps outlive the removal of their segment, as the file is only released once
+/// unmapped.
+#[derive(Debug)]
+enum SegmentFile {
+    Mapped { map: Mmap, version: u16 },
+    Active { file: File, version: u16 },
+}
+
+impl SegmentFile {
+    fn open(path: &Path, active: bool) -> Result<SegmentFile> {
+        let file = File::open(path)?;
+        let version = read_header(&mut BufReader::new(&file))?.version;
+        if active {
+            Ok(SegmentFile::Active { file, version })
+        } else {
+            // SAFETY: the segment is sealed and the store lock keeps it from being truncated while
+            // mapped, as described above
+            let map = unsafe { Mmap::map(&file)? };
+            Ok(SegmentFile::Mapped { map, version })
+        }
+    }
+
+    fn version(&self) -> u16 {
+        match self {
+            SegmentFile::Mapped { version, .. } | SegmentFile::Active { version, .. } => *version,
+        }
+    }
+
+    /// Read the bytes of the record at the offset
+    fn read_record(&self, offset: u64, len: u64) -> Result<Cow<'_, [u8]>> {
+        match self {
+            SegmentFile::Mapped { map, .. } => map
+                .get(offset as usize..(offset + len) as usize)
+                .map(Cow::Borrowed)
+                .ok_or_else(|| {
+                    HobbesError::IoError(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("record at offset {offset} runs past the end of the segment"),
+                    ))
+                }),
+            SegmentFile::Active { file, .. } => {
+                let mut record = vec![0u8; len as usize];
+                file.read_exact_at(&mut record, offset)?;
+                Ok(Cow::Owned(record))
+            }
+        }
+    }
+}
+
+/// Open segment files by id, shared with reads in progress, which may outlive the removal of a
+/// merged segment
+#[derive(Debug, Default)]
+struct SegmentFiles {
+    files: RwLock<HashMap<u64, Arc<SegmentFile>>>,
+}
+
+impl SegmentFiles {
+    fn get(&self, log_id: u64) -> Option<Arc<SegmentFile>> {
+        self.files.read().expect(RWLOCK_ERROR).get(&log_id).cloned()
+    }
+
+    fn insert(&self, log_id: u64, segment_file: SegmentFile) {
+        self.files
+            .write()
+            .expect(RWLOCK_ERROR)
+            .insert(log_id, Arc::new(segment_file));
+    }
+
+    fn remove(&self, log_id: u64) {
+        self.files.write().expect(RWLOCK_ERROR).remove(&log_id);
+    }
+}
+
+/// KvStore holds the in-memory index with keys and log pointers. The index and the segment files
+/// are shared with reads, which do not lock the store, while updates to the index are only made
+/// with the store locked for writes.
 #[derive(Debug)]
 pub struct BitcaskStore {
-    mem_index: HashMap<String, ValueMetadata>,
+    mem_index: Arc<ConcurrentKeyDir>,
+    segment_usage: HashMap<u64, SegmentUsage>,
     // logs_dir holds the path to the directory containing active logs
     logs_dir: PathBuf,
-    // db_dir holds the path to the directory used by the database,
-    // including the active and compacted logs sub-directories
-    db_dir: PathBuf,
     log_writer: Option<File>,
-    log_readers: Option<HashMap<u64, BufReader<File>>>,
+    segment_files: Arc<SegmentFiles>,
     current_log_id: u64,
+    // Set when a segment is sealed, until the sealed segments are considered for a merge
+    merge_pending: bool,
+    compaction_count: u64,
+    last_compaction_at: Option<DateTime<Local>>,
+    last_compaction_duration: Option<Duration>,
+    // Held until the last handle to the store is dropped, keeping other openers out. Absent for
+    // read-only openers of stores without a lock file.
+    _lock: Option<DirLock>,
 }
 
-#[derive(Debug, Clone)]
+/// Bytes of a segment held by its header, by records in the index and by tombstones. The rest are
+/// dead records, reclaimed by merging the segment.
+#[derive(Debug, Clone, Default)]
+struct SegmentUsage {
+    size: u64,
+    header_bytes: u64,
+    live_bytes: u64,
+    tombstone_bytes: u64,
+}
+
+impl SegmentUsage {
+    fn dead_bytes(&self) -> u64 {
+        self.size
+            .saturating_sub(self.header_bytes + self.live_bytes + self.tombstone_bytes)
+    }
+
+    /// Fraction of the records of the segment which are dead
+    fn dead_ratio(&self) -> f64 {
+        let records_size = self.size.saturating_sub(self.header_bytes);
+        if records_size == 0 {
+            return 0.0;
+        }
+        self.dead_bytes() as f64 / records_size as f64
+    }
+}
+
+/// Position of the live record of a key, with the segment id and length packed into 32 bits each
+/// to keep the index small
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct ValueMetadata {
     log_pointer: u64,
-    log_id: u64,
-    timestamp: DateTime<Local>,
+    log_id: u32,
+    // entry_len holds the size of the serialized log entry in bytes
+    entry_len: u32,
+}
+
+impl ValueMetadata {
+    fn new(log_id: u64, log_pointer: u64, entry_len: u64) -> Result<ValueMetadata> {
+        let log_id = u32::try_from(log_id).map_err(|_| {
+            HobbesError::IoError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("segment id {log_id} exceeds the limit of {}", u32::MAX),
+            ))
+        })?;
+        Ok(ValueMetadata {
+            log_pointer,
+            log_id,
+            entry_len: checked_record_len(entry_len)?,
+        })
+    }
+
+    fn log_id(&self) -> u64 {
+        self.log_id.into()
+    }
+
+    fn entry_len(&self) -> u64 {
+        self.entry_len.into()
+    }
+}
+
+impl BitcaskStore {
+    /// Index a record appended to a segment, moving the bytes of the record it replaces from
+    /// live to dead
+    fn index_insert(&mut self, key: &str, value_metadata: ValueMetadata) {
+        self.segment_usage
+            .entry(value_metadata.log_id())
+            .or_default()
+            .live_bytes += value_metadata.entry_len();
+        if let Some(replaced) = self.mem_index.insert(key, value_metadata) {
+            self.release(&replaced);
+        }
+    }
+
+    /// Remove a key from the index, moving the bytes of its record from live to dead
+    fn index_remove(&mut self, key: &str) -> Option<ValueMetadata> {
+        let removed = self.mem_index.remove(key)?;
+        self.release(&removed);
+        Some(removed)
+    }
+
+    fn release(&mut self, value_metadata: &ValueMetadata) {
+        if let Some(usage) = self.segment_usage.get_mut(&value_metadata.log_id()) {
+            usage.live_bytes = usage.live_bytes.saturating_sub(value_metadata.entry_len());
+        }
+    }
+
+    /// Account for bytes appended to the active log
+    fn record_append(&mut self, len: u64, tombstone: bool) {
+        let usage = self.segment_usage.entry(self.current_log_id).or_default();
+        usage.size += len;
+        if tombstone {
+            usage.tombstone_bytes += len;
+        }
+    }
+
+    /// Id after every segment on disk and the active log
+    fn next_log_id(&self) -> u64 {
+        self.segment_usage
+            .keys()
+            .copied()
+            .chain([self.current_log_id])
+            .max()
+            .unwrap_or_default()
+            + 1
+    }
+
+    /// Create the active log if the last one was sealed
+    fn open_active_log(&mut self) -> Result<()> {
+        if self.log_writer.is_some() {
+            return Ok(());
+        }
+        trace!(operation = "LOG_WRITER_INIT");
+
+        let write_log_path = self.logs_dir.join(PathBuf::from(format!(
+            "{}{LOG_EXTENSION}",
+            self.current_log_id
+        )));
+        let log_writer = create_log(&write_log_path).inspect_err(|_| {
+            error!("[LOG_WRITER_INIT] Error while creating a new mutable append log - log writer path -> {:?}", write_log_path);
+        })?;
+        // The log is new, holding only its header
+        let usage = self.segment_usage.entry(self.current_log_id).or_default();
+        usage.size = log_writer.metadata()?.len();
+        usage.header_bytes = usage.size;
+        self.log_writer = Some(log_writer);
+        self.segment_files.insert(
+            self.current_log_id,
+            SegmentFile::open(&write_log_path, true).inspect_err(|_| {
+                error!("[LOG_WRITER_INIT] Error while creating a reader for the new mutable append log - log reader path -> {:?}", write_log_path);
+            })?,
+        );
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
 pub struct BitcaskEngine {
     store: Arc<RwLock<BitcaskStore>>,
+    // Shared with the store, so that reads never wait for the store lock
+    index: Arc<ConcurrentKeyDir>,
+    segment_files: Arc<SegmentFiles>,
+    watch_hub: WatchHub,
+    options: Arc<BitcaskOptions>,
+    // Held while merging, so that a single merge runs at a time
+    compaction_lock: Arc<Mutex<()>>,
 }
 
-const TOMBSTONE: &str = "!tomb!";
+const LEGACY_TOMBSTONE: &str = "!tomb!";
 const LOG_EXTENSION: &str = ".db";
+const LOCK_PATH: &str = "LOCK";
 
 impl BitcaskEngine {
-    /// Open an instance of BitcaskEngine at the specified directory
+    /// Open an instance of BitcaskEngine at the specified directory. The directory stays locked
+    /// until every clone of the engine is dropped, and opening it again meanwhile, from this or
+    /// another process, fails with HobbesError::DirectoryLockedError.
     pub fn open(logs_dir_arg: &Path) -> Result<BitcaskEngine> {
+        BitcaskOptions::new().open(logs_dir_arg)
+    }
+
+    /// Open an instance of BitcaskEngine at the specified directory, encrypting records with the
+    /// active key of the keyring. Records left unencrypted or sealed with a retired key are
+    /// rewritten with the active key by compacting the store when it is opened.
+    pub fn open_encrypted(logs_dir_arg: &Path, keyring: Keyring) -> Result<BitcaskEngine> {
+        BitcaskOptions::new().keyring(keyring).open(logs_dir_arg)
+    }
+
+    /// Open an existing store at the specified directory without modifying it. Writes and
+    /// compaction fail with HobbesError::ReadOnlyError, and no files are created. Other
+    /// read-only openers may share the store, but not openers writing to it.
+    pub fn open_read_only(logs_dir_arg: &Path) -> Result<BitcaskEngine> {
+        BitcaskOptions::new().read_only(true).open(logs_dir_arg)
+    }
+
+    fn open_with_options(logs_dir_arg: &Path, options: BitcaskOptions) -> Result<BitcaskEngine> {
         let logging_level = match env::var("LOG_LEVEL") {
             Ok(level) => match level.as_str() {
                 "TRACE" => tracing::Level::TRACE,
@@ -97,6 +446,22 @@ impl BitcaskEngine {
             )))?;
         }
 
+        // Taken before reading the logs, as another opener may be appending to or compacting them.
+        // Read-only openers share the lock, without creating the lock file of stores without one.
+        let lock = if options.read_only {
+            if !Path::is_dir(&logs_dir) {
+                Err(HobbesError::IoError(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no bitcask store found at {logs_dir_arg:?}"),
+                )))?
+            }
+            DirLock::shared(&db_dir.join(LOCK_PATH))?
+        } else {
+            fs::create_dir_all(&db_dir)?;
+            Some(DirLock::exclusive(&db_dir.join(LOCK_PATH))?)
+        };
+        let metadata = StoreMetadata::read(&db_dir)?;
+
         let mut log_readers = HashMap::new();
         let mut latest_file_id = 0;
 
@@ -104,6 +469,9 @@ impl BitcaskEngine {
         if Path::is_dir(&logs_dir) {
             for entry in fs::read_dir(&logs_dir)? {
                 let log_path = entry?.path();
+                if is_hint(&log_path) {
+                    continue;
+                }
                 let mut log_id_path = log_path.clone();
                 log_id_path.set_extension("");
 
@@ -117,10 +485,9 @@ impl BitcaskEngine {
 
                 log_readers.insert(
                     log_id,
-                    BufReader::new(File::open(&log_path).map_err(|e| {
+                    LogReader::open(&log_path).inspect_err(|_| {
                         error!("[DB_INIT] Error while initialising log readers - log reader path -> {:?}", &log_path);
-                        HobbesError::IoError(e)
-                    })?),
+                    })?,
                 );
                 if log_id > latest_file_id {
                     latest_file_id = log_id;
@@ -130,169 +497,307 @@ impl BitcaskEngine {
             fs::create_dir_all(&logs_dir)?;
         }
 
-        let mut mem_index = HashMap::new();
-        let log_writer;
+        // Timestamps are only held while replaying, to tell which record of a key is the latest
+        let replay_index: ConcurrentKeyDir<(ValueMetadata, DateTime<Local>)> =
+            ConcurrentKeyDir::new();
+        let mut segment_usage: HashMap<u64, SegmentUsage> = HashMap::new();
+        let mut log_writer = None;
+        // Set when records must be rewritten to be sealed with the active key, or because
+        // segments without a header hold records in the legacy format
+        let mut needs_rewrite = log_readers
+            .values()
+            .any(|log_reader| log_reader.version == LEGACY_FORMAT_VERSION);
+        let mut current_log_id = latest_file_id;
 
         // Indicates logs are present in the directory
         if latest_file_id != 0 {
             let write_log_path =
                 logs_dir.join(PathBuf::from(latest_file_id.to_string() + LOG_EXTENSION));
-            log_writer = OpenOptions::new()
-                .append(true)
-                .open(&write_log_path)
-                .map_err(|e| {
-                    error!("[DB_INIT] Error while opening an existing mutable append log - log writer path -> {:?}", write_log_path);
-                    HobbesError::IoError(e)
-                })?;
+            // Records are not appended to legacy segments, which are sealed instead
+            if log_readers[&latest_file_id].version != FORMAT_VERSION {
+                current_log_id = latest_file_id + 1;
+            } else if !options.read_only {
+                log_writer = Some(OpenOptions::new()
+                    .append(true)
+                    .open(&write_log_path)
+                    .map_err(|e| {
+                        error!("[DB_INIT] Error while opening an existing mutable append log - log writer path -> {:?}", write_log_path);
+                        HobbesError::IoError(e)
+                    })?);
+            }
 
             // Replaying logs to recreate index
 
-            for (i, mut log_reader) in log_readers.iter_mut() {
-                let mut offset = 0;
-                log_reader.seek(SeekFrom::Start(0))?;
+            // Time each key was last removed, as segments are replayed in no particular order and
+            // an older record of a removed key may be replayed after its tombstone
+            let mut removed_at: KeyDir<DateTime<Local>> = KeyDir::new();
+            let is_stale = |replay_index: &ConcurrentKeyDir<(ValueMetadata, DateTime<Local>)>,
+                            removed_at: &KeyDir<DateTime<Local>>,
+                            key: &str,
+                            timestamp: DateTime<Local>| {
+                replay_index
+                    .get(key)
+                    .is_some_and(|(_, indexed_at)| timestamp < indexed_at)
+                    || removed_at
+                        .get(key)
+                        .is_some_and(|removed| timestamp < *removed)
+            };
+
+            for (i, log_reader) in log_readers.iter_mut() {
+                let segment_size = log_reader.reader.get_ref().metadata()?.len();
+                let usage = segment_usage.entry(*i).or_default();
+                usage.size = segment_size;
+                usage.header_bytes = log_reader.header_len;
+
+                // Encrypted stores are replayed in full, detecting records to be rewritten
+                if *i != latest_file_id && options.keyring.is_none() {
+                    if let Some(entries) = read_hint(&hint_path(&logs_dir, *i), segment_size) {
+                        for entry in entries {
+                            if is_stale(&replay_index, &removed_at, &entry.key, entry.timestamp) {
+                                continue;
+                            }
+                            replay_index.insert(
+                                &entry.key,
+                                (
+                                    ValueMetadata::new(*i, entry.log_pointer, entry.entry_len)?,
+                                    entry.timestamp,
+                                ),
+                            );
+                        }
+                        continue;
+                    }
+                }
 
-                while let Ok(decode_cmd) = decode::from_read(&mut log_reader) {
-                    let cmd: LogEntry = decode_cmd;
+                read_header(&mut log_reader.reader)?;
+                let mut offset = log_reader.reader.stream_position()?;
+
+                loop {
+                    let cmd = match read_entry(
+                        &mut log_reader.reader,
+                        options.keyring.as_ref(),
+                        log_reader.version,
+                    ) {
+                        Ok((cmd, stale)) => {
+                            needs_rewrite |= stale;
+                            cmd
+                        }
+                        // A record which cannot be decrypted is not a truncated tail
+                        Err(err @ HobbesError::EncryptionError(_)) => return Err(err),
+                        Err(_) => break,
+                    };
 
-                    if let Some(mem_cmd) = mem_index.get(&cmd.key) {
-                        let mem_cmd: &ValueMetadata = mem_cmd;
+                    let next_offset = log_reader.reader.stream_position()?;
+                    if cmd.is_delete() {
+                        segment_usage.entry(*i).or_default().tombstone_bytes +=
+                            next_offset - offset;
+                    }
 
-                        if cmd.timestamp < mem_cmd.timestamp {
-                            offset = log_reader.stream_position()?;
-                            continue;
-                        }
+                    if is_stale(&replay_index, &removed_at, &cmd.key, cmd.timestamp) {
+                        offset = next_offset;
+                        continue;
                     }
 
-                    match cmd.val.as_str() {
-                        TOMBSTONE => mem_index.remove(&cmd.key),
-                        _ => mem_index.insert(
-                            cmd.key,
-                            ValueMetadata {
-                                log_pointer: offset,
-                                log_id: i.to_owned(),
-                                timestamp: cmd.timestamp,
-                            },
-                        ),
-                    };
+                    if cmd.is_delete() {
+                        replay_index.remove(&cmd.key);
+                        removed_at.insert(&cmd.key, cmd.timestamp);
+                    } else {
+                        replay_index.insert(
+                            &cmd.key,
+                            (
+                                ValueMetadata::new(*i, offset, next_offset - offset)?,
+                                cmd.timestamp,
+                            ),
+                        );
+                    }
 
-                    offset = log_reader.stream_position()?;
+                    offset = next_offset;
                 }
             }
-        } else {
+
+            replay_index.for_each(|_, (value_metadata, _)| {
+                segment_usage
+                    .entry(value_metadata.log_id())
+                    .or_default()
+                    .live_bytes += value_metadata.entry_len();
+            });
+        } else if !options.read_only {
             // Indicates no logs in directory
 
             let write_log_path = logs_dir.join(PathBuf::from(String::from("1") + LOG_EXTENSION));
-            log_writer = OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(&write_log_path)
-                .map_err(|e| {
-                    error!("[DB_INIT] Error while creating a new mutable append log - log writer path -> {:?}", write_log_path);
-                    HobbesError::IoError(e)
-                })?;
-            log_readers.insert(1, BufReader::new(File::open(&write_log_path)
-                .map_err(|e| {
-                    error!("[DB_INIT] Error while creating a reader for the new mutable append log created - log reader path -> {:?}", write_log_path);
-                    HobbesError::IoError(e)
-                })?));
-            latest_file_id = 1;
-        }
-
-        Ok(BitcaskEngine {
+            let new_log_writer = create_log(&write_log_path).inspect_err(|_| {
+                error!("[DB_INIT] Error while creating a new mutable append log - log writer path -> {:?}", write_log_path);
+            })?;
+            let usage = segment_usage.entry(1).or_default();
+            usage.size = new_log_writer.metadata()?.len();
+            usage.header_bytes = usage.size;
+            log_writer = Some(new_log_writer);
+            current_log_id = 1;
+        }
+
+        // Every segment but the one appended to is sealed, and can be mapped
+        let segment_files = Arc::new(SegmentFiles::default());
+        let segment_ids = log_readers
+            .keys()
+            .copied()
+            .chain(log_writer.is_some().then_some(current_log_id));
+        for log_id in segment_ids.collect::<Vec<u64>>() {
+            let log_path = logs_dir.join(format!("{log_id}{LOG_EXTENSION}"));
+            let active = log_writer.is_some() && log_id == current_log_id;
+            segment_files.insert(
+                log_id,
+                SegmentFile::open(&log_path, active).inspect_err(|_| {
+                    error!(
+                        "[DB_INIT] Error while opening a segment for reads - segment path -> {:?}",
+                        &log_path
+                    );
+                })?,
+            );
+        }
+        drop(log_readers);
+
+        let index = Arc::new(replay_index.map_values(|(value_metadata, _)| value_metadata));
+        let engine = BitcaskEngine {
             store: Arc::new(RwLock::new(BitcaskStore {
-                mem_index,
+                mem_index: index.clone(),
+                segment_usage,
                 logs_dir,
-                db_dir,
-                log_writer: Some(log_writer),
-                log_readers: Some(log_readers),
-                current_log_id: latest_file_id,
+                log_writer,
+                segment_files: segment_files.clone(),
+                current_log_id,
+                merge_pending: false,
+                compaction_count: 0,
+                last_compaction_at: None,
+                last_compaction_duration: None,
+                _lock: lock,
             })),
-        })
-    }
-
-    fn log_writer_init(&self) -> Result<()> {
-        let store_mutex = self.store.clone();
-        let bitcask_writer_none = store_mutex.read().expect(RWLOCK_ERROR).log_writer.is_none();
-
-        if bitcask_writer_none {
-            trace!(operation = "LOG_WRITER_INIT");
-
-            let mut bitcask_store = store_mutex.write().expect(RWLOCK_ERROR);
+            index,
+            segment_files,
+            watch_hub: WatchHub::default(),
+            options: Arc::new(options),
+            compaction_lock: Arc::new(Mutex::new(())),
+        };
 
-            let write_log_path = bitcask_store.logs_dir.join(PathBuf::from(format!(
-                "{}{LOG_EXTENSION}",
-                bitcask_store.current_log_id
-            )));
+        if needs_rewrite && !engine.options.read_only {
+            info!("Rewriting records which are unencrypted, sealed with a retired key or in a previous format");
+            engine.compact()?;
+        }
 
-            bitcask_store.log_writer = Some(
-                fs::OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .open(&write_log_path).map_err(|e| {
-                    error!("[LOG_WRITER_INIT] Error while creating a new mutable append log - log writer path -> {:?}", write_log_path);
-                    HobbesError::IoError(e)
-                })?
+        // Segments of previous formats still readable are left as they are, and rewritten by
+        // merges over time
+        if !engine.options.read_only {
+            match metadata {
+                None => StoreMetadata::new().write(&db_dir)?,
+                Some(mut metadata) if metadata.format_version < FORMAT_VERSION => {
+                    info!(
+                        "Upgrading store from format version {} to {}",
+                        metadata.format_version, FORMAT_VERSION
+                    );
+                    metadata.upgrade();
+                    metadata.write(&db_dir)?;
+                }
+                Some(_) => {}
+            }
+        }
 
-            );
+        if let FsyncPolicy::Interval(interval) = engine.options.fsync {
+            let store = Arc::downgrade(&engine.store);
+            thread::spawn(move || sync_periodically(store, interval));
+        }
 
-            let bitcask_readers_none = bitcask_store.log_readers.is_none();
+        Ok(engine)
+    }
 
-            if bitcask_readers_none {
-                drop(bitcask_store);
-                self.log_readers_init()?;
-            }
+    /// Compress records written from now on, including those rewritten by compaction
+    pub fn with_compression(mut self, compression: Compression) -> BitcaskEngine {
+        Arc::make_mut(&mut self.options).compression = Some(compression);
+        self
+    }
 
-            let mut bitcask_store = store_mutex.write().expect(RWLOCK_ERROR);
+    /// Append serialized records to the active log in a single write, then index them and
+    /// publish them to watchers. If the write fails, the log is truncated back, so that no
+    /// record is left on disk without being indexed.
+    fn append_records(
+        &self,
+        bitcask_store: &mut BitcaskStore,
+        records: Vec<(String, String, Vec<u8>)>,
+    ) -> Result<()> {
+        let log_writer = bitcask_store.log_writer.as_mut().unwrap();
+        let start = log_writer.metadata()?.len();
+
+        let mut buf = Vec::new();
+        let mut pending = Vec::with_capacity(records.len());
+        for (key, value, cmd) in records {
+            let value_metadata = ValueMetadata::new(
+                bitcask_store.current_log_id,
+                start + buf.len() as u64,
+                cmd.len() as u64,
+            )?;
+            buf.extend_from_slice(&cmd);
+            pending.push((key, value, value_metadata));
+        }
 
-            let current_log_id = bitcask_store.current_log_id;
-            bitcask_store.log_readers.as_mut().unwrap().insert(
-                current_log_id,
-                BufReader::new(fs::File::open(&write_log_path).map_err(|e| {
-                    error!("[LOG_WRITER_INIT] Error while creating a reader for the new mutable append log - log reader path -> {:?}", write_log_path);
-                    HobbesError::IoError(e)
-                })?),
-            );
+        if let Err(e) = log_writer.write_all(&buf) {
+            error!("[SET_MANY] Error while appending records, truncating the active log -> {e}");
+            log_writer.set_len(start)?;
+            Err(e)?
         }
 
+        for (key, _, value_metadata) in &pending {
+            bitcask_store.record_append(value_metadata.entry_len(), false);
+            bitcask_store.index_insert(key, *value_metadata);
+        }
+        self.watch_hub.publish(
+            pending
+                .iter()
+                .map(|(key, value, _)| (key.as_str(), Some(value.as_str()))),
+        );
         Ok(())
     }
 
-    fn log_readers_init(&self) -> Result<()> {
-        let store_clone = self.store.clone();
-        let bitcask_readers_none = store_clone
-            .read()
-            .expect(RWLOCK_ERROR)
-            .log_readers
-            .is_none();
-
-        if bitcask_readers_none {
-            trace!(operation = "LOG_READERS_INIT");
-
-            let mut bitcask_store = store_clone.write().expect(RWLOCK_ERROR);
-
-            let mut readers = HashMap::new();
-            for entry in fs::read_dir(&bitcask_store.logs_dir)? {
-                let log_path = entry?.path();
-                let mut log_id_path = log_path.clone();
-                log_id_path.set_extension("");
+    fn ensure_writable(&self) -> Result<()> {
+        if self.options.read_only {
+            Err(HobbesError::ReadOnlyError)?
+        }
+        Ok(())
+    }
 
-                let log_id = log_id_path
-                    .strip_prefix(&bitcask_store.logs_dir)?
-                    .to_str()
-                    .ok_or(HobbesError::CliError(String::from(
-                        "invalid log filename, {err}",
-                    )))?
-                    .parse::<u64>()?;
+    /// Seal the active log once it reaches the segment size, leaving the next write to start a
+    /// new one
+    fn roll_over(&self, bitcask_store: &mut BitcaskStore) -> Result<bool> {
+        let active_size = bitcask_store
+            .segment_usage
+            .get(&bitcask_store.current_log_id)
+            .map_or(0, |usage| usage.size);
+        if active_size < self.options.segment_size {
+            return Ok(false);
+        }
+        self.seal_active_log(bitcask_store)
+    }
 
-                readers.insert(log_id, BufReader::new(File::open(&log_path).map_err(|e| {
-                    error!("[LOG_READERS_INIT] Error while creating a new reader - log reader path -> {:?}", &log_path);
-                    HobbesError::IoError(e)
-                })?));
-            }
+    fn seal_active_log(&self, bitcask_store: &mut BitcaskStore) -> Result<bool> {
+        let Some(log_writer) = bitcask_store.log_writer.take() else {
+            return Ok(false);
+        };
 
-            bitcask_store.log_readers = Some(readers);
+        if self.options.fsync != FsyncPolicy::Never {
+            log_writer.sync_data()?;
         }
-        Ok(())
+        // Reads of the sealed log go through a map from now on
+        let log_path = bitcask_store
+            .logs_dir
+            .join(format!("{}{LOG_EXTENSION}", bitcask_store.current_log_id));
+        bitcask_store.segment_files.insert(
+            bitcask_store.current_log_id,
+            SegmentFile::open(&log_path, false)?,
+        );
+        debug!(
+            operation = "ROLL_OVER",
+            log_id = bitcask_store.current_log_id,
+            "Sealed the active log"
+        );
+        bitcask_store.current_log_id = bitcask_store.next_log_id();
+        bitcask_store.merge_pending = true;
+        Ok(true)
     }
 }
 
@@ -300,33 +805,38 @@ impl Engine for BitcaskEngine {
     /// Store a key-value pair
     fn set(&self, key: String, value: String) -> Result<()> {
         trace!(operation = "SET", key = key, value = value);
-        self.log_writer_init()?;
+        self.ensure_writable()?;
 
-        let cmd = serialize_command(&LogEntry {
-            key: key.clone(),
-            val: value.clone(),
-            timestamp: Local::now(),
-        })?;
+        let cmd = serialize_command(
+            &LogEntry::put(key.clone(), value.clone(), Local::now()),
+            self.options.compression.as_ref(),
+            self.options.keyring.as_ref(),
+        )?;
 
         let store_mutex = self.store.clone();
         let mut bitcask_store = store_mutex.write().expect(RWLOCK_ERROR);
+        bitcask_store.open_active_log()?;
 
-        let log_writer = bitcask_store.log_writer.as_mut().unwrap();
+        self.watch_hub.record(&key, Some(&value), || {
+            let log_writer = bitcask_store.log_writer.as_mut().unwrap();
 
-        let offset = log_writer.metadata()?.len();
+            let offset = log_writer.metadata()?.len();
 
-        log_writer.seek(SeekFrom::Start(offset))?;
-        log_writer.write_all(&cmd)?;
+            log_writer.seek(SeekFrom::Start(offset))?;
+            log_writer.write_all(&cmd)?;
+            if self.options.fsync == FsyncPolicy::Always {
+                log_writer.sync_data()?;
+            }
 
-        let current_log_id = bitcask_store.current_log_id;
-        bitcask_store.mem_index.insert(
-            key,
-            ValueMetadata {
-                log_pointer: offset,
-                log_id: current_log_id,
-                timestamp: Local::now(),
-            },
-        );
+            let current_log_id = bitcask_store.current_log_id;
+            bitcask_store.record_append(cmd.len() as u64, false);
+            bitcask_store.index_insert(
+                &key,
+                ValueMetadata::new(current_log_id, offset, cmd.len() as u64)?,
+            );
+            Ok(())
+        })?;
+        self.roll_over(&mut bitcask_store)?;
 
         // let get_val = self.get(key.clone())?;
         // trace!(
@@ -346,6 +856,62 @@ impl Engine for BitcaskEngine {
         Ok(())
     }
 
+    /// Store many key-value pairs, appending them to the active log in as few writes as the
+    /// segment size allows and checking for compaction once at the end. Returns the number of
+    /// pairs stored. Pairs are indexed, becoming visible to reads, and published to watchers once
+    /// their records are written.
+    ///
+    /// Every pair is serialized before anything is written, so a pair failing to serialize
+    /// leaves the store unchanged.
+    fn set_many(&self, pairs: impl IntoIterator<Item = (String, String)>) -> Result<u64> {
+        self.ensure_writable()?;
+
+        let records = pairs
+            .into_iter()
+            .map(|(key, value)| {
+                let cmd = serialize_command(
+                    &LogEntry::put(key.clone(), value.clone(), Local::now()),
+                    self.options.compression.as_ref(),
+                    self.options.keyring.as_ref(),
+                )?;
+                Ok((key, value, cmd))
+            })
+            .collect::<Result<Vec<(String, String, Vec<u8>)>>>()?;
+        let count = records.len() as u64;
+
+        let store_mutex = self.store.clone();
+        let mut bitcask_store = store_mutex.write().expect(RWLOCK_ERROR);
+        let store = &mut *bitcask_store;
+
+        // Records are written a segment at a time, rolling the active log over in between
+        let mut records = records.into_iter().peekable();
+        while records.peek().is_some() {
+            // Opened under the lock, as a compaction may seal the active log until it is taken
+            store.open_active_log()?;
+            let mut offset = store.log_writer.as_ref().unwrap().metadata()?.len();
+            let mut segment_records = Vec::new();
+            while offset < self.options.segment_size {
+                let Some(record) = records.next() else {
+                    break;
+                };
+                offset += record.2.len() as u64;
+                segment_records.push(record);
+            }
+
+            self.append_records(store, segment_records)?;
+            self.roll_over(store)?;
+        }
+        if self.options.fsync == FsyncPolicy::Always {
+            if let Some(log_writer) = &store.log_writer {
+                log_writer.sync_data()?;
+            }
+        }
+        drop(bitcask_store);
+
+        self.compaction_manager()?;
+        Ok(count)
+    }
+
     /// Retrieve the value associated with a key from the store
     ///
     /// ```
@@ -386,72 +952,364 @@ impl Engine for BitcaskEngine {
     /// ```
     fn remove(&self, key: String) -> Result<()> {
         // trace!(operation = "RM", key = key);
-        self.log_writer_init()?;
+        self.ensure_writable()?;
 
         let store_mutex = self.store.clone();
         let mut bitcask_store = store_mutex.write().expect(RWLOCK_ERROR);
+        bitcask_store.open_active_log()?;
 
-        bitcask_store
-            .mem_index
-            .remove(&key)
-            .ok_or_else(|| HobbesError::KeyNotFoundError)?;
+        self.watch_hub.record(&key, None, || {
+            if bitcask_store.mem_index.get(&key).is_none() {
+                return Err(HobbesError::KeyNotFoundError);
+            }
 
-        let cmd = serialize_command(&LogEntry {
-            key,
-            val: TOMBSTONE.to_string(),
-            timestamp: Local::now(),
-        })?;
+            let cmd = serialize_command(
+                &LogEntry::delete(key.clone(), Local::now()),
+                self.options.compression.as_ref(),
+                self.options.keyring.as_ref(),
+            )?;
 
-        let log_writer = bitcask_store.log_writer.as_mut().unwrap();
-        let offset = log_writer.metadata()?.len();
+            let log_writer = bitcask_store.log_writer.as_mut().unwrap();
+            let offset = log_writer.metadata()?.len();
 
-        log_writer.seek(SeekFrom::Start(offset))?;
-        log_writer.write_all(&cmd)?;
+            log_writer.seek(SeekFrom::Start(offset))?;
+            log_writer.write_all(&cmd)?;
+            if self.options.fsync == FsyncPolicy::Always {
+                log_writer.sync_data()?;
+            }
+
+            // The key leaves the index only once its tombstone is written, so a failed write
+            // does not hide a value that is still live on disk
+            bitcask_store.record_append(cmd.len() as u64, true);
+            bitcask_store.index_remove(&key);
+            Ok(())
+        })?;
+        self.roll_over(&mut bitcask_store)?;
 
         drop(bitcask_store);
         self.compaction_manager()?;
         Ok(())
     }
+
+    /// Report statistics about the in-memory index and the on-disk logs
+    fn stats(&self) -> Result<EngineStats> {
+        let store_mutex = self.store.clone();
+        let bitcask_store = store_mutex.read().expect(RWLOCK_ERROR);
+
+        let mut segments = 0;
+        let mut segments_size = 0;
+        for entry in fs::read_dir(&bitcask_store.logs_dir)? {
+            let entry = entry?;
+            if is_hint(&entry.path()) {
+                continue;
+            }
+            segments += 1;
+            segments_size += entry.metadata()?.len();
+        }
+
+        let mut live_bytes = 0;
+        self.index
+            .for_each(|_, value_metadata| live_bytes += value_metadata.entry_len());
+        let header_bytes: u64 = bitcask_store
+            .segment_usage
+            .values()
+            .map(|usage| usage.header_bytes)
+            .sum();
+        let dead_bytes_ratio = if segments_size == 0 {
+            0.0
+        } else {
+            segments_size.saturating_sub(live_bytes + header_bytes) as f64 / segments_size as f64
+        };
+
+        Ok(EngineStats {
+            engine: String::from("bitcask"),
+            keys: self.index.len() as u64,
+            segments,
+            segments_size,
+            dead_bytes_ratio,
+            index_bytes: Some(self.index.memory_usage()),
+            compactions: bitcask_store.compaction_count,
+            last_compaction_at: bitcask_store.last_compaction_at,
+            last_compaction_ms: bitcask_store
+                .last_compaction_duration
+                .map(|duration| duration.as_millis() as u64),
+        })
+    }
+
+    /// Retrieve all key-value pairs whose keys start with the prefix, sorted by key
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let mut keys = Vec::new();
+        self.index.for_each(|key, _| {
+            if key.starts_with(&prefix) {
+                keys.push(String::from(key));
+            }
+        });
+        keys.sort();
+
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            // Keys removed after the index was read are skipped
+            if let Some(val) = self.get(key.clone())? {
+                pairs.push((key, val));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Retrieve the first `limit` key-value pairs whose keys start with the prefix and sort
+    /// after `after`, sorted by key. The index is unordered, so each page takes a pass over it,
+    /// keeping only the smallest keys seen so far.
+    fn scan_page(
+        &self,
+        prefix: String,
+        mut after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        loop {
+            let mut keys: BinaryHeap<String> = BinaryHeap::with_capacity(limit + 1);
+            self.index.for_each(|key, _| {
+                if !key.starts_with(&prefix) || after.as_deref().is_some_and(|after| key <= after) {
+                    return;
+                }
+                if keys.len() < limit {
+                    keys.push(String::from(key));
+                } else if keys.peek().is_some_and(|largest| key < largest.as_str()) {
+                    keys.pop();
+                    keys.push(String::from(key));
+                }
+            });
+            let keys = keys.into_sorted_vec();
+            let Some(last) = keys.last().cloned() else {
+                return Ok(Vec::new());
+            };
+
+            let mut pairs = Vec::with_capacity(keys.len());
+            for key in keys {
+                // Keys removed after the index was read are skipped
+                if let Some(val) = self.get(key.clone())? {
+                    pairs.push((key, val));
+                }
+            }
+            // A page whose keys were all removed meanwhile is skipped rather than returned
+            // empty, which would end the scan early
+            if !pairs.is_empty() {
+                return Ok(pairs);
+            }
+            after = Some(last);
+        }
+    }
+
+    /// Subscribe to changes to keys starting with the prefix
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        Ok(self.watch_hub.subscribe(prefix))
+    }
+
+    /// Seal the active log and merge every sealed segment, rewriting the live records with the
+    /// configured codec and the active encryption key
+    fn compact(&self) -> Result<()> {
+        self.compact_all()
+    }
+
+    /// Flush every segment and the logs directory. Sealed and merged segments are only flushed
+    /// as they are written under `FsyncPolicy::Always`, so each is flushed here.
+    fn sync(&self) -> Result<()> {
+        let bitcask_store = self.store.read().expect(RWLOCK_ERROR);
+        // Segments are only removed with the store locked for writes, so none vanish meanwhile
+        for entry in fs::read_dir(&bitcask_store.logs_dir)? {
+            let path = entry?.path();
+            if is_hint(&path) {
+                continue;
+            }
+            File::open(&path)?.sync_all()?;
+        }
+        File::open(&bitcask_store.logs_dir)?.sync_all()?;
+        Ok(())
+    }
 }
 
 impl BitcaskEngine {
+    /// Read the value of a key along with the position of its record, without locking the store.
+    /// Only the shard of the index holding the key is locked while looking the record up.
     fn get_val_metadata(&self, key: String) -> Result<Option<(String, ValueMetadata)>> {
-        self.log_readers_init()?;
+        let Some(mut value_metadata) = self.index.get(&key) else {
+            return Ok(None);
+        };
+        let segment_file = loop {
+            if let Some(segment_file) = self.segment_files.get(value_metadata.log_id()) {
+                break segment_file;
+            }
+            // The segment was removed by a merge after the lookup, relocating the record unless
+            // the key was written or removed meanwhile
+            match self.index.get(&key) {
+                None => return Ok(None),
+                Some(current) if current != value_metadata => value_metadata = current,
+                Some(_) => Err(HobbesError::LogReaderNotFoundError(format!(
+                    "Log {} does not have a valid reader",
+                    value_metadata.log_id
+                )))?,
+            }
+        };
 
-        let store_mutex = self.store.clone();
-        let mut bitcask_store = store_mutex.write().expect(RWLOCK_ERROR);
+        let record =
+            segment_file.read_record(value_metadata.log_pointer, value_metadata.entry_len())?;
+        let (cmd, _) = read_entry(
+            &mut record.as_ref(),
+            self.options.keyring.as_ref(),
+            segment_file.version(),
+        )?;
 
-        let value_metadata_opt = bitcask_store.mem_index.get(&key);
-
-        match value_metadata_opt {
-            Some(value_metadata) => {
-                let value_metadata = value_metadata.clone();
-
-                let mut requested_log_reader = bitcask_store
-                    .log_readers
-                    .as_mut()
-                    .unwrap()
-                    .get_mut(&value_metadata.log_id)
-                    .ok_or_else(|| {
-                        HobbesError::LogReaderNotFoundError(format!(
-                            "Log {} does not have a valid reader",
-                            value_metadata.log_id
-                        ))
-                    })?;
-
-                requested_log_reader.seek(SeekFrom::Start(value_metadata.log_pointer))?;
-                let cmd: LogEntry = decode::from_read(&mut requested_log_reader)?;
-
-                match cmd.val.as_str() {
-                    TOMBSTONE => Ok(None),
-                    _ => Ok(Some((cmd.val, value_metadata.to_owned()))),
-                }
+        if cmd.is_delete() {
+            Ok(None)
+        } else {
+            Ok(Some((cmd.val, value_metadata)))
+        }
+    }
+}
+
+/// Flush the active log at the interval until the store is dropped
+fn sync_periodically(store: Weak<RwLock<BitcaskStore>>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let Some(store) = store.upgrade() else {
+            return;
+        };
+
+        let bitcask_store = store.read().expect(RWLOCK_ERROR);
+        if let Some(log_writer) = bitcask_store.log_writer.as_ref() {
+            if let Err(e) = log_writer.sync_data() {
+                error!("[FSYNC] Error while flushing the active log -> {e}");
             }
-            None => Ok(None),
         }
     }
 }
 
-fn serialize_command(cmd: &LogEntry) -> Result<Vec<u8>> {
-    Ok(rmp_serde::to_vec(cmd)?)
+/// Open a log for appending, writing the segment header if the log is new
+fn create_log(path: &Path) -> Result<File> {
+    let mut log_writer = OpenOptions::new().create(true).append(true).open(path)?;
+    if log_writer.metadata()?.len() == 0 {
+        write_header(&mut log_writer)?;
+    }
+    Ok(log_writer)
+}
+
+fn is_hint(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(HINT_EXTENSION))
+}
+
+fn serialize_command(
+    cmd: &LogEntry,
+    compression: Option<&Compression>,
+    keyring: Option<&Keyring>,
+) -> Result<Vec<u8>> {
+    let mut record = rmp_serde::to_vec(cmd)?;
+    if let Some(compression) = compression {
+        if let Some(compressed_record) = compression.compress(&record)? {
+            record = compressed_record;
+        }
+    }
+    if let Some(keyring) = keyring {
+        record = keyring.seal(&record)?;
+    }
+    // Checked before the record is written, as the index could not hold it
+    checked_record_len(record.len() as u64)?;
+    Ok(record)
+}
+
+fn checked_record_len(len: u64) -> Result<u32> {
+    u32::try_from(len).map_err(|_| {
+        HobbesError::IoError(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "record of {len} bytes exceeds the limit of {} bytes",
+                u32::MAX
+            ),
+        ))
+    })
+}
+
+/// A record read from a log, either a plain MessagePack record left in the reader, or the
+/// payload of an extension record
+enum RawRecord {
+    Plain,
+    Sealed(Vec<u8>),
+    Compressed(Vec<u8>),
+}
+
+/// Read the next record from a log of the format version, decrypting and decompressing it if
+/// needed. Also reports whether the record must be rewritten to be sealed with the active key.
+fn read_entry(
+    log_reader: &mut impl BufRead,
+    keyring: Option<&Keyring>,
+    version: u16,
+) -> Result<(LogEntry, bool)> {
+    match read_raw_record(log_reader)? {
+        RawRecord::Plain => Ok((decode_entry(log_reader, version)?, keyring.is_some())),
+        RawRecord::Compressed(payload) => Ok((
+            decode_entry(compression::decompress(&payload)?.as_slice(), version)?,
+            keyring.is_some(),
+        )),
+        RawRecord::Sealed(payload) => {
+            let keyring = keyring.ok_or_else(|| {
+                HobbesError::EncryptionError(String::from(
+                    "logs hold encrypted records, but no encryption key is configured",
+                ))
+            })?;
+            let (record, fingerprint) = keyring.open(&payload)?;
+
+            let mut record = record.as_slice();
+            let cmd = match read_raw_record(&mut record)? {
+                RawRecord::Plain => decode_entry(record, version)?,
+                RawRecord::Compressed(payload) => {
+                    decode_entry(compression::decompress(&payload)?.as_slice(), version)?
+                }
+                RawRecord::Sealed(_) => Err(HobbesError::EncryptionError(String::from(
+                    "encrypted record holds another encrypted record",
+                )))?,
+            };
+            Ok((cmd, keyring.is_retired(fingerprint)))
+        }
+    }
+}
+
+fn decode_entry(record: impl Read, version: u16) -> Result<LogEntry> {
+    if version == LEGACY_FORMAT_VERSION {
+        Ok(decode::from_read::<_, LegacyLogEntry>(record)?.into())
+    } else {
+        Ok(decode::from_read(record)?)
+    }
+}
+
+/// Peek at the next record, consuming the header and payload of extension records. Plain
+/// records are left in the reader to be decoded directly.
+fn read_raw_record(reader: &mut impl BufRead) -> Result<RawRecord> {
+    let marker = *reader
+        .fill_buf()?
+        .first()
+        .ok_or_else(|| HobbesError::IoError(io::Error::from(io::ErrorKind::UnexpectedEof)))?;
+
+    // fixext and ext markers
+    if !matches!(marker, 0xc7..=0xc9 | 0xd4..=0xd8) {
+        return Ok(RawRecord::Plain);
+    }
+
+    // A truncated header is reported as an I/O error, like any other truncated record
+    let meta = rmp::decode::read_ext_meta(reader).map_err(|e| match e {
+        ValueReadError::InvalidMarkerRead(e) | ValueReadError::InvalidDataRead(e) => {
+            HobbesError::IoError(e)
+        }
+        e => HobbesError::IoError(io::Error::new(io::ErrorKind::InvalidData, e)),
+    })?;
+    let mut payload = vec![0u8; meta.size as usize];
+    reader.read_exact(&mut payload)?;
+
+    match meta.typeid {
+        ENCRYPTED_RECORD_EXT_TYPE => Ok(RawRecord::Sealed(payload)),
+        COMPRESSED_RECORD_EXT_TYPE => Ok(RawRecord::Compressed(payload)),
+        typeid => Err(HobbesError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown record extension type {typeid}"),
+        ))),
+    }
 }
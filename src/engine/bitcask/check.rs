@@ -0,0 +1,253 @@
+//! Offline verification of bitcask stores
+//!
+//! Checking walks every segment of a closed store, decoding each record, and rebuilds the index
+//! the way opening the store would. Replay stops at the first record of a segment which cannot
+//! be decoded, so repairing truncates segments at that record.
+
+use chrono::{DateTime, Local};
+use rmp_serde::decode;
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::engine::lock::DirLock;
+use crate::engine::{BITCASK_DB_PATH, BITCASK_LOGS_PATH};
+use crate::{HobbesError, Result};
+
+use super::{is_hint, read_entry, Keyring, LogReader, SegmentReader, LOCK_PATH, LOG_EXTENSION};
+
+/// Outcome of checking a store
+#[derive(Debug, Clone)]
+pub struct CheckReport {
+    /// Segments in ascending order of their ids
+    pub segments: Vec<SegmentReport>,
+    /// Records referenced by the rebuilt index
+    pub live: u64,
+    /// Records superseded by later writes or removals
+    pub dead: u64,
+    /// Records marking removed keys
+    pub tombstones: u64,
+    /// Keys of the rebuilt index whose records could not be read back
+    pub index_errors: Vec<String>,
+    /// Set when bad tails were truncated
+    pub repaired: bool,
+}
+
+/// Outcome of checking a segment
+#[derive(Debug, Clone)]
+pub struct SegmentReport {
+    pub log_id: u64,
+    pub path: PathBuf,
+    /// Size in bytes before any repair
+    pub size: u64,
+    /// Records decoded before the first bad record, if any
+    pub records: u64,
+    pub problem: Option<RecordProblem>,
+}
+
+/// First record of a segment which could not be decoded
+#[derive(Debug, Clone)]
+pub struct RecordProblem {
+    pub offset: u64,
+    /// Bytes from the bad record to the end of the segment, ignored when the store is opened
+    pub lost_bytes: u64,
+    /// Set when the segment ends partway through the record, as after a crash mid-write
+    pub truncated: bool,
+    pub reason: String,
+}
+
+impl CheckReport {
+    /// Check whether every record was decoded and the index was rebuilt without errors
+    pub fn is_healthy(&self) -> bool {
+        self.index_errors.is_empty()
+            && self
+                .segments
+                .iter()
+                .all(|segment| segment.problem.is_none())
+    }
+
+    pub fn records(&self) -> u64 {
+        self.live + self.dead + self.tombstones
+    }
+}
+
+struct IndexEntry {
+    log_id: u64,
+    offset: u64,
+    timestamp: DateTime<Local>,
+}
+
+/// Check the bitcask store at the directory passed to BitcaskEngine::open, truncating segments
+/// at their first bad record if repair is set. Checking takes a shared lock on the store, and
+/// repairing an exclusive lock, so the store must not be open for writes. Records which cannot
+/// be decrypted abort the check, as they do opening the store.
+pub fn check(dir: &Path, keyring: Option<&Keyring>, repair: bool) -> Result<CheckReport> {
+    let db_dir = dir.join(BITCASK_DB_PATH);
+    let logs_dir = dir.join(BITCASK_LOGS_PATH);
+    if !Path::is_dir(&logs_dir) {
+        Err(HobbesError::IoError(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no bitcask store found at {dir:?}"),
+        )))?
+    }
+    let _lock = if repair {
+        Some(DirLock::exclusive(&db_dir.join(LOCK_PATH))?)
+    } else {
+        DirLock::shared(&db_dir.join(LOCK_PATH))?
+    };
+
+    let mut segments = Vec::new();
+    let mut index: HashMap<String, IndexEntry> = HashMap::new();
+    // Merged segments may hold records older than the tombstones of earlier segments
+    let mut removed_at: HashMap<String, DateTime<Local>> = HashMap::new();
+    let mut tombstones = 0;
+    let mut total_records = 0;
+
+    for (log_id, path) in segment_paths(&logs_dir)? {
+        let mut reader = SegmentReader::open(&path, keyring.cloned())?;
+        let size = reader.size();
+        let mut records = 0;
+        let mut problem = None;
+
+        loop {
+            let record = match reader.next_record() {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(err @ HobbesError::EncryptionError(_)) => return Err(err),
+                Err(err) => {
+                    problem = Some(RecordProblem {
+                        offset: reader.offset(),
+                        lost_bytes: size - reader.offset(),
+                        truncated: is_truncation(&err),
+                        reason: err.to_string(),
+                    });
+                    break;
+                }
+            };
+            records += 1;
+
+            let is_stale = index
+                .get(&record.key)
+                .is_some_and(|indexed| record.timestamp < indexed.timestamp)
+                || removed_at
+                    .get(&record.key)
+                    .is_some_and(|removed| record.timestamp < *removed);
+            if record.value.is_none() {
+                tombstones += 1;
+                if !is_stale {
+                    index.remove(&record.key);
+                    removed_at.insert(record.key, record.timestamp);
+                }
+            } else if !is_stale {
+                index.insert(
+                    record.key,
+                    IndexEntry {
+                        log_id,
+                        offset: record.offset,
+                        timestamp: record.timestamp,
+                    },
+                );
+            }
+        }
+
+        total_records += records;
+        segments.push(SegmentReport {
+            log_id,
+            path,
+            size,
+            records,
+            problem,
+        });
+    }
+
+    let index_errors = verify_index(&segments, &index, keyring)?;
+    let live = index.len() as u64;
+
+    let mut repaired = false;
+    if repair {
+        for segment in &segments {
+            if let Some(problem) = &segment.problem {
+                OpenOptions::new()
+                    .write(true)
+                    .open(&segment.path)?
+                    .set_len(problem.offset)?;
+                repaired = true;
+            }
+        }
+    }
+
+    Ok(CheckReport {
+        segments,
+        live,
+        dead: total_records - live - tombstones,
+        tombstones,
+        index_errors,
+        repaired,
+    })
+}
+
+/// Read back the record of every key in the index
+fn verify_index(
+    segments: &[SegmentReport],
+    index: &HashMap<String, IndexEntry>,
+    keyring: Option<&Keyring>,
+) -> Result<Vec<String>> {
+    let mut readers = HashMap::new();
+    for segment in segments {
+        readers.insert(segment.log_id, LogReader::open(&segment.path)?);
+    }
+
+    let mut errors = Vec::new();
+    for (key, indexed) in index {
+        let Some(reader) = readers.get_mut(&indexed.log_id) else {
+            errors.push(format!("{key}: segment {} is missing", indexed.log_id));
+            continue;
+        };
+        reader.reader.seek(SeekFrom::Start(indexed.offset))?;
+        match read_entry(&mut reader.reader, keyring, reader.version) {
+            Ok((entry, _)) if entry.key == *key => {}
+            Ok((entry, _)) => errors.push(format!(
+                "{key}: record at offset {} of segment {} holds key {}",
+                indexed.offset, indexed.log_id, entry.key
+            )),
+            Err(err) => errors.push(format!(
+                "{key}: record at offset {} of segment {} is unreadable, {err}",
+                indexed.offset, indexed.log_id
+            )),
+        }
+    }
+    errors.sort();
+    Ok(errors)
+}
+
+fn segment_paths(logs_dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(logs_dir)? {
+        let path = entry?.path();
+        if is_hint(&path) {
+            continue;
+        }
+        let log_id = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(LOG_EXTENSION))
+            .and_then(|id| id.parse::<u64>().ok())
+            .ok_or_else(|| HobbesError::CliError(format!("invalid log filename {path:?}")))?;
+        paths.push((log_id, path));
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn is_truncation(err: &HobbesError) -> bool {
+    let err = match err {
+        HobbesError::IoError(err) => err,
+        HobbesError::DeserializationError(
+            decode::Error::InvalidMarkerRead(err) | decode::Error::InvalidDataRead(err),
+        ) => err,
+        _ => return false,
+    };
+    err.kind() == io::ErrorKind::UnexpectedEof
+}
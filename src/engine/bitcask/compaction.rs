@@ -1,140 +1,371 @@
+use chrono::Local;
 use tracing::{debug, error};
 
 use std::collections::HashMap;
-use std::fs::{self, OpenOptions};
-use std::io::{Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::engine::BITCASK_COMPACTED_LOGS_SUBPATH;
 use crate::{HobbesError, RWLOCK_ERROR};
 
-use super::{serialize_command, BitcaskEngine, LogEntry, Result, ValueMetadata, LOG_EXTENSION};
+use super::header::write_header;
+use super::hint::hint_path;
+use super::{
+    read_entry, serialize_command, BitcaskEngine, BitcaskStore, FsyncPolicy, LogReader, Result,
+    SegmentFile, SegmentUsage, ValueMetadata, LOG_EXTENSION,
+};
 
-const MAX_FILE_SIZE: u64 = 1000000;
+const MUTEX_ERROR: &str = "Failed to lock Mutex";
+
+// A live record copied by a merge, moved in the index unless it changed during the merge
+struct Relocation {
+    key: String,
+    log_id: u64,
+    log_pointer: u64,
+    value_metadata: ValueMetadata,
+}
+
+/// Throttle paces the writes of a merge by sleeping whenever they run ahead of the rate limit
+struct Throttle {
+    bytes_per_sec: Option<u64>,
+    started_at: Instant,
+    written: u64,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: Option<u64>) -> Throttle {
+        Throttle {
+            bytes_per_sec,
+            started_at: Instant::now(),
+            written: 0,
+        }
+    }
+
+    fn record(&mut self, bytes: u64) {
+        let Some(bytes_per_sec) = self.bytes_per_sec else {
+            return;
+        };
+        self.written += bytes;
+        let due = Duration::from_secs_f64(self.written as f64 / bytes_per_sec as f64);
+        if let Some(ahead) = due.checked_sub(self.started_at.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+}
 
 impl BitcaskEngine {
+    /// Merge the sealed segments whose dead ratio exceeds the configured one, once a segment
+    /// has been sealed since the last merge and the sealed segments hold enough dead bytes.
+    /// Merges outside the compaction window or within the merge interval of the last one are
+    /// left for a later write.
     pub fn compaction_manager(&self) -> Result<()> {
         debug!(operation = "COMPACTION");
-        self.log_writer_init()?;
+        self.ensure_writable()?;
+
+        // Segments sealed during a running merge are considered once it completes
+        let Ok(_merging) = self.compaction_lock.try_lock() else {
+            return Ok(());
+        };
 
         let store_mutex = self.store.clone();
         let bitcask_store = store_mutex.read().expect(RWLOCK_ERROR);
+        if !bitcask_store.merge_pending {
+            return Ok(());
+        }
+
+        let outside_window = self
+            .options
+            .compaction_window
+            .is_some_and(|window| !window.contains(Local::now().time()));
+        let merged_recently = bitcask_store.last_compaction_at.is_some_and(|last| {
+            (Local::now() - last)
+                .to_std()
+                .is_ok_and(|elapsed| elapsed < self.options.merge_interval)
+        });
+        if outside_window || merged_recently {
+            return Ok(());
+        }
+        drop(bitcask_store);
 
-        let writer_len = bitcask_store.log_writer.as_ref().unwrap().metadata()?.len();
-        if writer_len < MAX_FILE_SIZE {
+        let mut bitcask_store = store_mutex.write().expect(RWLOCK_ERROR);
+        bitcask_store.merge_pending = false;
+
+        let dead_bytes: u64 = bitcask_store
+            .segment_usage
+            .iter()
+            .filter(|(log_id, _)| **log_id != bitcask_store.current_log_id)
+            .map(|(_, usage)| usage.dead_bytes())
+            .sum();
+        if dead_bytes < self.options.compaction_threshold {
             return Ok(());
         }
 
-        let bitcask_compacted_logs_path = bitcask_store
-            .db_dir
-            .join(PathBuf::from(BITCASK_COMPACTED_LOGS_SUBPATH));
+        let log_ids = self.merge_candidates(&bitcask_store);
+        drop(bitcask_store);
+        if log_ids.is_empty() {
+            return Ok(());
+        }
+        self.merge_segments(&log_ids)
+    }
 
-        fs::create_dir_all(&bitcask_compacted_logs_path)?;
+    /// Pick the sealed segments with a dead ratio above the configured one, most dead first,
+    /// until the merge reaches its size limit
+    fn merge_candidates(&self, bitcask_store: &BitcaskStore) -> Vec<u64> {
+        let mut candidates = bitcask_store
+            .segment_usage
+            .iter()
+            .filter(|(log_id, usage)| {
+                **log_id != bitcask_store.current_log_id
+                    && usage.size > 0
+                    && usage.dead_ratio() >= self.options.merge_dead_ratio
+            })
+            .map(|(log_id, usage)| (*log_id, usage.dead_ratio(), usage.size))
+            .collect::<Vec<(u64, f64, u64)>>();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
 
-        let mem_index_keys = bitcask_store
-            .mem_index
-            .keys()
-            .cloned()
-            .collect::<Vec<String>>();
+        let mut merge_bytes = 0;
+        let mut log_ids = Vec::new();
+        for (log_id, _, size) in candidates {
+            if !log_ids.is_empty() && merge_bytes + size > self.options.max_merge_bytes {
+                break;
+            }
+            merge_bytes += size;
+            log_ids.push(log_id);
+        }
+        log_ids.sort();
+        log_ids
+    }
+
+    /// Rewrite the live records of sealed segments into new segments after every existing one,
+    /// and delete them. Tombstones of removed keys are kept unless every sealed segment is
+    /// merged, as other segments may hold older records of those keys.
+    ///
+    /// Records are copied without holding the store lock, at the configured rate, and the index
+    /// is only updated once they are on disk, leaving keys written meanwhile untouched. Callers
+    /// hold the compaction lock.
+    fn merge_segments(&self, log_ids: &[u64]) -> Result<()> {
+        let merge_start = Instant::now();
+        let store_mutex = self.store.clone();
 
+        let bitcask_store = store_mutex.read().expect(RWLOCK_ERROR);
+        let logs_dir = bitcask_store.logs_dir.clone();
+        let drop_tombstones = bitcask_store
+            .segment_usage
+            .keys()
+            .all(|log_id| log_ids.contains(log_id) || *log_id == bitcask_store.current_log_id);
         drop(bitcask_store);
 
-        // The updated in-memory index
-        let mut updated_index = HashMap::new();
-
-        let mut current_compact_log_id = 1;
-        let mut current_compact_log_path =
-            bitcask_compacted_logs_path
-                .clone()
-                .join(PathBuf::from(format!(
-                    "{current_compact_log_id}{LOG_EXTENSION}"
-                )));
-        let mut current_compact_log_writer = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&current_compact_log_path).map_err(|e| {
-                    error!("[COMPACTION] Error while creating a new compacted log writer - log writer path -> {:?}", &current_compact_log_path);
-                    HobbesError::IoError(e)
-
-                })?;
-
-        let mut offset;
-
-        // Persisting compacted logs and updating the index
-        for k in mem_index_keys {
-            offset = current_compact_log_writer.metadata()?.len();
-
-            // Write to a new file if filse size threshold exceeded
-            if offset >= MAX_FILE_SIZE {
-                current_compact_log_id += 1;
-                current_compact_log_path = bitcask_compacted_logs_path.join(PathBuf::from(
-                    format!("{current_compact_log_id}{LOG_EXTENSION}"),
-                ));
-                current_compact_log_writer = OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .open(&current_compact_log_path).map_err(|e| {
-                    error!("[COMPACTION] Error while creating a new compacted log writer - log writer path -> {:?}", &current_compact_log_path);
-                    HobbesError::IoError(e)
-
-                })?;
-                offset = 0;
+        let mut merged_usage: HashMap<u64, SegmentUsage> = HashMap::new();
+        let mut relocations = Vec::new();
+        if let Err(e) = self.copy_live_records(
+            &logs_dir,
+            log_ids,
+            drop_tombstones,
+            &mut merged_usage,
+            &mut relocations,
+        ) {
+            // Partially merged segments only hold copies, so they are discarded
+            let mut bitcask_store = store_mutex.write().expect(RWLOCK_ERROR);
+            for merged_log_id in merged_usage.keys() {
+                bitcask_store.segment_usage.remove(merged_log_id);
+                let _ = fs::remove_file(segment_path(&logs_dir, *merged_log_id));
             }
+            return Err(e);
+        }
 
-            let (val, value_metadata) =
-                self.get_val_metadata(k.clone())?
-                    .ok_or(HobbesError::CompactionError(format!(
-                        "{k} present in index not found on disk while compacting!"
-                    )))?;
-
-            // Get value of key and serialise
-            let cmd = serialize_command(&LogEntry {
-                key: k.clone(),
-                val,
-                timestamp: value_metadata.timestamp,
-            })?;
-
-            current_compact_log_writer.seek(SeekFrom::Start(offset))?;
-            current_compact_log_writer.write_all(&cmd)?;
-
-            updated_index.insert(
-                k,
-                ValueMetadata {
-                    log_pointer: offset,
-                    log_id: current_compact_log_id,
-                    timestamp: value_metadata.timestamp,
-                },
+        let mut bitcask_store = store_mutex.write().expect(RWLOCK_ERROR);
+        for (merged_log_id, usage) in merged_usage {
+            bitcask_store.segment_files.insert(
+                merged_log_id,
+                SegmentFile::open(&segment_path(&logs_dir, merged_log_id), false)?,
             );
-            // debug!(
-            //     operation = "COMPACTION",
-            //     "compacted key \"{k}\" with value \"{val}\" to file {:?}  at offset {offset}\n getting from mem_index - {:?}",
-            //     current_compact_log_path,
-            //     updated_index.get(&k)
-            // );
+            bitcask_store.segment_usage.insert(merged_log_id, usage);
+        }
+        for relocation in relocations {
+            let unchanged =
+                bitcask_store
+                    .mem_index
+                    .get(&relocation.key)
+                    .is_some_and(|value_metadata| {
+                        value_metadata.log_id() == relocation.log_id
+                            && value_metadata.log_pointer == relocation.log_pointer
+                    });
+            if unchanged {
+                bitcask_store.index_insert(&relocation.key, relocation.value_metadata);
+            }
         }
 
-        // Updating KvStore
-        // TODO: Make these operations atomic
-        // TODO: Handle failure when renaming compacted logs and DB crashes
+        for log_id in log_ids {
+            bitcask_store.segment_usage.remove(log_id);
+            bitcask_store.segment_files.remove(*log_id);
+            fs::remove_file(segment_path(&logs_dir, *log_id))?;
+            // Ignoring error as only bulk-loaded segments have hints
+            let _ = fs::remove_file(hint_path(&logs_dir, *log_id));
+        }
 
-        let mut bitcask_store = store_mutex.write().expect(RWLOCK_ERROR);
+        bitcask_store.compaction_count += 1;
+        bitcask_store.last_compaction_at = Some(Local::now());
+        bitcask_store.last_compaction_duration = Some(merge_start.elapsed());
+        debug!(
+            operation = "COMPACTION",
+            segments = log_ids.len(),
+            duration_ms = merge_start.elapsed().as_millis() as u64,
+            "Merge complete"
+        );
 
-        bitcask_store.log_readers = None;
-        // Ignoring error as directory may not exist
-        let _ = fs::remove_dir_all(&bitcask_store.logs_dir);
+        Ok(())
+    }
 
-        fs::rename(&bitcask_compacted_logs_path, &bitcask_store.logs_dir).map_err(|e| {
-            error!(
-                "[COMPACTION] Error while renaming {:?} to {:?}, Current logs dir -> {:?}",
-                bitcask_compacted_logs_path, bitcask_store.logs_dir, bitcask_store.logs_dir
-            );
-            HobbesError::IoError(e)
-        })?;
+    /// Copy the live records of the segments, and the tombstones kept, into new segments whose
+    /// ids are reserved in the store as they are created
+    fn copy_live_records(
+        &self,
+        logs_dir: &Path,
+        log_ids: &[u64],
+        drop_tombstones: bool,
+        merged_usage: &mut HashMap<u64, SegmentUsage>,
+        relocations: &mut Vec<Relocation>,
+    ) -> Result<()> {
+        let store_mutex = self.store.clone();
+        let mut throttle = Throttle::new(self.options.compaction_rate_limit);
+        let mut merged_log_writer: Option<(u64, File)> = None;
+        let mut offset = 0;
 
-        bitcask_store.mem_index = updated_index;
-        bitcask_store.current_log_id = current_compact_log_id + 1;
-        bitcask_store.log_writer = None;
+        for log_id in log_ids {
+            let LogReader {
+                reader: mut log_reader,
+                version,
+                ..
+            } = LogReader::open(&segment_path(logs_dir, *log_id))?;
+            let segment_size = log_reader.get_ref().metadata()?.len();
+            let mut record_offset = log_reader.stream_position()?;
+
+            // Records after the first unreadable one are ignored, as they are when replaying
+            while record_offset < segment_size {
+                let Ok((cmd, _)) =
+                    read_entry(&mut log_reader, self.options.keyring.as_ref(), version)
+                else {
+                    break;
+                };
+                let copied_offset = record_offset;
+                record_offset = log_reader.stream_position()?;
+
+                let bitcask_store = store_mutex.read().expect(RWLOCK_ERROR);
+                let indexed = bitcask_store.mem_index.get(&cmd.key);
+                let is_live = indexed.is_some_and(|value_metadata| {
+                    value_metadata.log_id() == *log_id
+                        && value_metadata.log_pointer == copied_offset
+                });
+                let keeps_tombstone = cmd.is_delete() && !drop_tombstones && indexed.is_none();
+                drop(bitcask_store);
+                if !is_live && !keeps_tombstone {
+                    continue;
+                }
+
+                if merged_log_writer.is_none() || offset >= self.options.segment_size {
+                    if let Some((_, log_writer)) = merged_log_writer.take() {
+                        if self.options.fsync != FsyncPolicy::Never {
+                            log_writer.sync_data()?;
+                        }
+                    }
+
+                    // Reserving the id keeps the active log from rolling over onto it
+                    let mut bitcask_store = store_mutex.write().expect(RWLOCK_ERROR);
+                    let merged_log_id = bitcask_store.next_log_id();
+                    bitcask_store
+                        .segment_usage
+                        .insert(merged_log_id, SegmentUsage::default());
+                    drop(bitcask_store);
+                    merged_usage.insert(merged_log_id, SegmentUsage::default());
+
+                    let merged_log_path = segment_path(logs_dir, merged_log_id);
+                    let mut log_writer = OpenOptions::new()
+                        .create_new(true)
+                        .append(true)
+                        .open(&merged_log_path)
+                        .map_err(|e| {
+                            error!("[COMPACTION] Error while creating a new merged log writer - log writer path -> {:?}", &merged_log_path);
+                            HobbesError::IoError(e)
+                        })?;
+                    offset = write_header(&mut log_writer)?;
+                    let usage = merged_usage.entry(merged_log_id).or_default();
+                    usage.size = offset;
+                    usage.header_bytes = offset;
+                    merged_log_writer = Some((merged_log_id, log_writer));
+                }
+                let (merged_log_id, log_writer) = merged_log_writer.as_mut().unwrap();
+
+                let record = serialize_command(
+                    &cmd,
+                    self.options.compression.as_ref(),
+                    self.options.keyring.as_ref(),
+                )?;
+                let entry_len = record.len() as u64;
+                log_writer.write_all(&record)?;
+                throttle.record(entry_len);
+
+                let usage = merged_usage.entry(*merged_log_id).or_default();
+                usage.size += entry_len;
+                if is_live {
+                    relocations.push(Relocation {
+                        key: cmd.key,
+                        log_id: *log_id,
+                        log_pointer: copied_offset,
+                        value_metadata: ValueMetadata::new(*merged_log_id, offset, entry_len)?,
+                    });
+                } else {
+                    usage.tombstone_bytes += entry_len;
+                }
+                offset += entry_len;
+            }
+        }
 
+        // Merged segments replace the sealed ones, so they are flushed before those are deleted
+        // unless flushing is left to the OS
+        if let Some((_, log_writer)) = merged_log_writer {
+            if self.options.fsync != FsyncPolicy::Never {
+                log_writer.sync_data()?;
+            }
+        }
         Ok(())
     }
+
+    /// Seal the active log and merge every sealed segment, rewriting the live records with the
+    /// configured codec and the active encryption key, and dropping every tombstone. Compacting
+    /// ignores the compaction window but not the rate limit, and waits for a running merge.
+    pub(super) fn compact_all(&self) -> Result<()> {
+        self.ensure_writable()?;
+        let _merging = self.compaction_lock.lock().expect(MUTEX_ERROR);
+
+        let store_mutex = self.store.clone();
+        let mut bitcask_store = store_mutex.write().expect(RWLOCK_ERROR);
+        let active_size = bitcask_store
+            .segment_usage
+            .get(&bitcask_store.current_log_id)
+            .map_or(0, |usage| usage.size);
+        if active_size > 0 {
+            self.seal_active_log(&mut bitcask_store)?;
+        }
+        bitcask_store.merge_pending = false;
+
+        let mut log_ids = bitcask_store
+            .segment_usage
+            .keys()
+            .filter(|log_id| **log_id != bitcask_store.current_log_id)
+            .copied()
+            .collect::<Vec<u64>>();
+        log_ids.sort();
+        drop(bitcask_store);
+
+        if log_ids.is_empty() {
+            return Ok(());
+        }
+        self.merge_segments(&log_ids)
+    }
+}
+
+fn segment_path(logs_dir: &Path, log_id: u64) -> PathBuf {
+    logs_dir.join(format!("{log_id}{LOG_EXTENSION}"))
 }
@@ -0,0 +1,89 @@
+//! Compression of log records
+//!
+//! A compressed record is stored as a MessagePack extension value whose payload holds the codec
+//! followed by the compressed serialized record. Compression happens before encryption, so an
+//! encrypted record may hold a compressed record.
+
+use std::io;
+
+use crate::{HobbesError, Result};
+
+// MessagePack extension type marking compressed records
+pub(super) const COMPRESSED_RECORD_EXT_TYPE: i8 = 2;
+// Records smaller than this are rarely worth compressing
+const DEFAULT_MIN_RECORD_SIZE: usize = 256;
+const ZSTD_LEVEL: i32 = 3;
+
+const LZ4_CODEC_ID: u8 = 1;
+const ZSTD_CODEC_ID: u8 = 2;
+
+/// Algorithm compressing records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// Fast compression with a moderate ratio
+    Lz4,
+    /// Slower compression with a higher ratio
+    Zstd,
+}
+
+/// Compression applied to records written by the bitcask engine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub codec: Codec,
+    /// Serialized records smaller than this many bytes are stored uncompressed
+    pub min_record_size: usize,
+}
+
+impl Compression {
+    pub fn new(codec: Codec) -> Compression {
+        Compression {
+            codec,
+            min_record_size: DEFAULT_MIN_RECORD_SIZE,
+        }
+    }
+
+    /// Compress a serialized record, returning None if it is too small or would not shrink
+    pub(super) fn compress(&self, record: &[u8]) -> Result<Option<Vec<u8>>> {
+        if record.len() < self.min_record_size {
+            return Ok(None);
+        }
+
+        let (codec_id, compressed) = match self.codec {
+            Codec::Lz4 => (LZ4_CODEC_ID, lz4_flex::compress_prepend_size(record)),
+            Codec::Zstd => (ZSTD_CODEC_ID, zstd::bulk::compress(record, ZSTD_LEVEL)?),
+        };
+        // The extension header and codec take up to 6 bytes
+        if compressed.len() + 6 >= record.len() {
+            return Ok(None);
+        }
+
+        let payload_len = compressed.len() + 1;
+        let mut compressed_record = Vec::with_capacity(payload_len + 5);
+        rmp::encode::write_ext_meta(
+            &mut compressed_record,
+            payload_len as u32,
+            COMPRESSED_RECORD_EXT_TYPE,
+        )
+        .map_err(|e| HobbesError::IoError(io::Error::other(e)))?;
+        compressed_record.push(codec_id);
+        compressed_record.extend_from_slice(&compressed);
+        Ok(Some(compressed_record))
+    }
+}
+
+/// Decompress the payload of a compressed record, returning the serialized record
+pub(super) fn decompress(payload: &[u8]) -> Result<Vec<u8>> {
+    let invalid_data =
+        |msg: String| HobbesError::IoError(io::Error::new(io::ErrorKind::InvalidData, msg));
+
+    match payload.split_first() {
+        Some((&LZ4_CODEC_ID, compressed)) => lz4_flex::decompress_size_prepended(compressed)
+            .map_err(|e| invalid_data(format!("failed to decompress LZ4 record, {e}"))),
+        Some((&ZSTD_CODEC_ID, compressed)) => zstd::decode_all(compressed)
+            .map_err(|e| invalid_data(format!("failed to decompress zstd record, {e}"))),
+        Some((codec_id, _)) => Err(invalid_data(format!(
+            "unknown compression codec {codec_id}"
+        ))),
+        None => Err(invalid_data(String::from("compressed record is empty"))),
+    }
+}
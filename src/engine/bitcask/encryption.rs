@@ -0,0 +1,174 @@
+//! Authenticated encryption of log records at rest
+//!
+//! An encrypted record is stored as a MessagePack extension value, keeping segments a valid
+//! MessagePack stream. Its payload holds the fingerprint of the key which sealed it, a random
+//! nonce, and the ChaCha20-Poly1305 ciphertext of the serialized record.
+
+use blake2::{Blake2b512, Digest};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::{HobbesError, Result};
+
+/// Environment variable holding the encryption keys when no key file is configured
+pub const ENCRYPTION_KEY_ENV: &str = "HOBBES_ENCRYPTION_KEY";
+
+// MessagePack extension type marking encrypted records
+pub(super) const ENCRYPTED_RECORD_EXT_TYPE: i8 = 1;
+const KEY_LEN: usize = 32;
+const FINGERPRINT_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+
+/// Keyring holds the key encrypting new records, along with retired keys still able to
+/// decrypt records written before a key rotation
+#[derive(Clone)]
+pub struct Keyring {
+    active: EncryptionKey,
+    retired: Vec<EncryptionKey>,
+}
+
+#[derive(Clone)]
+struct EncryptionKey {
+    fingerprint: [u8; FINGERPRINT_LEN],
+    cipher: ChaCha20Poly1305,
+}
+
+impl fmt::Debug for Keyring {
+    // Keys are never printed
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("retired_keys", &self.retired.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Keyring {
+    /// Parse hex-encoded 256-bit keys separated by whitespace or commas. The first key encrypts
+    /// new records, and the remaining keys are retired keys used only for decryption. Lines
+    /// starting with `#` are ignored.
+    pub fn parse(keys: &str) -> Result<Keyring> {
+        let mut keys = keys
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .flat_map(|line| line.split(|c: char| c == ',' || c.is_whitespace()))
+            .filter(|key| !key.is_empty())
+            .map(EncryptionKey::from_hex);
+
+        let active = keys.next().ok_or_else(|| {
+            HobbesError::EncryptionError(String::from("no encryption key provided"))
+        })??;
+        let retired = keys.collect::<Result<Vec<EncryptionKey>>>()?;
+
+        Ok(Keyring { active, retired })
+    }
+
+    /// Read the keys from a key file
+    pub fn from_file(path: &Path) -> Result<Keyring> {
+        let keys = fs::read_to_string(path).map_err(|e| {
+            HobbesError::EncryptionError(format!("failed to read key file {path:?}, {e}"))
+        })?;
+        Keyring::parse(&keys)
+    }
+
+    /// Read the keys from the HOBBES_ENCRYPTION_KEY environment variable, if set
+    pub fn from_env() -> Result<Option<Keyring>> {
+        match env::var(ENCRYPTION_KEY_ENV) {
+            Ok(keys) => Ok(Some(Keyring::parse(&keys)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Generate a random hex-encoded key
+    pub fn generate_key() -> String {
+        ChaCha20Poly1305::generate_key(&mut OsRng)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// Check whether a record was sealed with a retired key
+    pub(super) fn is_retired(&self, fingerprint: [u8; FINGERPRINT_LEN]) -> bool {
+        fingerprint != self.active.fingerprint
+    }
+
+    /// Encrypt a serialized record with the active key, returning the record to be stored
+    pub(super) fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext =
+            self.active.cipher.encrypt(&nonce, plaintext).map_err(|e| {
+                HobbesError::EncryptionError(format!("failed to encrypt record, {e}"))
+            })?;
+
+        let payload_len = FINGERPRINT_LEN + NONCE_LEN + ciphertext.len();
+        let mut record = Vec::with_capacity(payload_len + 6);
+        rmp::encode::write_ext_meta(&mut record, payload_len as u32, ENCRYPTED_RECORD_EXT_TYPE)
+            .map_err(|e| HobbesError::EncryptionError(format!("failed to encode record, {e}")))?;
+        record.extend_from_slice(&self.active.fingerprint);
+        record.extend_from_slice(&nonce);
+        record.extend_from_slice(&ciphertext);
+        Ok(record)
+    }
+
+    /// Decrypt a sealed payload, returning the serialized record and the fingerprint of the key
+    /// which sealed it
+    pub(super) fn open(&self, payload: &[u8]) -> Result<(Vec<u8>, [u8; FINGERPRINT_LEN])> {
+        if payload.len() < FINGERPRINT_LEN + NONCE_LEN {
+            Err(HobbesError::EncryptionError(String::from(
+                "encrypted record is truncated",
+            )))?
+        }
+        let (fingerprint, rest) = payload.split_at(FINGERPRINT_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        let key = std::iter::once(&self.active)
+            .chain(&self.retired)
+            .find(|key| key.fingerprint == fingerprint)
+            .ok_or_else(|| {
+                HobbesError::EncryptionError(String::from(
+                    "record was encrypted with a key missing from the keyring",
+                ))
+            })?;
+        let plaintext = key
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| {
+                HobbesError::EncryptionError(String::from(
+                    "failed to authenticate encrypted record",
+                ))
+            })?;
+
+        Ok((plaintext, key.fingerprint))
+    }
+}
+
+impl EncryptionKey {
+    fn from_hex(hex: &str) -> Result<EncryptionKey> {
+        let invalid_key = || {
+            HobbesError::EncryptionError(format!(
+                "encryption keys must be {} hex characters",
+                KEY_LEN * 2
+            ))
+        };
+        if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+            return Err(invalid_key());
+        }
+
+        let mut key = [0u8; KEY_LEN];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid_key())?;
+        }
+
+        let mut fingerprint = [0u8; FINGERPRINT_LEN];
+        fingerprint.copy_from_slice(&Blake2b512::digest(key)[..FINGERPRINT_LEN]);
+
+        Ok(EncryptionKey {
+            fingerprint,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+}
@@ -0,0 +1,113 @@
+//! Segment headers identifying the format of the records of a segment
+//!
+//! Segments start with the magic bytes `HBSG` followed by the format version as a little-endian
+//! u16. From version 3, the version is followed by the length of the creation info as a
+//! little-endian u32 and the creation info itself, encoded with MessagePack. Segments written
+//! before headers were introduced start directly with their first record, whose MessagePack
+//! marker never matches the magic, and are read as the legacy format, which marks removed keys
+//! with a tombstone value instead of a record type. Segments of a version newer than
+//! FORMAT_VERSION are refused.
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use std::io::{self, BufRead, Seek, Write};
+
+use crate::{HobbesError, Result};
+
+const SEGMENT_MAGIC: &[u8; 4] = b"HBSG";
+/// Version of the segments written by this version of hobbes
+pub(super) const FORMAT_VERSION: u16 = 3;
+/// Version of segments without a header
+pub(super) const LEGACY_FORMAT_VERSION: u16 = 1;
+// First version whose header holds creation info
+const SEGMENT_INFO_VERSION: u16 = 3;
+
+/// Creation info recorded in the header of a segment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentInfo {
+    pub created_at: DateTime<Local>,
+    /// Version of hobbes which wrote the segment
+    pub created_by: String,
+}
+
+/// Header read from a segment
+#[derive(Debug, Clone)]
+pub(super) struct SegmentHeader {
+    pub(super) version: u16,
+    /// Creation info, None for segments of versions before 3
+    pub(super) info: Option<SegmentInfo>,
+}
+
+/// Write the header of a new segment, returning its length in bytes
+pub(super) fn write_header(writer: &mut impl Write) -> Result<u64> {
+    let info = rmp_serde::to_vec(&SegmentInfo {
+        created_at: Local::now(),
+        created_by: format!("hobbes {}", env!("CARGO_PKG_VERSION")),
+    })?;
+    writer.write_all(SEGMENT_MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(&(info.len() as u32).to_le_bytes())?;
+    writer.write_all(&info)?;
+    Ok((SEGMENT_MAGIC.len() + 2 + 4 + info.len()) as u64)
+}
+
+/// Read the header of a segment, leaving the reader at its first record. Segments written by a
+/// newer version of hobbes are refused with HobbesError::UnsupportedFormatError.
+pub(super) fn read_header(reader: &mut (impl BufRead + Seek)) -> Result<SegmentHeader> {
+    reader.rewind()?;
+    let start = reader.fill_buf()?;
+    if start.is_empty() || start[0] != SEGMENT_MAGIC[0] {
+        return Ok(SegmentHeader {
+            version: LEGACY_FORMAT_VERSION,
+            info: None,
+        });
+    }
+
+    let mut header = [0u8; SEGMENT_MAGIC.len() + 2];
+    read_header_bytes(reader, &mut header)?;
+    if header[..SEGMENT_MAGIC.len()] != SEGMENT_MAGIC[..] {
+        Err(HobbesError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid segment header",
+        )))?
+    }
+
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version > FORMAT_VERSION {
+        Err(HobbesError::UnsupportedFormatError(format!(
+            "segment format version {version} is newer than version {FORMAT_VERSION} supported by hobbes {}",
+            env!("CARGO_PKG_VERSION")
+        )))?
+    }
+    if version <= LEGACY_FORMAT_VERSION {
+        Err(HobbesError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid segment format version {version}"),
+        )))?
+    }
+    if version < SEGMENT_INFO_VERSION {
+        return Ok(SegmentHeader {
+            version,
+            info: None,
+        });
+    }
+
+    let mut info_len = [0u8; 4];
+    read_header_bytes(reader, &mut info_len)?;
+    let mut info = vec![0u8; u32::from_le_bytes(info_len) as usize];
+    read_header_bytes(reader, &mut info)?;
+    Ok(SegmentHeader {
+        version,
+        info: Some(rmp_serde::from_slice(&info)?),
+    })
+}
+
+fn read_header_bytes(reader: &mut impl BufRead, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| {
+        HobbesError::IoError(io::Error::new(
+            e.kind(),
+            format!("truncated segment header, {e}"),
+        ))
+    })
+}
@@ -0,0 +1,83 @@
+//! Hint files listing the live records of immutable segments
+//!
+//! A hint file sits next to its segment as `<log_id>.hint`, and holds the size of the segment
+//! when it was written followed by the key, position and timestamp of each live record. Opening
+//! a store builds the index of a segment from its hint instead of decoding every record, unless
+//! the segment has changed size since, the segment is the active log, or the store is
+//! encrypted, as hints hold keys in the clear.
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::Result;
+
+pub(super) const HINT_EXTENSION: &str = ".hint";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Hint {
+    segment_size: u64,
+    entries: Vec<HintEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct HintEntry {
+    pub(super) key: String,
+    pub(super) log_pointer: u64,
+    pub(super) entry_len: u64,
+    pub(super) timestamp: DateTime<Local>,
+}
+
+pub(super) fn hint_path(logs_dir: &Path, log_id: u64) -> PathBuf {
+    logs_dir.join(format!("{log_id}{HINT_EXTENSION}"))
+}
+
+/// Write the hint of a segment, flushing it to disk if sync is set
+pub(super) fn write_hint(
+    path: &Path,
+    segment_size: u64,
+    entries: Vec<HintEntry>,
+    sync: bool,
+) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    rmp_serde::encode::write(
+        &mut writer,
+        &Hint {
+            segment_size,
+            entries,
+        },
+    )?;
+    writer.flush()?;
+    if sync {
+        writer.get_ref().sync_data()?;
+    }
+    Ok(())
+}
+
+/// Read the hint of a segment, returning None if it is missing, unreadable or describes a
+/// segment of another size
+pub(super) fn read_hint(path: &Path, segment_size: u64) -> Option<Vec<HintEntry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Ignoring unreadable hint file {path:?}, {e}");
+            return None;
+        }
+    };
+    match rmp_serde::from_read::<_, Hint>(BufReader::new(file)) {
+        Ok(hint) if hint.segment_size == segment_size => Some(hint.entries),
+        Ok(_) => {
+            warn!("Ignoring hint file {path:?} written for a segment of another size");
+            None
+        }
+        Err(e) => {
+            warn!("Ignoring unreadable hint file {path:?}, {e}");
+            None
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! Compact in-memory index of the live record of each key
+//!
+//! Keys are copied once into an arena of large chunks, each key prefixed by its length as a LEB128
+//! varint, instead of being allocated one by one. The hash table only holds the position of the
+//! key in the arena along with its value, which for the index of a store is the packed position of
+//! its record. Removed keys leave their bytes in the arena until they outweigh the live ones, when
+//! the arena is rebuilt.
+//!
+//! The index of a store is a ConcurrentKeyDir, spreading keys by hash across shards locked
+//! independently, so that reads only wait for updates of keys in the same shard.
+
+use hashbrown::HashTable;
+
+use std::hash::{BuildHasher, RandomState};
+use std::mem;
+use std::sync::RwLock;
+
+use crate::RWLOCK_ERROR;
+
+use super::ValueMetadata;
+
+// Arena chunks double in size from the minimum up to the maximum, keys larger than a chunk getting
+// one of their own
+const MIN_CHUNK_SIZE: usize = 4 << 10;
+const MAX_CHUNK_SIZE: usize = 1 << 20;
+const SHARD_COUNT: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+struct KeyRef {
+    chunk: u32,
+    offset: u32,
+}
+
+#[derive(Debug)]
+struct Slot<V> {
+    key: KeyRef,
+    value: V,
+}
+
+#[derive(Debug, Default)]
+struct Arena {
+    chunks: Vec<Vec<u8>>,
+    live_bytes: usize,
+    dead_bytes: usize,
+}
+
+impl Arena {
+    fn push(&mut self, key: &[u8]) -> KeyRef {
+        let mut len_prefix = [0u8; 10];
+        let prefix_len = encode_len(key.len(), &mut len_prefix);
+        let needed = prefix_len + key.len();
+
+        let fits = self
+            .chunks
+            .last()
+            .is_some_and(|chunk| chunk.capacity() - chunk.len() >= needed);
+        if !fits {
+            let chunk_size = self.chunks.last().map_or(MIN_CHUNK_SIZE, |chunk| {
+                (chunk.capacity() * 2).clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE)
+            });
+            self.chunks.push(Vec::with_capacity(chunk_size.max(needed)));
+        }
+
+        let chunk_id = self.chunks.len() - 1;
+        let chunk = &mut self.chunks[chunk_id];
+        let offset = chunk.len();
+        chunk.extend_from_slice(&len_prefix[..prefix_len]);
+        chunk.extend_from_slice(key);
+        self.live_bytes += needed;
+        KeyRef {
+            chunk: chunk_id as u32,
+            offset: offset as u32,
+        }
+    }
+
+    fn get(&self, key_ref: KeyRef) -> &[u8] {
+        let chunk = &self.chunks[key_ref.chunk as usize];
+        let (len, prefix_len) = decode_len(&chunk[key_ref.offset as usize..]);
+        let start = key_ref.offset as usize + prefix_len;
+        &chunk[start..start + len]
+    }
+
+    fn release(&mut self, key_ref: KeyRef) {
+        let len = self.get(key_ref).len();
+        let needed = encode_len(len, &mut [0u8; 10]) + len;
+        self.live_bytes -= needed;
+        self.dead_bytes += needed;
+    }
+
+    fn capacity(&self) -> usize {
+        self.chunks.iter().map(Vec::capacity).sum()
+    }
+}
+
+/// KeyDir maps keys to values, storing the keys in an arena
+#[derive(Debug)]
+pub(super) struct KeyDir<V = ValueMetadata> {
+    table: HashTable<Slot<V>>,
+    arena: Arena,
+    hasher: RandomState,
+}
+
+impl<V> Default for KeyDir<V> {
+    fn default() -> Self {
+        KeyDir {
+            table: HashTable::new(),
+            arena: Arena::default(),
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<V> KeyDir<V> {
+    pub(super) fn new() -> KeyDir<V> {
+        KeyDir::default()
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub(super) fn get(&self, key: &str) -> Option<&V> {
+        let hash = self.hasher.hash_one(key.as_bytes());
+        self.table
+            .find(hash, |slot| self.arena.get(slot.key) == key.as_bytes())
+            .map(|slot| &slot.value)
+    }
+
+    /// Insert a value, returning the one it replaces
+    pub(super) fn insert(&mut self, key: &str, value: V) -> Option<V> {
+        let hash = self.hasher.hash_one(key.as_bytes());
+        let arena = &mut self.arena;
+        if let Some(slot) = self
+            .table
+            .find_mut(hash, |slot| arena.get(slot.key) == key.as_bytes())
+        {
+            return Some(mem::replace(&mut slot.value, value));
+        }
+
+        let key_ref = arena.push(key.as_bytes());
+        let (arena, hasher) = (&self.arena, &self.hasher);
+        self.table.insert_unique(
+            hash,
+            Slot {
+                key: key_ref,
+                value,
+            },
+            |slot| hasher.hash_one(arena.get(slot.key)),
+        );
+        None
+    }
+
+    pub(super) fn remove(&mut self, key: &str) -> Option<V> {
+        let hash = self.hasher.hash_one(key.as_bytes());
+        let arena = &self.arena;
+        let (slot, _) = self
+            .table
+            .find_entry(hash, |slot| arena.get(slot.key) == key.as_bytes())
+            .ok()?
+            .remove();
+        self.arena.release(slot.key);
+
+        if self.arena.dead_bytes >= MIN_CHUNK_SIZE && self.arena.dead_bytes > self.arena.live_bytes
+        {
+            self.rebuild_arena();
+        }
+        Some(slot.value)
+    }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item = (&str, &V)> {
+        self.table.iter().map(|slot| {
+            let key = std::str::from_utf8(self.arena.get(slot.key)).expect("keys are UTF-8");
+            (key, &slot.value)
+        })
+    }
+
+    /// Bytes allocated for the table and the arena
+    pub(super) fn memory_usage(&self) -> u64 {
+        (self.table.allocation_size() + self.arena.capacity()) as u64
+    }
+
+    /// Convert the values, keeping the keys in place
+    pub(super) fn map_values<W>(self, mut f: impl FnMut(V) -> W) -> KeyDir<W> {
+        let mut table = HashTable::with_capacity(self.table.len());
+        for slot in self.table {
+            let hash = self.hasher.hash_one(self.arena.get(slot.key));
+            table.insert_unique(
+                hash,
+                Slot {
+                    key: slot.key,
+                    value: f(slot.value),
+                },
+                |slot: &Slot<W>| self.hasher.hash_one(self.arena.get(slot.key)),
+            );
+        }
+        KeyDir {
+            table,
+            arena: self.arena,
+            hasher: self.hasher,
+        }
+    }
+
+    // Copy the live keys into a new arena, dropping the removed ones
+    fn rebuild_arena(&mut self) {
+        let mut arena = Arena::default();
+        for slot in self.table.iter_mut() {
+            slot.key = arena.push(self.arena.get(slot.key));
+        }
+        self.arena = arena;
+    }
+}
+
+/// ConcurrentKeyDir maps keys to values across shards which are locked independently
+#[derive(Debug)]
+pub(super) struct ConcurrentKeyDir<V = ValueMetadata> {
+    shards: Box<[RwLock<KeyDir<V>>]>,
+    hasher: RandomState,
+}
+
+impl<V: Copy> ConcurrentKeyDir<V> {
+    pub(super) fn new() -> ConcurrentKeyDir<V> {
+        ConcurrentKeyDir {
+            shards: (0..SHARD_COUNT).map(|_| RwLock::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &RwLock<KeyDir<V>> {
+        let hash = self.hasher.hash_one(key.as_bytes());
+        &self.shards[hash as usize % self.shards.len()]
+    }
+
+    pub(super) fn get(&self, key: &str) -> Option<V> {
+        self.shard(key)
+            .read()
+            .expect(RWLOCK_ERROR)
+            .get(key)
+            .copied()
+    }
+
+    /// Insert a value, returning the one it replaces
+    pub(super) fn insert(&self, key: &str, value: V) -> Option<V> {
+        self.shard(key)
+            .write()
+            .expect(RWLOCK_ERROR)
+            .insert(key, value)
+    }
+
+    pub(super) fn remove(&self, key: &str) -> Option<V> {
+        self.shard(key).write().expect(RWLOCK_ERROR).remove(key)
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().expect(RWLOCK_ERROR).len())
+            .sum()
+    }
+
+    /// Visit every key and value, locking one shard at a time, so that updates made meanwhile to
+    /// other shards may or may not be seen
+    pub(super) fn for_each(&self, mut f: impl FnMut(&str, &V)) {
+        for shard in self.shards.iter() {
+            for (key, value) in shard.read().expect(RWLOCK_ERROR).iter() {
+                f(key, value);
+            }
+        }
+    }
+
+    /// Bytes allocated for the tables and arenas of all shards
+    pub(super) fn memory_usage(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().expect(RWLOCK_ERROR).memory_usage())
+            .sum()
+    }
+
+    /// Convert the values, keeping the keys in place
+    pub(super) fn map_values<W>(self, mut f: impl FnMut(V) -> W) -> ConcurrentKeyDir<W> {
+        let shards =
+            self.shards.into_vec().into_iter().map(|shard| {
+                RwLock::new(shard.into_inner().expect(RWLOCK_ERROR).map_values(&mut f))
+            });
+        ConcurrentKeyDir {
+            shards: shards.collect(),
+            hasher: self.hasher,
+        }
+    }
+}
+
+fn encode_len(mut len: usize, buf: &mut [u8; 10]) -> usize {
+    let mut i = 0;
+    while len >= 0x80 {
+        buf[i] = (len as u8) | 0x80;
+        len >>= 7;
+        i += 1;
+    }
+    buf[i] = len as u8;
+    i + 1
+}
+
+fn decode_len(buf: &[u8]) -> (usize, usize) {
+    let mut len = 0;
+    for (i, byte) in buf.iter().enumerate() {
+        len |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return (len, i + 1);
+        }
+    }
+    unreachable!("arena keys are prefixed by their length")
+}
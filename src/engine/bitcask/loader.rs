@@ -0,0 +1,251 @@
+//! Offline bulk loading of fresh stores
+//!
+//! Loading appends each pair to segments in a staging directory, rolling over to a new segment
+//! at the configured segment size, without triggering compaction. Finishing writes a hint file
+//! per segment and an empty active log, then moves the segments into place, so that an
+//! interrupted load leaves no store behind.
+
+use chrono::{DateTime, Local};
+use tracing::{debug, error};
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::engine::lock::DirLock;
+use crate::engine::{BITCASK_DB_PATH, BITCASK_LOGS_PATH, SLED_DB_PATH};
+use crate::{HobbesError, Result};
+
+use super::header::write_header;
+use super::hint::{hint_path, write_hint, HintEntry};
+use super::metadata::StoreMetadata;
+use super::{serialize_command, BitcaskOptions, FsyncPolicy, LogEntry, LOCK_PATH, LOG_EXTENSION};
+
+const BULK_LOAD_STAGING_SUBPATH: &str = "bulk-load/";
+
+/// BulkLoader writes key-value pairs straight into the segments of a fresh store, producing a
+/// store which BitcaskEngine::open accepts without replaying or compacting it
+///
+/// ```
+/// use tempfile::TempDir;
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+///
+/// use hobbes::engine::bitcask::{BitcaskEngine, BitcaskOptions, BulkLoader};
+/// use hobbes::engine::Engine;
+///
+/// let mut loader = BulkLoader::create(temp_dir.path(), BitcaskOptions::new())
+///     .expect("unable to create the store");
+/// loader.add("Foo".to_owned(), "Bar".to_owned()).expect("unable to add key 'Foo'");
+/// loader.finish().expect("unable to finish the load");
+///
+/// let kv_store = BitcaskEngine::open(temp_dir.path()).expect("unable to open the store");
+/// assert_eq!(kv_store.get("Foo".to_owned()).expect("unable to get key 'Foo'"), Some("Bar".to_owned()));
+/// ```
+pub struct BulkLoader {
+    options: BitcaskOptions,
+    db_dir: PathBuf,
+    logs_dir: PathBuf,
+    staging_dir: PathBuf,
+    index: HashMap<String, LoadedEntry>,
+    writer: BufWriter<File>,
+    log_id: u64,
+    offset: u64,
+    pairs: u64,
+    _lock: DirLock,
+}
+
+struct LoadedEntry {
+    log_id: u64,
+    log_pointer: u64,
+    entry_len: u64,
+    timestamp: DateTime<Local>,
+}
+
+/// Counts reported by BulkLoader::finish
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BulkLoadReport {
+    /// Number of pairs added
+    pub pairs: u64,
+    /// Number of distinct keys stored
+    pub keys: u64,
+    /// Number of segments written, excluding the empty active log
+    pub segments: u64,
+}
+
+impl BulkLoader {
+    /// Create a store at the specified directory, which must not hold a store yet. The directory
+    /// stays locked until the load is finished or dropped.
+    pub fn create(logs_dir_arg: &Path, options: BitcaskOptions) -> Result<BulkLoader> {
+        if options.segment_size == 0 {
+            Err(HobbesError::CliError(String::from(
+                "segment size must be greater than zero",
+            )))?
+        }
+        if Path::is_dir(&logs_dir_arg.join(SLED_DB_PATH)) {
+            Err(HobbesError::CliError(String::from(
+                "sled storage engine used previously, using the bitcask engine is an invalid operation",
+            )))?
+        }
+
+        let db_dir = logs_dir_arg.join(BITCASK_DB_PATH);
+        let logs_dir = logs_dir_arg.join(BITCASK_LOGS_PATH);
+        fs::create_dir_all(&db_dir)?;
+        let lock = DirLock::exclusive(&db_dir.join(LOCK_PATH))?;
+
+        if Path::is_dir(&logs_dir) && fs::read_dir(&logs_dir)?.next().is_some() {
+            Err(HobbesError::CliError(format!(
+                "bulk loads need a fresh store, but {logs_dir_arg:?} already holds one"
+            )))?
+        }
+
+        // Left behind by an interrupted load
+        let staging_dir = db_dir.join(BULK_LOAD_STAGING_SUBPATH);
+        match fs::remove_dir_all(&staging_dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e)?,
+            _ => {}
+        }
+        fs::create_dir_all(&staging_dir)?;
+
+        let (writer, offset) = create_segment(&staging_dir, 1)?;
+        Ok(BulkLoader {
+            options,
+            db_dir,
+            logs_dir,
+            staging_dir,
+            index: HashMap::new(),
+            writer,
+            log_id: 1,
+            offset,
+            pairs: 0,
+            _lock: lock,
+        })
+    }
+
+    /// Add a key-value pair. Keys added more than once keep their last value, leaving the
+    /// earlier records as dead bytes for compaction to reclaim.
+    pub fn add(&mut self, key: String, value: String) -> Result<()> {
+        if self.offset >= self.options.segment_size {
+            self.roll_over()?;
+        }
+
+        let timestamp = Local::now();
+        let cmd = serialize_command(
+            &LogEntry::put(key.clone(), value, timestamp),
+            self.options.compression.as_ref(),
+            self.options.keyring.as_ref(),
+        )?;
+        self.writer.write_all(&cmd)?;
+
+        self.index.insert(
+            key,
+            LoadedEntry {
+                log_id: self.log_id,
+                log_pointer: self.offset,
+                entry_len: cmd.len() as u64,
+                timestamp,
+            },
+        );
+        self.offset += cmd.len() as u64;
+        self.pairs += 1;
+        Ok(())
+    }
+
+    /// Add every pair of a stream, in any order
+    pub fn add_all(&mut self, pairs: impl IntoIterator<Item = (String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.add(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Write the hint files and the active log, and move the segments into the store
+    pub fn finish(mut self) -> Result<BulkLoadReport> {
+        self.sync_segment()?;
+        let segments = self.log_id;
+
+        // Hints would hold the keys of encrypted stores in the clear
+        if self.options.keyring.is_none() {
+            let mut hints: HashMap<u64, Vec<HintEntry>> = HashMap::new();
+            for (key, entry) in &self.index {
+                hints.entry(entry.log_id).or_default().push(HintEntry {
+                    key: key.clone(),
+                    log_pointer: entry.log_pointer,
+                    entry_len: entry.entry_len,
+                    timestamp: entry.timestamp,
+                });
+            }
+            for log_id in 1..=segments {
+                let segment_size = fs::metadata(segment_path(&self.staging_dir, log_id))?.len();
+                write_hint(
+                    &hint_path(&self.staging_dir, log_id),
+                    segment_size,
+                    hints.remove(&log_id).unwrap_or_default(),
+                    self.options.fsync != FsyncPolicy::Never,
+                )?;
+            }
+        }
+
+        // Opening the store appends to the latest segment, which must not have a hint
+        create_segment(&self.staging_dir, segments + 1)?;
+
+        // Ignoring error as an empty logs directory may not exist
+        let _ = fs::remove_dir(&self.logs_dir);
+        fs::rename(&self.staging_dir, &self.logs_dir).map_err(|e| {
+            error!(
+                "[BULK_LOAD] Error while renaming {:?} to {:?}",
+                self.staging_dir, self.logs_dir
+            );
+            HobbesError::IoError(e)
+        })?;
+        StoreMetadata::new().write(&self.db_dir)?;
+        debug!(
+            operation = "BULK_LOAD",
+            pairs = self.pairs,
+            keys = self.index.len(),
+            segments,
+            "Bulk load complete"
+        );
+
+        Ok(BulkLoadReport {
+            pairs: self.pairs,
+            keys: self.index.len() as u64,
+            segments,
+        })
+    }
+
+    fn roll_over(&mut self) -> Result<()> {
+        self.sync_segment()?;
+        self.log_id += 1;
+        (self.writer, self.offset) = create_segment(&self.staging_dir, self.log_id)?;
+        Ok(())
+    }
+
+    fn sync_segment(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if self.options.fsync != FsyncPolicy::Never {
+            self.writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, log_id: u64) -> PathBuf {
+    dir.join(format!("{log_id}{LOG_EXTENSION}"))
+}
+
+/// Create a segment and write its header, returning its writer and the offset of its first record
+fn create_segment(dir: &Path, log_id: u64) -> Result<(BufWriter<File>, u64)> {
+    let path = segment_path(dir, log_id);
+    let file = OpenOptions::new()
+        .create_new(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| {
+            error!("[BULK_LOAD] Error while creating a new segment - segment path -> {path:?}");
+            HobbesError::IoError(e)
+        })?;
+    let mut writer = BufWriter::new(file);
+    let header_len = write_header(&mut writer)?;
+    Ok((writer, header_len))
+}
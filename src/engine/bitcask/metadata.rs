@@ -0,0 +1,87 @@
+//! Store metadata file
+//!
+//! `bitcask-store/META` holds, as JSON, the format version of the store along with when and by
+//! which version of hobbes it was created and last upgraded. Stores written before the file was
+//! introduced have none, and are given one when next opened for writes.
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::{HobbesError, Result};
+
+use super::header::FORMAT_VERSION;
+
+const METADATA_PATH: &str = "META";
+const METADATA_TMP_PATH: &str = "META.tmp";
+
+/// Metadata of a bitcask store
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct StoreMetadata {
+    /// Newest format version of the segments of the store
+    pub(super) format_version: u16,
+    /// Time the store was created, or first opened by a version of hobbes writing metadata
+    pub(super) created_at: DateTime<Local>,
+    pub(super) created_by: String,
+    #[serde(default)]
+    pub(super) upgraded_at: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub(super) upgraded_by: Option<String>,
+}
+
+impl StoreMetadata {
+    pub(super) fn new() -> StoreMetadata {
+        StoreMetadata {
+            format_version: FORMAT_VERSION,
+            created_at: Local::now(),
+            created_by: hobbes_version(),
+            upgraded_at: None,
+            upgraded_by: None,
+        }
+    }
+
+    /// Read the metadata of the store at the directory, returning None if it has none. Stores
+    /// written by a newer version of hobbes are refused with
+    /// HobbesError::UnsupportedFormatError.
+    pub(super) fn read(db_dir: &Path) -> Result<Option<StoreMetadata>> {
+        let contents = match fs::read_to_string(db_dir.join(METADATA_PATH)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => Err(e)?,
+        };
+        let metadata: StoreMetadata = serde_json::from_str(&contents)?;
+        if metadata.format_version > FORMAT_VERSION {
+            Err(HobbesError::UnsupportedFormatError(format!(
+                "store format version {} written by {} is newer than version {FORMAT_VERSION} supported by {}",
+                metadata.format_version,
+                metadata.upgraded_by.as_ref().unwrap_or(&metadata.created_by),
+                hobbes_version()
+            )))?
+        }
+        Ok(Some(metadata))
+    }
+
+    /// Record that the store now holds segments of the current format version
+    pub(super) fn upgrade(&mut self) {
+        self.format_version = FORMAT_VERSION;
+        self.upgraded_at = Some(Local::now());
+        self.upgraded_by = Some(hobbes_version());
+    }
+
+    /// Replace the metadata file of the store, through a temporary file renamed over it
+    pub(super) fn write(&self, db_dir: &Path) -> Result<()> {
+        let tmp_path = db_dir.join(METADATA_TMP_PATH);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        file.sync_data()?;
+        fs::rename(&tmp_path, db_dir.join(METADATA_PATH))?;
+        Ok(())
+    }
+}
+
+fn hobbes_version() -> String {
+    format!("hobbes {}", env!("CARGO_PKG_VERSION"))
+}
@@ -0,0 +1,264 @@
+use chrono::NaiveTime;
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::config::parse_interval;
+use crate::{HobbesError, Result};
+
+use super::{BitcaskEngine, Compression, Keyring};
+
+const DEFAULT_SEGMENT_SIZE: u64 = 1_000_000;
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1_000_000;
+const DEFAULT_MERGE_DEAD_RATIO: f64 = 0.5;
+const DEFAULT_MAX_MERGE_BYTES: u64 = 64_000_000;
+
+/// When appended records are flushed from the OS page cache to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FsyncPolicy {
+    /// Leave flushing to the OS, losing recent writes if the machine crashes
+    #[default]
+    Never,
+    /// Flush after every write
+    Always,
+    /// Flush from a background thread at the interval
+    Interval(Duration),
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = HobbesError;
+
+    /// Parse `never`, `always`, or a non-zero interval such as `100ms` or `1s`
+    fn from_str(policy: &str) -> Result<FsyncPolicy> {
+        match policy {
+            "never" => Ok(FsyncPolicy::Never),
+            "always" => Ok(FsyncPolicy::Always),
+            _ => {
+                let interval = parse_interval(policy)?;
+                if interval.is_zero() {
+                    Err(HobbesError::ConfigError(format!(
+                        "invalid fsync interval {policy}, expected a non-zero interval"
+                    )))?
+                }
+                Ok(FsyncPolicy::Interval(interval))
+            }
+        }
+    }
+}
+
+impl fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsyncPolicy::Never => write!(f, "never"),
+            FsyncPolicy::Always => write!(f, "always"),
+            FsyncPolicy::Interval(interval) => write!(f, "{}ms", interval.as_millis()),
+        }
+    }
+}
+
+/// Daily time range, in local time, within which merges may start. Windows ending before they
+/// start span midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl CompactionWindow {
+    pub fn new(start: NaiveTime, end: NaiveTime) -> CompactionWindow {
+        CompactionWindow { start, end }
+    }
+
+    /// Whether the time falls within the window, including its start but not its end
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl FromStr for CompactionWindow {
+    type Err = HobbesError;
+
+    /// Parse a range of times such as `01:00-05:00`
+    fn from_str(window: &str) -> Result<CompactionWindow> {
+        let invalid_window = || {
+            HobbesError::CliError(format!(
+                "invalid compaction window {window}, expected a range of times such as 01:00-05:00"
+            ))
+        };
+
+        let (start, end) = window.split_once('-').ok_or_else(invalid_window)?;
+        let parse_time = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| invalid_window())
+        };
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end {
+            return Err(invalid_window());
+        }
+        Ok(CompactionWindow { start, end })
+    }
+}
+
+impl fmt::Display for CompactionWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// Options used to open a BitcaskEngine
+///
+/// ```
+/// use tempfile::TempDir;
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+///
+/// use hobbes::engine::bitcask::{BitcaskOptions, FsyncPolicy};
+/// use hobbes::engine::Engine;
+///
+/// let kv_store = BitcaskOptions::new()
+///     .segment_size(4 * 1024 * 1024)
+///     .fsync(FsyncPolicy::Always)
+///     .open(temp_dir.path())
+///     .expect("unable to open the store");
+/// kv_store.set("Foo".to_owned(), "Bar".to_owned()).expect("unable to set key 'Foo' to value 'Bar'");
+/// ```
+#[derive(Debug, Clone)]
+pub struct BitcaskOptions {
+    pub(crate) segment_size: u64,
+    pub(crate) compaction_threshold: u64,
+    pub(crate) merge_dead_ratio: f64,
+    pub(crate) max_merge_bytes: u64,
+    pub(crate) merge_interval: Duration,
+    pub(crate) compaction_window: Option<CompactionWindow>,
+    pub(crate) compaction_rate_limit: Option<u64>,
+    pub(crate) fsync: FsyncPolicy,
+    pub(crate) compression: Option<Compression>,
+    pub(crate) keyring: Option<Keyring>,
+    pub(crate) read_only: bool,
+}
+
+impl Default for BitcaskOptions {
+    fn default() -> BitcaskOptions {
+        BitcaskOptions {
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            merge_dead_ratio: DEFAULT_MERGE_DEAD_RATIO,
+            max_merge_bytes: DEFAULT_MAX_MERGE_BYTES,
+            merge_interval: Duration::ZERO,
+            compaction_window: None,
+            compaction_rate_limit: None,
+            fsync: FsyncPolicy::default(),
+            compression: None,
+            keyring: None,
+            read_only: false,
+        }
+    }
+}
+
+impl BitcaskOptions {
+    pub fn new() -> BitcaskOptions {
+        BitcaskOptions::default()
+    }
+
+    /// Size in bytes at which the active log is sealed and a new one started, also bounding the
+    /// logs written by merges and compaction
+    pub fn segment_size(mut self, segment_size: u64) -> BitcaskOptions {
+        self.segment_size = segment_size;
+        self
+    }
+
+    /// Dead bytes the sealed segments must hold for sealing a segment to trigger a merge
+    pub fn compaction_threshold(mut self, compaction_threshold: u64) -> BitcaskOptions {
+        self.compaction_threshold = compaction_threshold;
+        self
+    }
+
+    /// Fraction of a sealed segment which must be dead records for merges to rewrite it
+    pub fn merge_dead_ratio(mut self, merge_dead_ratio: f64) -> BitcaskOptions {
+        self.merge_dead_ratio = merge_dead_ratio;
+        self
+    }
+
+    /// Maximum size in bytes of the segments rewritten by a merge. The segment with the most
+    /// dead records is merged even if larger.
+    pub fn max_merge_bytes(mut self, max_merge_bytes: u64) -> BitcaskOptions {
+        self.max_merge_bytes = max_merge_bytes;
+        self
+    }
+
+    /// Minimum time after a merge before the next one, leaving segments for a later merge if
+    /// the last one was too recent
+    pub fn merge_interval(mut self, merge_interval: Duration) -> BitcaskOptions {
+        self.merge_interval = merge_interval;
+        self
+    }
+
+    /// Daily window outside of which sealing a segment does not start a merge. Merges skipped
+    /// outside the window start on the first seal within it, while compacting on demand ignores
+    /// the window.
+    pub fn compaction_window(mut self, compaction_window: CompactionWindow) -> BitcaskOptions {
+        self.compaction_window = Some(compaction_window);
+        self
+    }
+
+    /// Maximum rate in bytes per second at which merges and compaction write segments
+    pub fn compaction_rate_limit(mut self, bytes_per_sec: u64) -> BitcaskOptions {
+        self.compaction_rate_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// When appended records are flushed to disk
+    pub fn fsync(mut self, fsync: FsyncPolicy) -> BitcaskOptions {
+        self.fsync = fsync;
+        self
+    }
+
+    /// Compress records written from now on, including those rewritten by compaction
+    pub fn compression(mut self, compression: Compression) -> BitcaskOptions {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Encrypt records with the active key of the keyring. Records left unencrypted or sealed
+    /// with a retired key are rewritten with the active key by compacting the store when it is
+    /// opened.
+    pub fn keyring(mut self, keyring: Keyring) -> BitcaskOptions {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Open an existing store without modifying it, as BitcaskEngine::open_read_only does
+    pub fn read_only(mut self, read_only: bool) -> BitcaskOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Open an instance of BitcaskEngine at the specified directory
+    pub fn open(&self, logs_dir_arg: &Path) -> Result<BitcaskEngine> {
+        if self.segment_size == 0 || self.compaction_threshold == 0 {
+            Err(HobbesError::CliError(String::from(
+                "segment size and compaction threshold must be greater than zero",
+            )))?
+        }
+        if !(self.merge_dead_ratio > 0.0 && self.merge_dead_ratio <= 1.0) {
+            Err(HobbesError::CliError(String::from(
+                "merge dead ratio must be greater than zero and at most one",
+            )))?
+        }
+        if self.compaction_rate_limit == Some(0) {
+            Err(HobbesError::CliError(String::from(
+                "compaction rate limit must be greater than zero",
+            )))?
+        }
+        BitcaskEngine::open_with_options(logs_dir_arg, self.clone())
+    }
+}
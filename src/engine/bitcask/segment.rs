@@ -0,0 +1,96 @@
+//! Sequential decoding of the records of a segment
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use std::fs::File;
+use std::io::{BufReader, Seek};
+use std::path::Path;
+
+use crate::Result;
+
+use super::header::{read_header, SegmentInfo};
+use super::{read_entry, Keyring};
+
+/// A record decoded from a segment
+#[derive(Debug, Clone, Serialize)]
+pub struct SegmentRecord {
+    /// Position of the record in the segment, in bytes
+    pub offset: u64,
+    /// Size of the record as stored, in bytes
+    pub size: u64,
+    pub key: String,
+    /// Value set by the record, or None if it removed the key
+    pub value: Option<String>,
+    pub timestamp: DateTime<Local>,
+}
+
+/// SegmentReader decodes the records of a segment in order, decrypting and decompressing them
+pub struct SegmentReader {
+    reader: BufReader<File>,
+    keyring: Option<Keyring>,
+    version: u16,
+    info: Option<SegmentInfo>,
+    offset: u64,
+    size: u64,
+}
+
+impl SegmentReader {
+    /// Open a segment, passing the keyring if its records may be encrypted
+    pub fn open(path: &Path, keyring: Option<Keyring>) -> Result<SegmentReader> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let header = read_header(&mut reader)?;
+        Ok(SegmentReader {
+            offset: reader.stream_position()?,
+            reader,
+            keyring,
+            version: header.version,
+            info: header.info,
+            size,
+        })
+    }
+
+    /// Decode the next record, returning None at the end of the segment. After an error, the
+    /// offset is left at the record which could not be decoded, and no further records should
+    /// be read.
+    pub fn next_record(&mut self) -> Result<Option<SegmentRecord>> {
+        if self.offset >= self.size {
+            return Ok(None);
+        }
+
+        let (entry, _) = read_entry(&mut self.reader, self.keyring.as_ref(), self.version)?;
+        let next_offset = self.reader.stream_position()?;
+        let record = SegmentRecord {
+            offset: self.offset,
+            size: next_offset - self.offset,
+            value: (!entry.is_delete()).then_some(entry.val),
+            key: entry.key,
+            timestamp: entry.timestamp,
+        };
+        self.offset = next_offset;
+        Ok(Some(record))
+    }
+
+    /// Offset of the next record to be decoded
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Size of the segment in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Format version of the records of the segment
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// When and by which version of hobbes the segment was written, None for segments of
+    /// format versions before 3
+    pub fn info(&self) -> Option<&SegmentInfo> {
+        self.info.as_ref()
+    }
+}
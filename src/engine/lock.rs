@@ -0,0 +1,67 @@
+//! Advisory locks on data directories
+//!
+//! Locks are taken with `flock`, so the OS releases them when the holding process exits, even if
+//! it crashes. The lock file records the id of the process holding it.
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+
+use crate::{HobbesError, Result};
+
+/// DirLock holds a lock on a directory until dropped
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Take an exclusive lock on the lock file, creating it if missing
+    pub(crate) fn exclusive(lock_path: &Path) -> Result<DirLock> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(lock_path)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => Err(locked_error(lock_path, &mut file))?,
+            Err(TryLockError::Error(e)) => Err(e)?,
+        }
+
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(DirLock { _file: file })
+    }
+
+    /// Take a shared lock on the lock file. Stores without a lock file are left unlocked, as
+    /// read-only openers never create files and may be reading from read-only media.
+    pub(crate) fn shared(lock_path: &Path) -> Result<Option<DirLock>> {
+        let mut file = match File::open(lock_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => Err(e)?,
+        };
+
+        match file.try_lock_shared() {
+            Ok(()) => Ok(Some(DirLock { _file: file })),
+            Err(TryLockError::WouldBlock) => Err(locked_error(lock_path, &mut file)),
+            Err(TryLockError::Error(e)) => Err(e)?,
+        }
+    }
+}
+
+fn locked_error(lock_path: &Path, file: &mut File) -> HobbesError {
+    let mut holder = String::new();
+    let _ = file.read_to_string(&mut holder);
+    let dir = lock_path.parent().unwrap_or(Path::new(""));
+    match holder.trim().parse::<u32>() {
+        Ok(pid) => HobbesError::DirectoryLockedError(format!("{dir:?} is in use by process {pid}")),
+        Err(_) => {
+            HobbesError::DirectoryLockedError(format!("{dir:?} is in use by another process"))
+        }
+    }
+}
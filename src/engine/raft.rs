@@ -0,0 +1,1147 @@
+use rand::Rng;
+use rmp_serde::{decode, encode};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use storage::{RaftCommand, RaftEntry, RaftStorage};
+
+use crate::client::Connector;
+use crate::protocol::{encode_command, write_frame};
+
+use super::{Engine, EngineType, HobbesError, Result};
+
+mod storage;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+const ELECTION_TIMEOUT_MIN_MS: u64 = 500;
+const ELECTION_TIMEOUT_MAX_MS: u64 = 1000;
+const TICK_INTERVAL: Duration = Duration::from_millis(20);
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_ENTRIES_PER_APPEND: usize = 128;
+// Snapshots are sent in chunks of this many pairs, each within the RPC timeout
+const SNAPSHOT_CHUNK_PAIRS: usize = 1024;
+// Time allowed per pair for a follower to install a snapshot before acknowledging its last chunk
+const SNAPSHOT_INSTALL_TIME_PER_PAIR: Duration = Duration::from_micros(200);
+// The log is compacted once it holds this many applied entries
+const SNAPSHOT_THRESHOLD: u64 = 1024;
+// Applied entries retained after compaction, so that slightly lagging followers and waiting
+// proposers do not require a snapshot
+const SNAPSHOT_RETAINED_ENTRIES: u64 = 128;
+
+const MUTEX_ERROR: &str = "Failed to lock Mutex";
+
+/// Membership of a Raft cluster
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    /// Identifier of this node, which must be present in peers
+    pub node_id: u64,
+    /// Client addresses of every node in the cluster, keyed by node id
+    pub peers: HashMap<u64, String>,
+}
+
+/// Cluster state reported by the INFO command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterInfo {
+    pub node_id: u64,
+    /// One of "leader", "candidate" or "follower"
+    pub state: String,
+    pub term: u64,
+    /// Address of the current leader, if known
+    pub leader: Option<String>,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub last_log_index: u64,
+    pub snapshot_index: u64,
+    pub nodes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum RaftRequest {
+    RequestVote {
+        term: u64,
+        candidate_id: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    AppendEntries {
+        term: u64,
+        leader_id: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<RaftEntry>,
+        leader_commit: u64,
+    },
+    InstallSnapshot {
+        term: u64,
+        leader_id: u64,
+        last_included_index: u64,
+        last_included_term: u64,
+        // Position of the chunk's first pair within the snapshot
+        offset: u64,
+        data: Vec<(String, String)>,
+        // Set on the last chunk, after which the follower installs the snapshot
+        done: bool,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum RaftResponse {
+    RequestVote {
+        term: u64,
+        vote_granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        success: bool,
+        // On success, the index of the last entry matching the leader's log. On failure, an
+        // index up to which the follower's log is known to match, used to backtrack quickly.
+        match_index: u64,
+    },
+    InstallSnapshot {
+        term: u64,
+        // Whether the follower's state includes the snapshot, either installed now or earlier
+        installed: bool,
+        // Offset of the next chunk expected by the follower, when not installed
+        next_offset: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RaftRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// RaftNode replicates writes to the storage engine across a cluster using the Raft consensus
+/// algorithm. The storage engine acts as the state machine, and snapshots sent to lagging
+/// followers are taken directly from it.
+pub(super) struct RaftNode {
+    id: u64,
+    // Addresses of the other nodes in the cluster
+    peers: HashMap<u64, String>,
+    connector: Connector,
+    store: EngineType,
+    state: Mutex<RaftState>,
+    // Signalled whenever the log, commit index or role changes
+    changed: Condvar,
+}
+
+struct RaftState {
+    storage: RaftStorage,
+    role: RaftRole,
+    leader_id: Option<u64>,
+    commit_index: u64,
+    last_applied: u64,
+    election_deadline: Instant,
+    votes: HashSet<u64>,
+    next_index: HashMap<u64, u64>,
+    match_index: HashMap<u64, u64>,
+    // Chunks of the snapshot being received from the leader
+    incoming_snapshot: Option<Snapshot>,
+    // Set while a received snapshot is written to the storage engine outside the lock, pausing
+    // the applier and elections
+    installing_snapshot: bool,
+    // Set while the applier writes an entry to the storage engine outside the lock, delaying
+    // the installation of snapshots
+    applying: bool,
+}
+
+/// Snapshot of the leader's storage engine taken at an applied entry, received in key order
+struct Snapshot {
+    index: u64,
+    term: u64,
+    data: Vec<(String, String)>,
+}
+
+// Snapshot being sent to a lagging peer, read from the storage engine a chunk at a time
+struct OutgoingSnapshot {
+    index: u64,
+    term: u64,
+    // Pairs acknowledged by the peer, which is the offset of the next chunk
+    offset: usize,
+    // Last key of the acknowledged chunks, after which the next chunk is read
+    after: Option<String>,
+    // Chunk awaiting acknowledgement, kept so that a resent chunk is unchanged
+    chunk: Option<Vec<(String, String)>>,
+}
+
+impl RaftNode {
+    /// Start a node using the Raft log at the directory, spawning its background threads
+    pub(super) fn start(
+        config: &ClusterConfig,
+        store: EngineType,
+        raft_dir: &Path,
+        connector: Connector,
+    ) -> Result<Arc<RaftNode>> {
+        if !config.peers.contains_key(&config.node_id) {
+            Err(HobbesError::CliError(format!(
+                "node id {} is not a member of the cluster",
+                config.node_id
+            )))?
+        }
+
+        let storage = RaftStorage::open(raft_dir)?;
+        // The storage engine already reflects every entry up to the snapshot index. Entries
+        // after it are reapplied once committed, which is safe as sets and removes are
+        // idempotent when replayed in order.
+        let snapshot_index = storage.snapshot_index();
+
+        let node = Arc::new(RaftNode {
+            id: config.node_id,
+            peers: config
+                .peers
+                .iter()
+                .filter(|(id, _)| **id != config.node_id)
+                .map(|(id, addr)| (*id, addr.clone()))
+                .collect(),
+            connector,
+            store,
+            state: Mutex::new(RaftState {
+                storage,
+                role: RaftRole::Follower,
+                leader_id: None,
+                commit_index: snapshot_index,
+                last_applied: snapshot_index,
+                election_deadline: election_deadline(),
+                votes: HashSet::new(),
+                next_index: HashMap::new(),
+                match_index: HashMap::new(),
+                incoming_snapshot: None,
+                installing_snapshot: false,
+                applying: false,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let node_clone = node.clone();
+        thread::spawn(move || node_clone.run_ticker());
+        let node_clone = node.clone();
+        thread::spawn(move || node_clone.run_applier());
+        for peer_id in node.peers.keys() {
+            let node_clone = node.clone();
+            let peer_id = *peer_id;
+            thread::spawn(move || node_clone.run_replicator(peer_id));
+        }
+
+        info!(
+            node_id = node.id,
+            nodes = node.peers.len() + 1,
+            "Raft node started"
+        );
+        Ok(node)
+    }
+
+    /// Store a key-value pair through the cluster
+    pub(super) fn set(&self, key: String, val: String) -> Result<()> {
+        self.propose(RaftCommand::Set { key, val })
+    }
+
+    /// Store many key-value pairs through the cluster as a single entry
+    pub(super) fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.propose(RaftCommand::SetMany { pairs })
+    }
+
+    /// Remove a key through the cluster
+    pub(super) fn remove(&self, key: String) -> Result<()> {
+        self.check_leader()?;
+        if self.store.get(key.clone())?.is_none() {
+            return Err(HobbesError::KeyNotFoundError);
+        }
+        self.propose(RaftCommand::Remove { key })
+    }
+
+    // Replicates a command through the cluster, returning once it has been applied locally
+    fn propose(&self, command: RaftCommand) -> Result<()> {
+        let mut state = self.lock();
+        if state.role != RaftRole::Leader {
+            return Err(self.not_leader(&state));
+        }
+
+        let term = state.storage.current_term();
+        let index = state.storage.last_index() + 1;
+        state.storage.append(vec![RaftEntry {
+            index,
+            term,
+            command,
+        }])?;
+        self.advance_commit_index(&mut state);
+        self.changed.notify_all();
+
+        let deadline = Instant::now() + PROPOSAL_TIMEOUT;
+        loop {
+            if state.last_applied >= index {
+                // The entry may have been replaced by a new leader before being committed
+                return match state.storage.term_at(index) {
+                    Some(entry_term) if entry_term != term => Err(self.not_leader(&state)),
+                    None if state.storage.current_term() != term => Err(self.not_leader(&state)),
+                    _ => Ok(()),
+                };
+            }
+            if state.storage.current_term() != term {
+                return Err(self.not_leader(&state));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(HobbesError::ConsensusError(format!(
+                    "timed out waiting for entry {index} to be committed"
+                )));
+            }
+            state = self
+                .changed
+                .wait_timeout(state, deadline - now)
+                .expect(MUTEX_ERROR)
+                .0;
+        }
+    }
+
+    /// Return an error redirecting the client unless this node is the leader
+    pub(super) fn check_leader(&self) -> Result<()> {
+        let state = self.lock();
+        match state.role {
+            RaftRole::Leader => Ok(()),
+            _ => Err(self.not_leader(&state)),
+        }
+    }
+
+    pub(super) fn info(&self) -> ClusterInfo {
+        let state = self.lock();
+        ClusterInfo {
+            node_id: self.id,
+            state: String::from(match state.role {
+                RaftRole::Leader => "leader",
+                RaftRole::Candidate => "candidate",
+                RaftRole::Follower => "follower",
+            }),
+            term: state.storage.current_term(),
+            leader: self.leader_addr(&state),
+            commit_index: state.commit_index,
+            last_applied: state.last_applied,
+            last_log_index: state.storage.last_index(),
+            snapshot_index: state.storage.snapshot_index(),
+            nodes: self.peers.len() as u64 + 1,
+        }
+    }
+
+    /// Handle a RAFT request from a peer, writing the response back to the same stream
+    pub(super) fn handle_rpc<S: Read + Write>(&self, reader: &mut BufReader<S>) -> Result<()> {
+        let req: RaftRequest = decode::from_read(&mut *reader)?;
+        let resp = match req {
+            RaftRequest::RequestVote {
+                term,
+                candidate_id,
+                last_log_index,
+                last_log_term,
+            } => self.handle_request_vote(term, candidate_id, last_log_index, last_log_term)?,
+            RaftRequest::AppendEntries {
+                term,
+                leader_id,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => self.handle_append_entries(
+                term,
+                leader_id,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            )?,
+            RaftRequest::InstallSnapshot {
+                term,
+                leader_id,
+                last_included_index,
+                last_included_term,
+                offset,
+                data,
+                done,
+            } => self.handle_install_snapshot(
+                term,
+                leader_id,
+                Snapshot {
+                    index: last_included_index,
+                    term: last_included_term,
+                    data,
+                },
+                offset,
+                done,
+            )?,
+        };
+
+        let mut writer = BufWriter::new(reader.get_mut());
+        encode::write(&mut writer, &resp)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn handle_request_vote(
+        &self,
+        term: u64,
+        candidate_id: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    ) -> Result<RaftResponse> {
+        let mut state = self.lock();
+        if term > state.storage.current_term() {
+            self.step_down(&mut state, term)?;
+        }
+
+        let current_term = state.storage.current_term();
+        let log_up_to_date = last_log_term > state.storage.last_term()
+            || (last_log_term == state.storage.last_term()
+                && last_log_index >= state.storage.last_index());
+        let can_vote = state
+            .storage
+            .voted_for()
+            .is_none_or(|voted_for| voted_for == candidate_id);
+
+        let vote_granted = term == current_term && can_vote && log_up_to_date;
+        if vote_granted {
+            state
+                .storage
+                .set_term_and_vote(current_term, Some(candidate_id))?;
+            state.election_deadline = election_deadline();
+        }
+        debug!(
+            term = current_term,
+            candidate_id = candidate_id,
+            vote_granted = vote_granted,
+            "Handled vote request"
+        );
+
+        Ok(RaftResponse::RequestVote {
+            term: current_term,
+            vote_granted,
+        })
+    }
+
+    fn handle_append_entries(
+        &self,
+        term: u64,
+        leader_id: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<RaftEntry>,
+        leader_commit: u64,
+    ) -> Result<RaftResponse> {
+        let mut state = self.lock();
+        if term < state.storage.current_term() {
+            return Ok(RaftResponse::AppendEntries {
+                term: state.storage.current_term(),
+                success: false,
+                match_index: 0,
+            });
+        }
+        self.follow_leader(&mut state, term, leader_id)?;
+
+        // Committed entries always match the leader's log, making the commit index a safe point
+        // to backtrack to
+        if prev_log_index > state.storage.last_index() {
+            return Ok(RaftResponse::AppendEntries {
+                term,
+                success: false,
+                match_index: state.commit_index.min(state.storage.last_index()),
+            });
+        }
+        if prev_log_index >= state.storage.snapshot_index()
+            && state.storage.term_at(prev_log_index) != Some(prev_log_term)
+        {
+            return Ok(RaftResponse::AppendEntries {
+                term,
+                success: false,
+                match_index: state.commit_index.min(prev_log_index.saturating_sub(1)),
+            });
+        }
+
+        let match_index = prev_log_index + entries.len() as u64;
+        let mut new_entries = Vec::new();
+        for entry in entries {
+            // Entries covered by the snapshot are already applied
+            if entry.index <= state.storage.snapshot_index() {
+                continue;
+            }
+            if !new_entries.is_empty() {
+                new_entries.push(entry);
+                continue;
+            }
+            match state.storage.term_at(entry.index) {
+                Some(existing_term) if existing_term == entry.term => {}
+                Some(_) => {
+                    warn!(
+                        index = entry.index,
+                        "Truncating conflicting entries from the raft log"
+                    );
+                    state.storage.truncate_from(entry.index)?;
+                    new_entries.push(entry);
+                }
+                None => new_entries.push(entry),
+            }
+        }
+        if !new_entries.is_empty() {
+            state.storage.append(new_entries)?;
+        }
+
+        let commit_index = leader_commit.min(match_index);
+        if commit_index > state.commit_index {
+            state.commit_index = commit_index;
+            self.changed.notify_all();
+        }
+
+        Ok(RaftResponse::AppendEntries {
+            term,
+            success: true,
+            match_index,
+        })
+    }
+
+    fn handle_install_snapshot(
+        &self,
+        term: u64,
+        leader_id: u64,
+        chunk: Snapshot,
+        offset: u64,
+        done: bool,
+    ) -> Result<RaftResponse> {
+        let mut state = self.lock();
+        if term < state.storage.current_term() {
+            return Ok(RaftResponse::InstallSnapshot {
+                term: state.storage.current_term(),
+                installed: false,
+                next_offset: 0,
+            });
+        }
+        self.follow_leader(&mut state, term, leader_id)?;
+
+        // A chunk resent while the snapshot is installed is answered once installation ends, and
+        // the snapshot is not installed while an entry is applied
+        while state.installing_snapshot || state.applying {
+            state = self.changed.wait(state).expect(MUTEX_ERROR);
+        }
+        if chunk.index <= state.last_applied {
+            return Ok(RaftResponse::InstallSnapshot {
+                term,
+                installed: true,
+                next_offset: 0,
+            });
+        }
+
+        // Chunks are accepted in order, restarting whenever the leader starts a new snapshot
+        let mut snapshot = match state.incoming_snapshot.take() {
+            Some(snapshot)
+                if snapshot.index == chunk.index
+                    && snapshot.term == chunk.term
+                    && snapshot.data.len() as u64 == offset =>
+            {
+                snapshot
+            }
+            _ if offset == 0 => Snapshot {
+                index: chunk.index,
+                term: chunk.term,
+                data: Vec::new(),
+            },
+            snapshot => {
+                let next_offset = snapshot
+                    .as_ref()
+                    .filter(|snapshot| snapshot.index == chunk.index && snapshot.term == chunk.term)
+                    .map_or(0, |snapshot| snapshot.data.len() as u64);
+                state.incoming_snapshot = snapshot;
+                return Ok(RaftResponse::InstallSnapshot {
+                    term,
+                    installed: false,
+                    next_offset,
+                });
+            }
+        };
+        snapshot.data.extend(chunk.data);
+        if !done {
+            let next_offset = snapshot.data.len() as u64;
+            state.incoming_snapshot = Some(snapshot);
+            return Ok(RaftResponse::InstallSnapshot {
+                term,
+                installed: false,
+                next_offset,
+            });
+        }
+
+        info!(
+            index = snapshot.index,
+            keys = snapshot.data.len(),
+            "Installing snapshot from leader"
+        );
+        // Written to the storage engine without holding the lock, so that heartbeats and votes
+        // are still answered
+        state.installing_snapshot = true;
+        drop(state);
+        let res = self.install_snapshot(snapshot.data);
+
+        let mut state = self.lock();
+        state.installing_snapshot = false;
+        state.election_deadline = election_deadline();
+        self.changed.notify_all();
+        res?;
+
+        state
+            .storage
+            .reset_to_snapshot(snapshot.index, snapshot.term)?;
+        state.commit_index = snapshot.index;
+        state.last_applied = snapshot.index;
+        self.changed.notify_all();
+
+        Ok(RaftResponse::InstallSnapshot {
+            term,
+            installed: true,
+            next_offset: 0,
+        })
+    }
+
+    // Replaces the contents of the storage engine with the snapshot, which is sorted by key
+    fn install_snapshot(&self, data: Vec<(String, String)>) -> Result<()> {
+        // Local keys are read a page at a time and removed when absent from the snapshot
+        let mut after = None;
+        loop {
+            let page = self
+                .store
+                .scan_page(String::new(), after, SNAPSHOT_CHUNK_PAIRS)?;
+            let Some((last, _)) = page.last() else {
+                break;
+            };
+            after = Some(last.clone());
+            for (key, _) in page {
+                if data
+                    .binary_search_by(|(snapshot_key, _)| snapshot_key.cmp(&key))
+                    .is_err()
+                {
+                    remove_if_present(&self.store, key)?;
+                }
+            }
+        }
+        for (key, val) in data {
+            self.store.set(key, val)?;
+        }
+        // The log is discarded once the snapshot is installed, so the snapshot must be on disk
+        self.store.sync()
+    }
+
+    // Drives elections on followers and candidates whose election timeout has elapsed
+    fn run_ticker(self: Arc<Self>) {
+        loop {
+            thread::sleep(TICK_INTERVAL);
+
+            let mut state = self.lock();
+            if state.role == RaftRole::Leader
+                || state.installing_snapshot
+                || Instant::now() < state.election_deadline
+            {
+                continue;
+            }
+            if let Err(e) = self.start_election(&mut state) {
+                error!("Failed to start election -> {e}");
+            }
+        }
+    }
+
+    fn start_election(self: &Arc<Self>, state: &mut MutexGuard<RaftState>) -> Result<()> {
+        let term = state.storage.current_term() + 1;
+        state.storage.set_term_and_vote(term, Some(self.id))?;
+        state.role = RaftRole::Candidate;
+        state.leader_id = None;
+        state.election_deadline = election_deadline();
+        state.votes = HashSet::from([self.id]);
+        info!(node_id = self.id, term = term, "Starting election");
+
+        if self.is_quorum(state.votes.len()) {
+            self.become_leader(state)?;
+            return Ok(());
+        }
+
+        let req = RaftRequest::RequestVote {
+            term,
+            candidate_id: self.id,
+            last_log_index: state.storage.last_index(),
+            last_log_term: state.storage.last_term(),
+        };
+        let req = Arc::new(req);
+        for (peer_id, peer_addr) in &self.peers {
+            let req = req.clone();
+            let peer_id = *peer_id;
+            let peer_addr = peer_addr.clone();
+            let node = self.clone();
+            thread::spawn(
+                move || match send_rpc(&peer_addr, &node.connector, &req, RPC_TIMEOUT) {
+                    Ok(RaftResponse::RequestVote { term, vote_granted }) => {
+                        node.handle_vote(peer_id, term, vote_granted)
+                    }
+                    Ok(resp) => warn!("Unexpected response to vote request -> {:?}", resp),
+                    Err(e) => debug!(peer_id = peer_id, "Vote request failed -> {e}"),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn handle_vote(&self, peer_id: u64, term: u64, vote_granted: bool) {
+        let mut state = self.lock();
+        if term > state.storage.current_term() {
+            if let Err(e) = self.step_down(&mut state, term) {
+                error!("Failed to step down -> {e}");
+            }
+            return;
+        }
+        if state.role != RaftRole::Candidate
+            || term != state.storage.current_term()
+            || !vote_granted
+        {
+            return;
+        }
+
+        state.votes.insert(peer_id);
+        if self.is_quorum(state.votes.len()) {
+            if let Err(e) = self.become_leader(&mut state) {
+                error!("Failed to become leader -> {e}");
+            }
+        }
+    }
+
+    fn become_leader(&self, state: &mut MutexGuard<RaftState>) -> Result<()> {
+        info!(
+            node_id = self.id,
+            term = state.storage.current_term(),
+            "Elected leader"
+        );
+        state.role = RaftRole::Leader;
+        state.leader_id = Some(self.id);
+
+        let next_index = state.storage.last_index() + 1;
+        state.next_index = self.peers.keys().map(|id| (*id, next_index)).collect();
+        state.match_index = self.peers.keys().map(|id| (*id, 0)).collect();
+
+        // Entries from previous terms are only committed alongside an entry from this term
+        let term = state.storage.current_term();
+        state.storage.append(vec![RaftEntry {
+            index: next_index,
+            term,
+            command: RaftCommand::Noop,
+        }])?;
+        self.advance_commit_index(state);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    // Sends entries, snapshots and heartbeats to a single peer while this node is the leader
+    fn run_replicator(&self, peer_id: u64) {
+        let peer_addr = self.peers[&peer_id].clone();
+        let mut snapshot = None;
+        loop {
+            let (req, term) = {
+                let mut state = self.lock();
+                let pending = state.role == RaftRole::Leader
+                    && state.next_index.get(&peer_id).copied().unwrap_or(0)
+                        <= state.storage.last_index();
+                if !pending {
+                    state = self
+                        .changed
+                        .wait_timeout(state, HEARTBEAT_INTERVAL)
+                        .expect(MUTEX_ERROR)
+                        .0;
+                }
+                if state.role != RaftRole::Leader {
+                    snapshot = None;
+                    continue;
+                }
+
+                match self.build_request(&state, peer_id, &mut snapshot) {
+                    Ok(req) => (req, state.storage.current_term()),
+                    Err(e) => {
+                        error!(peer_id = peer_id, "Failed to build raft request -> {e}");
+                        continue;
+                    }
+                }
+            };
+            // Snapshot chunks are read from the storage engine once the lock is released
+            let req = match (req, snapshot.as_mut()) {
+                (Some(req), _) => req,
+                (None, Some(outgoing)) => match self.snapshot_request(term, outgoing) {
+                    Ok(req) => req,
+                    Err(e) => {
+                        error!(peer_id = peer_id, "Failed to read snapshot chunk -> {e}");
+                        thread::sleep(HEARTBEAT_INTERVAL);
+                        continue;
+                    }
+                },
+                (None, None) => continue,
+            };
+
+            // The last chunk of a snapshot is acknowledged once the whole snapshot is installed
+            let timeout = match &req {
+                RaftRequest::InstallSnapshot {
+                    done: true, offset, ..
+                } => RPC_TIMEOUT + SNAPSHOT_INSTALL_TIME_PER_PAIR * *offset as u32,
+                _ => RPC_TIMEOUT,
+            };
+            match send_rpc(&peer_addr, &self.connector, &req, timeout) {
+                Ok(resp) => {
+                    self.handle_replication_response(peer_id, term, &req, resp, &mut snapshot)
+                }
+                Err(e) => {
+                    debug!(peer_id = peer_id, "Raft request failed -> {e}");
+                    // Avoids spinning while a peer is unreachable
+                    thread::sleep(HEARTBEAT_INTERVAL);
+                }
+            }
+        }
+    }
+
+    // Returns None when the peer is to be sent the next chunk of the snapshot
+    fn build_request(
+        &self,
+        state: &RaftState,
+        peer_id: u64,
+        snapshot: &mut Option<OutgoingSnapshot>,
+    ) -> Result<Option<RaftRequest>> {
+        let term = state.storage.current_term();
+        let next_index = state.next_index[&peer_id].max(1);
+        let prev_log_index = next_index - 1;
+
+        if let Some(prev_log_term) = state.storage.term_at(prev_log_index) {
+            *snapshot = None;
+            return Ok(Some(RaftRequest::AppendEntries {
+                term,
+                leader_id: self.id,
+                prev_log_index,
+                prev_log_term,
+                entries: state
+                    .storage
+                    .entries_from(next_index, MAX_ENTRIES_PER_APPEND),
+                leader_commit: state.commit_index,
+            }));
+        }
+
+        // The entries required by the peer were compacted, so it is sent the contents of the
+        // storage engine from the last applied entry, read in key order a chunk at a time rather
+        // than held in memory. Chunks may already hold the writes of later entries, which the
+        // peer converges from by replaying the entries following the snapshot.
+        if snapshot.is_none() {
+            let index = state.last_applied;
+            let term = state
+                .storage
+                .term_at(index)
+                .ok_or(HobbesError::ConsensusError(format!(
+                    "missing term for applied entry {index}"
+                )))?;
+            info!(peer_id = peer_id, index = index, "Sending snapshot to peer");
+            *snapshot = Some(OutgoingSnapshot {
+                index,
+                term,
+                offset: 0,
+                after: None,
+                chunk: None,
+            });
+        }
+        Ok(None)
+    }
+
+    fn snapshot_request(&self, term: u64, outgoing: &mut OutgoingSnapshot) -> Result<RaftRequest> {
+        let data = match &outgoing.chunk {
+            Some(chunk) => chunk.clone(),
+            None => {
+                let chunk = self.store.scan_page(
+                    String::new(),
+                    outgoing.after.clone(),
+                    SNAPSHOT_CHUNK_PAIRS,
+                )?;
+                outgoing.chunk.insert(chunk).clone()
+            }
+        };
+        Ok(RaftRequest::InstallSnapshot {
+            term,
+            leader_id: self.id,
+            last_included_index: outgoing.index,
+            last_included_term: outgoing.term,
+            offset: outgoing.offset as u64,
+            // An empty chunk follows the last key of the storage engine
+            done: data.is_empty(),
+            data,
+        })
+    }
+
+    fn handle_replication_response(
+        &self,
+        peer_id: u64,
+        req_term: u64,
+        req: &RaftRequest,
+        resp: RaftResponse,
+        snapshot: &mut Option<OutgoingSnapshot>,
+    ) {
+        let mut state = self.lock();
+        let resp_term = match resp {
+            RaftResponse::AppendEntries { term, .. } => term,
+            RaftResponse::InstallSnapshot { term, .. } => term,
+            RaftResponse::RequestVote { term, .. } => term,
+        };
+        if resp_term > state.storage.current_term() {
+            if let Err(e) = self.step_down(&mut state, resp_term) {
+                error!("Failed to step down -> {e}");
+            }
+            return;
+        }
+        if state.role != RaftRole::Leader || req_term != state.storage.current_term() {
+            return;
+        }
+
+        match (req, resp) {
+            (
+                _,
+                RaftResponse::AppendEntries {
+                    success: true,
+                    match_index,
+                    ..
+                },
+            ) => {
+                if match_index > state.match_index[&peer_id] {
+                    state.match_index.insert(peer_id, match_index);
+                }
+                state.next_index.insert(peer_id, match_index + 1);
+                self.advance_commit_index(&mut state);
+            }
+            (
+                _,
+                RaftResponse::AppendEntries {
+                    success: false,
+                    match_index,
+                    ..
+                },
+            ) => {
+                let next_index = state.next_index[&peer_id];
+                state.next_index.insert(
+                    peer_id,
+                    (match_index + 1).min(next_index.saturating_sub(1)).max(1),
+                );
+            }
+            (
+                RaftRequest::InstallSnapshot {
+                    last_included_index,
+                    ..
+                },
+                RaftResponse::InstallSnapshot {
+                    installed: true, ..
+                },
+            ) => {
+                *snapshot = None;
+                state.match_index.insert(peer_id, *last_included_index);
+                state.next_index.insert(peer_id, last_included_index + 1);
+                self.advance_commit_index(&mut state);
+            }
+            (
+                _,
+                RaftResponse::InstallSnapshot {
+                    installed: false,
+                    next_offset,
+                    ..
+                },
+            ) => {
+                let Some(outgoing) = snapshot else {
+                    return;
+                };
+                let chunk_len = outgoing.chunk.as_ref().map_or(0, |chunk| chunk.len());
+                if next_offset as usize == outgoing.offset + chunk_len {
+                    if let Some((last, _)) = outgoing.chunk.take().and_then(|mut chunk| chunk.pop())
+                    {
+                        outgoing.after = Some(last);
+                    }
+                    outgoing.offset = next_offset as usize;
+                } else if next_offset as usize != outgoing.offset {
+                    // Chunks cannot be read again from an earlier offset, so a peer which lost
+                    // track of the snapshot is sent a new one
+                    *snapshot = None;
+                }
+            }
+            (_, resp) => warn!("Unexpected response to replication request -> {:?}", resp),
+        }
+    }
+
+    // Commits the highest entry from the current term stored on a majority of nodes
+    fn advance_commit_index(&self, state: &mut MutexGuard<RaftState>) {
+        let current_term = state.storage.current_term();
+        let mut index = state.storage.last_index();
+        while index > state.commit_index {
+            if state.storage.term_at(index) == Some(current_term) {
+                let replicas = 1 + state
+                    .match_index
+                    .values()
+                    .filter(|match_index| **match_index >= index)
+                    .count();
+                if self.is_quorum(replicas) {
+                    state.commit_index = index;
+                    self.changed.notify_all();
+                    return;
+                }
+            }
+            index -= 1;
+        }
+    }
+
+    // Applies committed entries to the storage engine and compacts the log
+    fn run_applier(&self) {
+        let mut state = self.lock();
+        loop {
+            while state.last_applied >= state.commit_index || state.installing_snapshot {
+                state = self.changed.wait(state).expect(MUTEX_ERROR);
+            }
+
+            let index = state.last_applied + 1;
+            let entry = match state.storage.entry(index) {
+                Some(entry) => entry.clone(),
+                None => {
+                    error!(index = index, "Committed entry missing from the raft log");
+                    state = self
+                        .changed
+                        .wait_timeout(state, HEARTBEAT_INTERVAL)
+                        .expect(MUTEX_ERROR)
+                        .0;
+                    continue;
+                }
+            };
+
+            // Applied without holding the lock, so that a slow write, such as one compacting the
+            // storage engine inline, does not hold up heartbeats and RPCs
+            state.applying = true;
+            drop(state);
+            let res = match entry.command {
+                RaftCommand::Noop => Ok(()),
+                RaftCommand::Set { key, val } => self.store.set(key, val),
+                RaftCommand::SetMany { pairs } => self.store.set_many(pairs).map(|_| ()),
+                RaftCommand::Remove { key } => remove_if_present(&self.store, key),
+            };
+            state = self.lock();
+            state.applying = false;
+            self.changed.notify_all();
+            if let Err(e) = res {
+                // Retried until it succeeds, as skipping the entry would diverge from the peers
+                error!(index = index, "Failed to apply raft entry -> {e}");
+                state = self
+                    .changed
+                    .wait_timeout(state, HEARTBEAT_INTERVAL)
+                    .expect(MUTEX_ERROR)
+                    .0;
+                continue;
+            }
+            state.last_applied = index;
+            self.changed.notify_all();
+
+            if state.last_applied - state.storage.snapshot_index()
+                >= SNAPSHOT_THRESHOLD + SNAPSHOT_RETAINED_ENTRIES
+            {
+                let compact_index = state.last_applied - SNAPSHOT_RETAINED_ENTRIES;
+                // Entries are only discarded once their writes are on disk, whatever the fsync
+                // policy of the storage engine
+                drop(state);
+                let res = self.store.sync();
+                state = self.lock();
+                match res {
+                    // A snapshot installed meanwhile may have discarded the entries already
+                    Ok(()) if compact_index > state.storage.snapshot_index() => {
+                        debug!(index = compact_index, "Compacting the raft log");
+                        if let Err(e) = state.storage.compact(compact_index) {
+                            error!("Failed to compact the raft log -> {e}");
+                        }
+                    }
+                    Ok(()) => {}
+                    Err(e) => error!("Failed to sync the storage engine -> {e}"),
+                }
+            }
+        }
+    }
+
+    fn follow_leader(
+        &self,
+        state: &mut MutexGuard<RaftState>,
+        term: u64,
+        leader_id: u64,
+    ) -> Result<()> {
+        if term > state.storage.current_term() {
+            self.step_down(state, term)?;
+        }
+        if state.role != RaftRole::Follower {
+            state.role = RaftRole::Follower;
+            self.changed.notify_all();
+        }
+        state.leader_id = Some(leader_id);
+        state.election_deadline = election_deadline();
+        Ok(())
+    }
+
+    fn step_down(&self, state: &mut MutexGuard<RaftState>, term: u64) -> Result<()> {
+        if state.role == RaftRole::Leader {
+            info!(node_id = self.id, term = term, "Stepping down as leader");
+        }
+        state.storage.set_term_and_vote(term, None)?;
+        state.role = RaftRole::Follower;
+        state.leader_id = None;
+        state.election_deadline = election_deadline();
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    fn is_quorum(&self, count: usize) -> bool {
+        count * 2 > self.peers.len() + 1
+    }
+
+    fn leader_addr(&self, state: &RaftState) -> Option<String> {
+        match state.leader_id {
+            Some(id) if id == self.id => None,
+            Some(id) => self.peers.get(&id).cloned(),
+            None => None,
+        }
+    }
+
+    fn not_leader(&self, state: &RaftState) -> HobbesError {
+        HobbesError::NotLeaderError(self.leader_addr(state))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RaftState> {
+        self.state.lock().expect(MUTEX_ERROR)
+    }
+}
+
+fn election_deadline() -> Instant {
+    Instant::now()
+        + Duration::from_millis(
+            rand::thread_rng().gen_range(ELECTION_TIMEOUT_MIN_MS..ELECTION_TIMEOUT_MAX_MS),
+        )
+}
+
+fn remove_if_present(store: &EngineType, key: String) -> Result<()> {
+    match store.remove(key) {
+        Err(HobbesError::KeyNotFoundError) => Ok(()),
+        res => res,
+    }
+}
+
+fn send_rpc(
+    addr: &str,
+    connector: &Connector,
+    req: &RaftRequest,
+    timeout: Duration,
+) -> Result<RaftResponse> {
+    let mut tcp_stream = connector.connect(addr, Some(RPC_TIMEOUT))?;
+    tcp_stream.set_read_timeout(Some(timeout))?;
+    tcp_stream.set_write_timeout(Some(timeout))?;
+
+    let mut writer = BufWriter::new(&mut tcp_stream);
+    write_frame(&mut writer, &encode_command(&["RAFT"]))?;
+    encode::write(&mut writer, req)?;
+    writer.flush()?;
+    drop(writer);
+
+    Ok(decode::from_read(BufReader::new(&mut tcp_stream))?)
+}
@@ -0,0 +1,234 @@
+use rmp_serde::decode;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{HobbesError, Result};
+
+const HARD_STATE_FILENAME: &str = "state";
+const LOG_FILENAME: &str = "log";
+const TMP_EXTENSION: &str = "tmp";
+
+/// A command replicated through the Raft log and applied to the storage engine
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum RaftCommand {
+    // Appended by a new leader to commit entries from previous terms
+    Noop,
+    Set { key: String, val: String },
+    Remove { key: String },
+    // Appended after the other commands, keeping the encoding of existing logs
+    SetMany { pairs: Vec<(String, String)> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RaftEntry {
+    pub(crate) index: u64,
+    pub(crate) term: u64,
+    pub(crate) command: RaftCommand,
+}
+
+// State which must survive restarts, rewritten whenever it changes
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HardState {
+    current_term: u64,
+    voted_for: Option<u64>,
+    // Entries up to snapshot_index have been applied to the storage engine and discarded
+    snapshot_index: u64,
+    snapshot_term: u64,
+}
+
+/// RaftStorage persists the Raft term, vote and log entries following the latest snapshot
+#[derive(Debug)]
+pub(crate) struct RaftStorage {
+    dir: PathBuf,
+    hard_state: HardState,
+    entries: Vec<RaftEntry>,
+    log_writer: File,
+}
+
+impl RaftStorage {
+    /// Open the Raft storage at the specified directory, creating it if absent
+    pub(crate) fn open(dir: &Path) -> Result<RaftStorage> {
+        fs::create_dir_all(dir)?;
+
+        let hard_state_path = dir.join(HARD_STATE_FILENAME);
+        let hard_state = if hard_state_path.is_file() {
+            decode::from_read(BufReader::new(File::open(&hard_state_path)?))?
+        } else {
+            HardState::default()
+        };
+
+        let log_path = dir.join(LOG_FILENAME);
+        let mut entries = Vec::new();
+        if log_path.is_file() {
+            let mut log_reader = BufReader::new(File::open(&log_path)?);
+            // A partially written entry at the tail is discarded along with everything after it
+            while let Ok(entry) = decode::from_read::<_, RaftEntry>(&mut log_reader) {
+                if entry.index > hard_state.snapshot_index {
+                    entries.push(entry);
+                }
+            }
+        }
+
+        let log_writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .map_err(|e| {
+                error!(
+                    "[RAFT_INIT] Error while opening the raft log - log path -> {:?}",
+                    log_path
+                );
+                HobbesError::IoError(e)
+            })?;
+
+        let mut storage = RaftStorage {
+            dir: dir.to_path_buf(),
+            hard_state,
+            entries,
+            log_writer,
+        };
+        // Drops any torn tail so that new entries are appended after the last valid one
+        storage.rewrite_log()?;
+        Ok(storage)
+    }
+
+    pub(crate) fn current_term(&self) -> u64 {
+        self.hard_state.current_term
+    }
+
+    pub(crate) fn voted_for(&self) -> Option<u64> {
+        self.hard_state.voted_for
+    }
+
+    pub(crate) fn snapshot_index(&self) -> u64 {
+        self.hard_state.snapshot_index
+    }
+
+    pub(crate) fn set_term_and_vote(&mut self, term: u64, voted_for: Option<u64>) -> Result<()> {
+        self.hard_state.current_term = term;
+        self.hard_state.voted_for = voted_for;
+        self.persist_hard_state()
+    }
+
+    pub(crate) fn last_index(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.hard_state.snapshot_index, |entry| entry.index)
+    }
+
+    pub(crate) fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.hard_state.snapshot_term, |entry| entry.term)
+    }
+
+    /// Return the term of the entry at the index, or None if it is absent or was compacted
+    pub(crate) fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.hard_state.snapshot_index {
+            return Some(self.hard_state.snapshot_term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    pub(crate) fn entry(&self, index: u64) -> Option<&RaftEntry> {
+        if index <= self.hard_state.snapshot_index {
+            return None;
+        }
+        self.entries
+            .get((index - self.hard_state.snapshot_index - 1) as usize)
+    }
+
+    /// Return up to max_entries entries starting at the index
+    pub(crate) fn entries_from(&self, index: u64, max_entries: usize) -> Vec<RaftEntry> {
+        let start = index.saturating_sub(self.hard_state.snapshot_index + 1) as usize;
+        self.entries
+            .iter()
+            .skip(start)
+            .take(max_entries)
+            .cloned()
+            .collect()
+    }
+
+    /// Append entries to the end of the log, syncing them to disk
+    pub(crate) fn append(&mut self, entries: Vec<RaftEntry>) -> Result<()> {
+        let mut writer = BufWriter::new(&self.log_writer);
+        for entry in &entries {
+            rmp_serde::encode::write(&mut writer, entry)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        self.log_writer.sync_data()?;
+
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    /// Discard the entry at the index and every entry after it
+    pub(crate) fn truncate_from(&mut self, index: u64) -> Result<()> {
+        let retained = index.saturating_sub(self.hard_state.snapshot_index + 1) as usize;
+        self.entries.truncate(retained);
+        self.rewrite_log()
+    }
+
+    /// Discard entries up to and including the index, which must already be applied
+    pub(crate) fn compact(&mut self, index: u64) -> Result<()> {
+        let term = match self.term_at(index) {
+            Some(term) => term,
+            None => {
+                warn!(
+                    index = index,
+                    "Attempted to compact the raft log past its end"
+                );
+                return Ok(());
+            }
+        };
+
+        self.entries.retain(|entry| entry.index > index);
+        self.hard_state.snapshot_index = index;
+        self.hard_state.snapshot_term = term;
+        self.persist_hard_state()?;
+        self.rewrite_log()
+    }
+
+    /// Discard the whole log after a snapshot was installed from the leader
+    pub(crate) fn reset_to_snapshot(&mut self, index: u64, term: u64) -> Result<()> {
+        self.entries.clear();
+        self.hard_state.snapshot_index = index;
+        self.hard_state.snapshot_term = term;
+        self.persist_hard_state()?;
+        self.rewrite_log()
+    }
+
+    fn persist_hard_state(&self) -> Result<()> {
+        let hard_state_path = self.dir.join(HARD_STATE_FILENAME);
+        let tmp_path = hard_state_path.with_extension(TMP_EXTENSION);
+
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(&rmp_serde::to_vec(&self.hard_state)?)?;
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, &hard_state_path)?;
+        Ok(())
+    }
+
+    fn rewrite_log(&mut self) -> Result<()> {
+        let log_path = self.dir.join(LOG_FILENAME);
+        let tmp_path = log_path.with_extension(TMP_EXTENSION);
+
+        let tmp_file = File::create(&tmp_path)?;
+        let mut writer = BufWriter::new(&tmp_file);
+        for entry in &self.entries {
+            rmp_serde::encode::write(&mut writer, entry)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, &log_path)?;
+
+        self.log_writer = OpenOptions::new().append(true).open(&log_path)?;
+        Ok(())
+    }
+}
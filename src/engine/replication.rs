@@ -0,0 +1,464 @@
+use chrono::{DateTime, Local};
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender, TrySendError};
+use rmp_serde::{decode, encode};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{BufReader, BufWriter, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::client::Connector;
+use crate::protocol::{encode_command, write_frame};
+use crate::tls::Stream;
+
+use super::raft::{ClusterInfo, RaftNode};
+use super::{Engine, EngineType, HobbesError, Result, ServerInfo};
+
+// Interval at which an idle leader sends heartbeats carrying its latest sequence number
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+// A follower drops the connection if nothing is received within this duration
+const LEADER_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+// Entries buffered for a single follower before it is disconnected and forced to resync
+const FOLLOWER_BUFFER_SIZE: usize = 100_000;
+// Snapshots are read and diffed this many keys at a time so that neither side holds the
+// whole keyspace in memory
+const SNAPSHOT_PAGE_SIZE: usize = 1000;
+
+const MUTEX_ERROR: &str = "Failed to lock Mutex";
+
+/// A write accepted by the leader, shipped to followers in sequence order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ReplicationEntry {
+    seq: u64,
+    key: String,
+    // None indicates a removed key
+    val: Option<String>,
+    timestamp: DateTime<Local>,
+}
+
+/// Messages streamed from a leader to a follower after a SYNC request
+#[derive(Debug, Serialize, Deserialize)]
+enum ReplicationMessage {
+    SnapshotEntry { key: String, val: String },
+    SnapshotEnd { seq: u64 },
+    Entry(ReplicationEntry),
+    Heartbeat { seq: u64 },
+}
+
+/// Replication state reported by the INFO command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "role", rename_all = "lowercase")]
+pub enum ReplicationInfo {
+    Leader {
+        /// Sequence number of the latest write
+        seq: u64,
+        followers: u64,
+    },
+    Follower {
+        leader: String,
+        connected: bool,
+        /// Sequence number of the latest write applied from the leader
+        applied_seq: u64,
+        /// Latest sequence number reported by the leader
+        leader_seq: u64,
+        /// Number of leader writes not yet applied
+        lag_entries: u64,
+        /// Delay between the leader accepting and the follower applying the latest write
+        lag_ms: u64,
+    },
+    Cluster(ClusterInfo),
+    /// Reported by hobbes-proxy, holding the statistics of each backend
+    Proxy {
+        backends: BTreeMap<String, ServerInfo>,
+    },
+}
+
+/// Orders writes to the engine and fans them out to connected followers, or replicates them
+/// through a Raft cluster
+pub(super) struct Replication {
+    // Serialises engine writes with their publication, so that followers observe writes in the
+    // order they were applied while INFO and new followers only wait on the log
+    writes: Mutex<()>,
+    log: Mutex<ReplicationLog>,
+    role: Role,
+}
+
+struct ReplicationLog {
+    seq: u64,
+    followers: Vec<Sender<ReplicationEntry>>,
+}
+
+enum Role {
+    Leader,
+    Follower {
+        leader: String,
+        connector: Connector,
+        status: Mutex<FollowerStatus>,
+    },
+    Cluster(Arc<RaftNode>),
+}
+
+#[derive(Default)]
+struct FollowerStatus {
+    connected: bool,
+    applied_seq: u64,
+    leader_seq: u64,
+    lag_ms: u64,
+}
+
+impl Replication {
+    pub(super) fn leader() -> Replication {
+        Replication {
+            writes: Mutex::new(()),
+            log: Mutex::new(ReplicationLog {
+                seq: 0,
+                followers: Vec::new(),
+            }),
+            role: Role::Leader,
+        }
+    }
+
+    pub(super) fn follower(leader: String, connector: Connector) -> Replication {
+        Replication {
+            writes: Mutex::new(()),
+            log: Mutex::new(ReplicationLog {
+                seq: 0,
+                followers: Vec::new(),
+            }),
+            role: Role::Follower {
+                leader,
+                connector,
+                status: Mutex::new(FollowerStatus::default()),
+            },
+        }
+    }
+
+    pub(super) fn cluster(node: Arc<RaftNode>) -> Replication {
+        Replication {
+            writes: Mutex::new(()),
+            log: Mutex::new(ReplicationLog {
+                seq: 0,
+                followers: Vec::new(),
+            }),
+            role: Role::Cluster(node),
+        }
+    }
+
+    pub(super) fn cluster_node(&self) -> Option<&RaftNode> {
+        match &self.role {
+            Role::Cluster(node) => Some(node),
+            _ => None,
+        }
+    }
+
+    pub(super) fn is_follower(&self) -> bool {
+        matches!(self.role, Role::Follower { .. })
+    }
+
+    /// Store a key-value pair and publish the write to followers
+    pub(super) fn set(&self, store: &EngineType, key: String, val: String) -> Result<()> {
+        if let Role::Cluster(node) = &self.role {
+            return node.set(key, val);
+        }
+
+        // The engine write, which may run a compaction inline, happens outside the log lock
+        let _writes = self.writes.lock().expect(MUTEX_ERROR);
+        store.set(key.clone(), val.clone())?;
+        self.log.lock().expect(MUTEX_ERROR).publish(key, Some(val));
+        Ok(())
+    }
+
+    /// Store many key-value pairs in a single engine write and publish each to followers
+    pub(super) fn set_many(&self, store: &EngineType, pairs: Vec<(String, String)>) -> Result<()> {
+        if let Role::Cluster(node) = &self.role {
+            return node.set_many(pairs);
+        }
+
+        let _writes = self.writes.lock().expect(MUTEX_ERROR);
+        store.set_many(pairs.clone())?;
+        let mut log = self.log.lock().expect(MUTEX_ERROR);
+        for (key, val) in pairs {
+            log.publish(key, Some(val));
+        }
+        Ok(())
+    }
+
+    /// Remove a key and publish the removal to followers
+    pub(super) fn remove(&self, store: &EngineType, key: String) -> Result<()> {
+        if let Role::Cluster(node) = &self.role {
+            return node.remove(key);
+        }
+
+        let _writes = self.writes.lock().expect(MUTEX_ERROR);
+        store.remove(key.clone())?;
+        self.log.lock().expect(MUTEX_ERROR).publish(key, None);
+        Ok(())
+    }
+
+    pub(super) fn info(&self) -> ReplicationInfo {
+        let log = self.log.lock().expect(MUTEX_ERROR);
+        match &self.role {
+            Role::Leader => ReplicationInfo::Leader {
+                seq: log.seq,
+                followers: log.followers.len() as u64,
+            },
+            Role::Follower { leader, status, .. } => {
+                let status = status.lock().expect(MUTEX_ERROR);
+                ReplicationInfo::Follower {
+                    leader: leader.clone(),
+                    connected: status.connected,
+                    applied_seq: status.applied_seq,
+                    leader_seq: status.leader_seq,
+                    lag_entries: status.leader_seq.saturating_sub(status.applied_seq),
+                    lag_ms: status.lag_ms,
+                }
+            }
+            Role::Cluster(node) => ReplicationInfo::Cluster(node.info()),
+        }
+    }
+
+    fn subscribe(&self) -> (Receiver<ReplicationEntry>, u64) {
+        let (tx, rx) = channel::bounded(FOLLOWER_BUFFER_SIZE);
+        let mut log = self.log.lock().expect(MUTEX_ERROR);
+        log.followers.push(tx);
+        (rx, log.seq)
+    }
+
+    fn seq(&self) -> u64 {
+        self.log.lock().expect(MUTEX_ERROR).seq
+    }
+
+    fn update_status(&self, update: impl FnOnce(&mut FollowerStatus)) {
+        if let Role::Follower { status, .. } = &self.role {
+            update(&mut status.lock().expect(MUTEX_ERROR));
+        }
+    }
+}
+
+impl ReplicationLog {
+    fn publish(&mut self, key: String, val: Option<String>) {
+        self.seq += 1;
+        let entry = ReplicationEntry {
+            seq: self.seq,
+            key,
+            val,
+            timestamp: Local::now(),
+        };
+
+        // Followers that have disconnected or fallen too far behind are dropped, and resync
+        // with a fresh snapshot when they reconnect
+        self.followers
+            .retain(|tx| match tx.try_send(entry.clone()) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Follower buffer full, disconnecting follower");
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+/// Stream a snapshot of the store followed by every subsequent write to a follower
+pub(super) fn serve_follower(
+    store: EngineType,
+    replication: Arc<Replication>,
+    tcp_stream: Stream,
+) -> Result<()> {
+    let peer_addr = tcp_stream.peer_addr()?;
+    info!(follower_addr = %peer_addr, "Follower connected, sending snapshot");
+
+    // Subscribing before the snapshot is taken ensures no write is missed. Writes that race
+    // with the snapshot are replayed in order by the follower, converging on the leader's state.
+    let (rx, snapshot_seq) = replication.subscribe();
+    let mut writer = BufWriter::new(tcp_stream);
+
+    // Pages are sent in key order, which lets the follower find its stale keys a page at a time
+    let mut after = None;
+    loop {
+        let page = store.scan_page(String::new(), after, SNAPSHOT_PAGE_SIZE)?;
+        let Some((last, _)) = page.last() else {
+            break;
+        };
+        after = Some(last.clone());
+        for (key, val) in page {
+            encode::write(&mut writer, &ReplicationMessage::SnapshotEntry { key, val })?;
+        }
+    }
+    encode::write(
+        &mut writer,
+        &ReplicationMessage::SnapshotEnd { seq: snapshot_seq },
+    )?;
+    writer.flush()?;
+    info!(follower_addr = %peer_addr, seq = snapshot_seq, "Snapshot sent to follower");
+
+    loop {
+        match rx.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(entry) => {
+                encode::write(&mut writer, &ReplicationMessage::Entry(entry))?;
+                if rx.is_empty() {
+                    writer.flush()?;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                encode::write(
+                    &mut writer,
+                    &ReplicationMessage::Heartbeat {
+                        seq: replication.seq(),
+                    },
+                )?;
+                writer.flush()?;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(HobbesError::NetworkError(String::from(
+                    "follower disconnected by the leader",
+                )))
+            }
+        }
+    }
+}
+
+/// Continuously replicate from the leader, resyncing whenever the connection is lost
+pub(super) fn follow(store: EngineType, replication: Arc<Replication>) {
+    let (leader, connector) = match &replication.role {
+        Role::Follower {
+            leader, connector, ..
+        } => (leader.clone(), connector.clone()),
+        _ => return,
+    };
+
+    loop {
+        if let Err(e) = sync_from_leader(&store, &replication, &leader, &connector) {
+            warn!(
+                leader_addr = leader,
+                "Replication from leader interrupted -> {e}"
+            );
+        }
+        replication.update_status(|status| status.connected = false);
+        thread::sleep(RECONNECT_INTERVAL);
+    }
+}
+
+fn sync_from_leader(
+    store: &EngineType,
+    replication: &Replication,
+    leader: &str,
+    connector: &Connector,
+) -> Result<()> {
+    let mut tcp_stream = connector.connect(leader, None)?;
+    tcp_stream.set_read_timeout(Some(LEADER_TIMEOUT))?;
+
+    write_frame(&mut tcp_stream, &encode_command(&["SYNC"]))?;
+    info!(
+        leader_addr = leader,
+        "Connected to leader, receiving snapshot"
+    );
+
+    // Keys absent from the snapshot were removed on the leader while disconnected
+    let mut local_keys = LocalKeys::new(store);
+
+    let mut reader = BufReader::new(tcp_stream);
+    loop {
+        let msg: ReplicationMessage = decode::from_read(&mut reader)?;
+        match msg {
+            ReplicationMessage::SnapshotEntry { key, val } => {
+                local_keys.remove_before(replication, Some(&key))?;
+                replication.set(store, key, val)?;
+            }
+            ReplicationMessage::SnapshotEnd { seq } => {
+                local_keys.remove_before(replication, None)?;
+                replication.update_status(|status| {
+                    status.connected = true;
+                    status.applied_seq = seq;
+                    status.leader_seq = seq;
+                    status.lag_ms = 0;
+                });
+                info!(leader_addr = leader, seq = seq, "Snapshot applied");
+            }
+            ReplicationMessage::Entry(entry) => {
+                debug!(
+                    seq = entry.seq,
+                    key = entry.key,
+                    "Applying replicated entry"
+                );
+                match entry.val {
+                    Some(val) => replication.set(store, entry.key, val)?,
+                    None => apply_remove(store, replication, entry.key)?,
+                }
+                let lag_ms = (Local::now() - entry.timestamp).num_milliseconds().max(0) as u64;
+                replication.update_status(|status| {
+                    status.applied_seq = entry.seq;
+                    status.leader_seq = status.leader_seq.max(entry.seq);
+                    status.lag_ms = lag_ms;
+                });
+            }
+            ReplicationMessage::Heartbeat { seq } => {
+                replication.update_status(|status| {
+                    status.leader_seq = seq;
+                    if status.applied_seq >= seq {
+                        status.lag_ms = 0;
+                    }
+                });
+            }
+        }
+    }
+}
+
+fn apply_remove(store: &EngineType, replication: &Replication, key: String) -> Result<()> {
+    match replication.remove(store, key) {
+        Err(HobbesError::KeyNotFoundError) => Ok(()),
+        res => res,
+    }
+}
+
+/// Keys stored by a follower before a resync, read a page at a time in key order and merged
+/// with the leader's snapshot to find the keys removed on the leader
+struct LocalKeys<'a> {
+    store: &'a EngineType,
+    page: VecDeque<String>,
+    // Last key read, from which the next page starts
+    after: Option<String>,
+    exhausted: bool,
+}
+
+impl<'a> LocalKeys<'a> {
+    fn new(store: &'a EngineType) -> LocalKeys<'a> {
+        LocalKeys {
+            store,
+            page: VecDeque::new(),
+            after: None,
+            exhausted: false,
+        }
+    }
+
+    /// Remove every local key ordered before `key`, which is the next key of the snapshot, or
+    /// every remaining local key once the snapshot has ended
+    fn remove_before(&mut self, replication: &Replication, key: Option<&str>) -> Result<()> {
+        loop {
+            if self.page.is_empty() && !self.exhausted {
+                let page =
+                    self.store
+                        .scan_page(String::new(), self.after.take(), SNAPSHOT_PAGE_SIZE)?;
+                self.exhausted = page.is_empty();
+                self.after = page.last().map(|(key, _)| key.clone());
+                self.page = page.into_iter().map(|(key, _)| key).collect();
+            }
+            let Some(local) = self.page.pop_front() else {
+                return Ok(());
+            };
+
+            match key {
+                Some(key) if local.as_str() > key => {
+                    self.page.push_front(local);
+                    return Ok(());
+                }
+                Some(key) if local == key => return Ok(()),
+                _ => apply_remove(self.store, replication, local)?,
+            }
+        }
+    }
+}
@@ -1,13 +1,16 @@
 use sled;
 use tracing::error;
 
+use std::ops::Bound;
 use std::path::Path;
 
-use super::{Engine, HobbesError, Result, BITCASK_LOGS_PATH, SLED_DB_PATH};
+use super::watch::{WatchHub, Watcher};
+use super::{Engine, EngineStats, HobbesError, Result, BITCASK_LOGS_PATH, SLED_DB_PATH};
 
 #[derive(Clone)]
 pub struct SledEngine {
     db: sled::Db,
+    watch_hub: WatchHub,
 }
 
 impl SledEngine {
@@ -23,7 +26,10 @@ impl SledEngine {
 
         let logs_dir = logs_dir_arg.join(SLED_DB_PATH);
         let db = sled::open(logs_dir)?;
-        Ok(SledEngine { db })
+        Ok(SledEngine {
+            db,
+            watch_hub: WatchHub::default(),
+        })
     }
 }
 
@@ -42,27 +48,125 @@ impl Engine for SledEngine {
     }
 
     fn set(&self, key: String, value: String) -> Result<()> {
-        let set_ret = self.db.insert(key, value.as_bytes());
-        match set_ret {
-            Ok(_) => {
-                self.db.flush()?;
-                Ok(())
+        self.watch_hub.record(&key, Some(&value), || {
+            let set_ret = self.db.insert(key.as_bytes(), value.as_bytes());
+            match set_ret {
+                Ok(_) => {
+                    self.db.flush()?;
+                    Ok(())
+                }
+                Err(err) => Err(HobbesError::SledDbError(err)),
             }
-            Err(err) => Err(HobbesError::SledDbError(err)),
+        })
+    }
+
+    /// Store the pairs in a single batch, applied atomically
+    fn set_many(&self, pairs: impl IntoIterator<Item = (String, String)>) -> Result<u64> {
+        let pairs = pairs.into_iter().collect::<Vec<(String, String)>>();
+        let mut batch = sled::Batch::default();
+        for (key, value) in &pairs {
+            batch.insert(key.as_bytes(), value.as_bytes());
         }
+
+        let writes = pairs
+            .iter()
+            .map(|(key, value)| (key.as_str(), Some(value.as_str())));
+        self.watch_hub.record_many(writes, || {
+            self.db.apply_batch(batch)?;
+            self.db.flush()?;
+            Ok(pairs.len() as u64)
+        })
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        let rm_ret = self.db.remove(key.as_bytes());
-        match rm_ret {
-            Ok(opt) => match opt {
-                Some(_) => {
-                    self.db.flush()?;
-                    Ok(())
-                }
-                None => Err(HobbesError::KeyNotFoundError),
-            },
-            Err(err) => Err(HobbesError::SledDbError(err)),
+        self.watch_hub.record(&key, None, || {
+            let rm_ret = self.db.remove(key.as_bytes());
+            match rm_ret {
+                Ok(opt) => match opt {
+                    Some(_) => {
+                        self.db.flush()?;
+                        Ok(())
+                    }
+                    None => Err(HobbesError::KeyNotFoundError),
+                },
+                Err(err) => Err(HobbesError::SledDbError(err)),
+            }
+        })
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        // sled manages its own storage, so segment and compaction statistics are not tracked
+        Ok(EngineStats {
+            engine: String::from("sled"),
+            keys: self.db.len() as u64,
+            segments: 0,
+            segments_size: self.db.size_on_disk()?,
+            dead_bytes_ratio: 0.0,
+            index_bytes: None,
+            compactions: 0,
+            last_compaction_at: None,
+            last_compaction_ms: None,
+        })
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for pair in self.db.scan_prefix(prefix) {
+            let (key, val) = pair?;
+            match (
+                String::from_utf8(key.to_vec()),
+                String::from_utf8(val.to_vec()),
+            ) {
+                (Ok(key), Ok(val)) => pairs.push((key, val)),
+                _ => error!("failed to parse key-value pair retrieved from sled engine"),
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn scan_page(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        // Keys starting with the prefix are contiguous, from the prefix itself onwards
+        let range = match &after {
+            Some(after) if *after >= prefix => self
+                .db
+                .range::<&[u8], _>((Bound::Excluded(after.as_bytes()), Bound::Unbounded)),
+            _ => self.db.range::<&[u8], _>(prefix.as_bytes()..),
+        };
+
+        let mut pairs = Vec::new();
+        for pair in range {
+            let (key, val) = pair?;
+            if !key.starts_with(prefix.as_bytes()) || pairs.len() == limit {
+                break;
+            }
+            match (
+                String::from_utf8(key.to_vec()),
+                String::from_utf8(val.to_vec()),
+            ) {
+                (Ok(key), Ok(val)) => pairs.push((key, val)),
+                _ => error!("failed to parse key-value pair retrieved from sled engine"),
+            }
         }
+        Ok(pairs)
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        Ok(self.watch_hub.subscribe(prefix))
+    }
+
+    fn compact(&self) -> Result<()> {
+        // sled reclaims space in the background, so compacting only flushes pending writes
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
     }
 }
@@ -0,0 +1,212 @@
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender, TrySendError};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use crate::protocol::Response;
+use crate::tls::Stream;
+
+use super::{Engine, EngineType, HobbesError, Result};
+
+// Interval at which an idle watch connection receives an empty line, detecting closed
+// connections
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+// Events buffered for a single watcher before it is disconnected
+const WATCHER_BUFFER_SIZE: usize = 10_000;
+
+const MUTEX_ERROR: &str = "Failed to lock Mutex";
+
+/// A change to a key, published once the write is stored by the engine
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchEvent {
+    /// Position of the write among every write to the engine since it was opened
+    pub seq: u64,
+    pub key: String,
+    /// New value of the key, or None if the key was removed
+    pub val: Option<String>,
+}
+
+/// Watcher receives the events for keys starting with a prefix, in the order the writes were
+/// applied. A watcher which falls too far behind is disconnected.
+#[derive(Debug)]
+pub struct Watcher {
+    id: u64,
+    rx: Receiver<WatchEvent>,
+    hub: Weak<Mutex<WatchState>>,
+}
+
+impl Watcher {
+    /// Block until the next event, returning None once the watcher is disconnected
+    pub fn recv(&self) -> Option<WatchEvent> {
+        self.rx.recv().ok()
+    }
+
+    /// Wait up to the timeout for the next event, returning None if no event arrived
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<WatchEvent>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(event) => Ok(Some(event)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(HobbesError::WatchError(String::from(
+                "watcher disconnected by the engine",
+            ))),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.rx.is_empty()
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        if let Some(state) = self.hub.upgrade() {
+            state
+                .lock()
+                .expect(MUTEX_ERROR)
+                .watchers
+                .retain(|watcher| watcher.id != self.id);
+        }
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = WatchEvent;
+
+    fn next(&mut self) -> Option<WatchEvent> {
+        self.recv()
+    }
+}
+
+/// WatchHub numbers the writes to an engine and publishes them to the matching watchers
+#[derive(Debug, Clone, Default)]
+pub(crate) struct WatchHub {
+    state: Arc<Mutex<WatchState>>,
+}
+
+#[derive(Debug, Default)]
+struct WatchState {
+    seq: u64,
+    next_watcher_id: u64,
+    watchers: Vec<WatcherHandle>,
+}
+
+#[derive(Debug)]
+struct WatcherHandle {
+    id: u64,
+    prefix: String,
+    tx: Sender<WatchEvent>,
+}
+
+impl WatchHub {
+    pub(crate) fn subscribe(&self, prefix: String) -> Watcher {
+        let (tx, rx) = channel::bounded(WATCHER_BUFFER_SIZE);
+        let mut state = self.state.lock().expect(MUTEX_ERROR);
+        state.next_watcher_id += 1;
+
+        let id = state.next_watcher_id;
+        state.watchers.push(WatcherHandle { id, prefix, tx });
+        Watcher {
+            id,
+            rx,
+            hub: Arc::downgrade(&self.state),
+        }
+    }
+
+    /// Perform a write and publish it if it succeeds. Writes are serialized with each other so
+    /// that watchers observe them in the order they were applied.
+    pub(crate) fn record<T>(
+        &self,
+        key: &str,
+        val: Option<&str>,
+        write: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let mut state = self.state.lock().expect(MUTEX_ERROR);
+        let res = write()?;
+        state.publish(key, val);
+        Ok(res)
+    }
+
+    /// Perform a batch of writes and publish them in order if it succeeds, as record does for a
+    /// single write
+    pub(crate) fn record_many<'a, T>(
+        &self,
+        writes: impl IntoIterator<Item = (&'a str, Option<&'a str>)>,
+        write: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let mut state = self.state.lock().expect(MUTEX_ERROR);
+        let res = write()?;
+        for (key, val) in writes {
+            state.publish(key, val);
+        }
+        Ok(res)
+    }
+
+    /// Publish writes already applied, in order. Callers serialize them with every other write
+    /// to the engine, as record does.
+    pub(crate) fn publish<'a>(&self, writes: impl IntoIterator<Item = (&'a str, Option<&'a str>)>) {
+        let mut state = self.state.lock().expect(MUTEX_ERROR);
+        for (key, val) in writes {
+            state.publish(key, val);
+        }
+    }
+}
+
+impl WatchState {
+    fn publish(&mut self, key: &str, val: Option<&str>) {
+        self.seq += 1;
+
+        let seq = self.seq;
+        self.watchers.retain(|watcher| {
+            if !key.starts_with(watcher.prefix.as_str()) {
+                return true;
+            }
+            let event = WatchEvent {
+                seq,
+                key: key.to_string(),
+                val: val.map(str::to_string),
+            };
+            match watcher.tx.try_send(event) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        prefix = watcher.prefix,
+                        "Watcher buffer full, disconnecting watcher"
+                    );
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+/// Stream the events for keys starting with the prefix to a client as JSON lines
+pub(super) fn serve_watcher(store: EngineType, prefix: String, tcp_stream: Stream) -> Result<()> {
+    let peer_addr = tcp_stream.peer_addr()?;
+    let watcher = store.watch(prefix.clone())?;
+    let mut writer = BufWriter::new(tcp_stream);
+
+    // An OK status line acknowledges the subscription, every later write being streamed
+    writer.write_all(Response::ok("").encode().as_bytes())?;
+    writer.flush()?;
+    info!(client_addr = %peer_addr, prefix = prefix, "Watcher subscribed");
+
+    loop {
+        match watcher.recv_timeout(HEARTBEAT_INTERVAL)? {
+            Some(event) => {
+                serde_json::to_writer(&mut writer, &event)?;
+                writer.write_all(b"\n")?;
+                if watcher.is_empty() {
+                    writer.flush()?;
+                }
+            }
+            None => {
+                writer.write_all(b"\n")?;
+                writer.flush()?;
+            }
+        }
+    }
+}
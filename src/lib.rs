@@ -2,13 +2,25 @@
 
 //! A Bitcask-like log-structured key-value store with an in-memory index
 
+// Segments are read with Unix positional reads, and merged segments are removed while still
+// mapped, which Windows refuses
+#[cfg(not(unix))]
+compile_error!("hobbes only supports Unix platforms");
+
 use rmp_serde::{decode, encode};
 use tracing::subscriber;
 
 use std::{fmt, io, num, path};
 
+pub mod auth;
+pub mod bulk;
+pub mod client;
+pub mod config;
 pub mod engine;
+pub mod protocol;
+pub mod proxy;
 pub mod thread_pool;
+pub mod tls;
 
 const RWLOCK_ERROR: &str = "Failed to lock RwLock";
 
@@ -44,6 +56,33 @@ pub enum HobbesError {
     NetworkError(String),
     /// Indicates errors while sending types over a channel
     ChannelSendError(String),
+    /// Indicates errors while converting types to or from JSON
+    JsonError(serde_json::Error),
+    /// Indicates a request which must be handled by the cluster leader, holding the leader's
+    /// address if known
+    NotLeaderError(Option<String>),
+    /// Indicates errors while replicating commands through the cluster
+    ConsensusError(String),
+    /// Indicates a command rejected by the server, holding the server's response
+    ServerError(String),
+    /// Indicates a watch subscription closed by the engine or the server
+    WatchError(String),
+    /// Indicates errors while configuring or establishing TLS connections
+    TlsError(String),
+    /// Indicates failed authentication or a command the user is not permitted to perform
+    AuthError(String),
+    /// Indicates invalid encryption keys or records which cannot be decrypted
+    EncryptionError(String),
+    /// Indicates an unreadable or invalid configuration file or setting
+    ConfigError(String),
+    /// Indicates a data directory already opened by another process
+    DirectoryLockedError(String),
+    /// Indicates a write to a store opened read-only
+    ReadOnlyError,
+    /// Indicates a malformed record in an import file
+    ImportError(String),
+    /// Indicates a store or segment written in a format newer than this version of hobbes reads
+    UnsupportedFormatError(String),
 }
 
 /// Result type for the store
@@ -71,6 +110,26 @@ impl fmt::Display for HobbesError {
             HobbesError::SledDbError(ref err) => write!(f, "Sled Engine Error: {}", err),
             HobbesError::NetworkError(ref err) => write!(f, "Network Error: {}", err),
             HobbesError::ChannelSendError(ref err) => write!(f, "Channel Send Error: {}", err),
+            HobbesError::JsonError(ref err) => write!(f, "JSON Error: {}", err),
+            HobbesError::NotLeaderError(ref leader) => match leader {
+                Some(leader) => write!(f, "Not Leader Error: leader is at {}", leader),
+                None => write!(f, "Not Leader Error: no leader elected"),
+            },
+            HobbesError::ConsensusError(ref err) => write!(f, "Consensus Error: {}", err),
+            HobbesError::ServerError(ref err) => write!(f, "{}", err),
+            HobbesError::WatchError(ref err) => write!(f, "Watch Error: {}", err),
+            HobbesError::TlsError(ref err) => write!(f, "TLS Error: {}", err),
+            HobbesError::AuthError(ref err) => write!(f, "Auth Error: {}", err),
+            HobbesError::EncryptionError(ref err) => write!(f, "Encryption Error: {}", err),
+            HobbesError::ConfigError(ref err) => write!(f, "Config Error: {}", err),
+            HobbesError::DirectoryLockedError(ref err) => {
+                write!(f, "Directory Locked Error: {}", err)
+            }
+            HobbesError::ReadOnlyError => write!(f, "Read Only Error: store was opened read-only"),
+            HobbesError::ImportError(ref err) => write!(f, "Import Error: {}", err),
+            HobbesError::UnsupportedFormatError(ref err) => {
+                write!(f, "Unsupported Format Error: {}", err)
+            }
         }
     }
 }
@@ -116,3 +175,15 @@ impl From<sled::Error> for HobbesError {
         HobbesError::SledDbError(value)
     }
 }
+
+impl From<serde_json::Error> for HobbesError {
+    fn from(value: serde_json::Error) -> Self {
+        HobbesError::JsonError(value)
+    }
+}
+
+impl From<rustls::Error> for HobbesError {
+    fn from(value: rustls::Error) -> Self {
+        HobbesError::TlsError(value.to_string())
+    }
+}
@@ -0,0 +1,178 @@
+//! Framing used by the client-server protocol
+//!
+//! A command and its arguments are separated and terminated by CRLF, and the command is
+//! prefixed with its length in bytes, e.g. `10\r\nGET\r\nfoo\r\n`
+//!
+//! Batches of pairs are sent as a single JSON-encoded argument, e.g.
+//! `26\r\nMSET\r\n[["foo","a\r\nb"]]\r\n`, so that values holding CRLF or surrounding
+//! whitespace are not split or trimmed
+//!
+//! Responses start with a status line, followed by the payload until the connection closes,
+//! e.g. `OK\r\nbar` or `REDIRECT\r\n127.0.0.1:4001`, so that a stored value is never mistaken
+//! for a redirect or an error
+
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use crate::{HobbesError, Result};
+
+pub const CRLF: &str = "\r\n";
+
+/// Status line of a response, describing how its payload is interpreted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The command succeeded, the payload holding its result
+    Ok,
+    /// The key is absent
+    NotFound,
+    /// The node is not the cluster leader, the payload holding the leader's address
+    Redirect,
+    /// The node is a member of a cluster without an elected leader
+    NoLeader,
+    /// The command writes to a read-only replica
+    ReadOnly,
+    /// The client failed to authenticate, or its user may not run the command
+    AuthError,
+    /// The command failed, the payload describing the error
+    Error,
+}
+
+impl Status {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::NotFound => "NOT_FOUND",
+            Status::Redirect => "REDIRECT",
+            Status::NoLeader => "NO_LEADER",
+            Status::ReadOnly => "READ_ONLY",
+            Status::AuthError => "AUTH_ERROR",
+            Status::Error => "ERROR",
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Status {
+    type Err = HobbesError;
+
+    fn from_str(status: &str) -> Result<Status> {
+        match status {
+            "OK" => Ok(Status::Ok),
+            "NOT_FOUND" => Ok(Status::NotFound),
+            "REDIRECT" => Ok(Status::Redirect),
+            "NO_LEADER" => Ok(Status::NoLeader),
+            "READ_ONLY" => Ok(Status::ReadOnly),
+            "AUTH_ERROR" => Ok(Status::AuthError),
+            "ERROR" => Ok(Status::Error),
+            _ => Err(HobbesError::NetworkError(format!(
+                "invalid response status {status:?}"
+            ))),
+        }
+    }
+}
+
+/// Response to a command, made of a status and its payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: Status,
+    pub payload: String,
+}
+
+impl Response {
+    pub fn new(status: Status, payload: impl Into<String>) -> Response {
+        Response {
+            status,
+            payload: payload.into(),
+        }
+    }
+
+    /// Successful response holding the result of the command
+    pub fn ok(payload: impl Into<String>) -> Response {
+        Response::new(Status::Ok, payload)
+    }
+
+    /// Response describing the error the command failed with
+    pub fn error(payload: impl Into<String>) -> Response {
+        Response::new(Status::Error, payload)
+    }
+
+    /// Prefix the payload with the status line
+    pub fn encode(&self) -> String {
+        format!("{}{CRLF}{}", self.status, self.payload)
+    }
+
+    /// Return the payload of a successful response, and the error described by the status
+    /// otherwise
+    pub fn into_payload(self) -> Result<String> {
+        match self.status {
+            Status::Ok => Ok(self.payload),
+            Status::NotFound => Err(HobbesError::KeyNotFoundError),
+            Status::NoLeader => Err(HobbesError::NotLeaderError(None)),
+            Status::Redirect => Err(HobbesError::NotLeaderError(Some(self.payload))),
+            Status::AuthError => Err(HobbesError::AuthError(self.payload)),
+            Status::ReadOnly | Status::Error => Err(HobbesError::ServerError(self.payload)),
+        }
+    }
+
+    /// Split a response read until the connection closed into its status and payload
+    pub fn decode(resp: &str) -> Result<Response> {
+        let (status, payload) = resp.split_once(CRLF).ok_or_else(|| {
+            HobbesError::NetworkError(format!(
+                "response is missing its status line, response = {resp:?}"
+            ))
+        })?;
+        Ok(Response::new(status.parse()?, payload))
+    }
+}
+
+/// Join a command and its arguments into a CRLF-separated command string
+pub fn encode_command(args: &[&str]) -> String {
+    let mut cmd = String::new();
+    for arg in args {
+        cmd.push_str(arg);
+        cmd.push_str(CRLF);
+    }
+    cmd
+}
+
+/// Encode key-value pairs as the argument of a batch command
+pub fn encode_pairs(pairs: &[(String, String)]) -> Result<String> {
+    Ok(serde_json::to_string(pairs)?)
+}
+
+/// Decode the key-value pairs sent as the argument of a batch command
+pub fn decode_pairs(arg: &str) -> Result<Vec<(String, String)>> {
+    Ok(serde_json::from_str(arg)?)
+}
+
+/// Write a command prefixed with its length
+pub fn write_frame(writer: &mut impl Write, cmd: &str) -> Result<()> {
+    writer.write_all(format!("{}{CRLF}{cmd}", cmd.len()).as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read a length-prefixed command
+pub fn read_frame(reader: &mut impl BufRead) -> Result<String> {
+    let mut cmd_prefix = String::new();
+    reader.read_line(&mut cmd_prefix)?;
+    let cmd_len = cmd_prefix
+        .strip_suffix(CRLF)
+        .ok_or_else(|| {
+            HobbesError::NetworkError(format!(
+                "network command prefix not appended with CRLF, command = {cmd_prefix}"
+            ))
+        })?
+        .parse::<usize>()?;
+
+    let mut cmd_bytes = vec![0u8; cmd_len];
+    reader.read_exact(&mut cmd_bytes)?;
+    String::from_utf8(cmd_bytes)
+        .map_err(|err| HobbesError::NetworkError(format!("command is not valid UTF-8, {err}")))
+}
@@ -0,0 +1,699 @@
+//! Proxy distributing keys across multiple hobbes servers using consistent hashing
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use crossbeam::channel::{self, RecvTimeoutError};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::client::Client;
+use crate::engine::{parse_scan_page, EngineStats, ReplicationInfo, ServerInfo};
+use crate::protocol::{decode_pairs, read_frame, Response, Status, CRLF};
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use crate::{HobbesError, Result, RWLOCK_ERROR};
+
+pub use ring::HashRing;
+
+mod ring;
+
+const MUTEX_ERROR: &str = "Failed to lock Mutex";
+// Number of keys listed per request while migrating keys off a backend
+const MIGRATION_PAGE_SIZE: usize = 1000;
+const TMP_EXTENSION: &str = "tmp";
+// Interval at which an idle watch connection receives an empty line, as sent by servers
+const WATCH_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Response sent to clients attempting to authenticate, as the proxy does not forward
+/// credentials to the backends
+pub const AUTH_UNSUPPORTED_RESPONSE: &str = "Authentication is not supported by hobbes-proxy";
+/// Response sent to clients changing the backends through the address serving keys
+pub const MEMBERSHIP_DENIED_RESPONSE: &str =
+    "Backends can only be changed through the admin address of hobbes-proxy";
+
+/// Options used to start a proxy
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// Address to listen on
+    pub addr: String,
+    /// Addresses of the hobbes servers storing the keys
+    pub backends: Vec<String>,
+    /// Number of points on the hash ring per backend
+    pub virtual_nodes: u32,
+    /// File persisting the backends across restarts. Once it exists, the backends it lists take
+    /// precedence over `backends`.
+    pub state_file: Option<PathBuf>,
+    /// Address accepting ADDNODE and RMNODE, which are refused on `addr` as the proxy does not
+    /// authenticate clients. Backends only change through `backends` and the state file when
+    /// unset.
+    pub admin_addr: Option<String>,
+}
+
+pub fn start_proxy(config: &ProxyConfig) -> Result<()> {
+    let proxy = Arc::new(Proxy::open(config)?);
+    let pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
+
+    // A migration interrupted by a restart is resumed, keys being routed through both of their
+    // owners meanwhile
+    if proxy.is_migrating() {
+        let proxy = proxy.clone();
+        thread::spawn(move || {
+            let _changing = proxy.changing.lock().expect(MUTEX_ERROR);
+            match proxy.migrate() {
+                Ok(moved) => info!(migrated = moved, "Resumed migration completed"),
+                Err(e) => error!("Failed to resume migration -> {e}"),
+            }
+        });
+    }
+
+    let listener = TcpListener::bind(&config.addr)?;
+    if let Some(admin_addr) = &config.admin_addr {
+        let admin_listener = TcpListener::bind(admin_addr)?;
+        info!(admin_addr = admin_addr, "Accepting backend changes");
+        let proxy = proxy.clone();
+        // Served one connection at a time, as backends are changed one at a time anyway
+        thread::spawn(move || {
+            for tcp_stream in admin_listener.incoming().flatten() {
+                proxy_handler(&proxy, tcp_stream, true);
+            }
+        });
+    }
+    info!(
+        proxy_addr = config.addr,
+        backends = proxy
+            .membership
+            .read()
+            .expect(RWLOCK_ERROR)
+            .ring
+            .nodes()
+            .len(),
+        "Proxy started"
+    );
+
+    for tcp_stream in listener.incoming().flatten() {
+        let proxy_clone = proxy.clone();
+        pool.spawn(move || {
+            proxy_clone
+                .connections_active
+                .fetch_add(1, Ordering::Relaxed);
+            proxy_clone
+                .connections_total
+                .fetch_add(1, Ordering::Relaxed);
+            proxy_handler(&proxy_clone, tcp_stream, false);
+            proxy_clone
+                .connections_active
+                .fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+
+    Ok(())
+}
+
+/// Backends the keys are routed to. While keys are migrated, the ring before the change is
+/// kept, and keys whose owner changed are read from either owner and written to the new one.
+#[derive(Debug)]
+struct Membership {
+    ring: HashRing,
+    previous: Option<HashRing>,
+}
+
+// Layout of the state file
+#[derive(Debug, Serialize, Deserialize)]
+struct MembershipState {
+    backends: Vec<String>,
+    // Backends before the change being migrated, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    previous_backends: Option<Vec<String>>,
+}
+
+// Owner of a key, and its previous owner if the key is being moved
+struct Route<'a> {
+    owner: &'a str,
+    previous: Option<&'a str>,
+}
+
+impl Membership {
+    fn route(&self, key: &str) -> Result<Route<'_>> {
+        let owner = self
+            .ring
+            .node_for(key)
+            .ok_or(HobbesError::CliError(String::from("no backends available")))?;
+        let previous = self
+            .previous
+            .as_ref()
+            .and_then(|previous| previous.node_for(key))
+            .filter(|previous| *previous != owner);
+        Ok(Route { owner, previous })
+    }
+
+    // Every backend holding keys, including those being migrated away from
+    fn nodes(&self) -> Vec<&str> {
+        let mut nodes: Vec<&str> = self.ring.nodes().iter().map(String::as_str).collect();
+        if let Some(previous) = &self.previous {
+            for node in previous.nodes() {
+                if !nodes.contains(&node.as_str()) {
+                    nodes.push(node);
+                }
+            }
+        }
+        nodes
+    }
+}
+
+struct Proxy {
+    membership: RwLock<Membership>,
+    // Serializes membership changes, so that a single migration runs at a time
+    changing: Mutex<()>,
+    // Held while a key is moved between backends and while requests for keys being moved are
+    // forwarded, so that neither observes a key half moved
+    moving: Mutex<()>,
+    state_file: Option<PathBuf>,
+    started_at: Instant,
+    connections_active: AtomicU64,
+    connections_total: AtomicU64,
+}
+
+impl Proxy {
+    fn open(config: &ProxyConfig) -> Result<Proxy> {
+        let ring = |backends: &[String]| {
+            let mut ring = HashRing::new(config.virtual_nodes);
+            for backend in backends {
+                ring.add(backend);
+            }
+            ring
+        };
+
+        let membership = match &config.state_file {
+            Some(state_file) if state_file.is_file() => {
+                let state: MembershipState =
+                    serde_json::from_reader(BufReader::new(File::open(state_file)?))?;
+                info!(
+                    state_file = ?state_file,
+                    backends = state.backends.len(),
+                    "Loaded backends from state file"
+                );
+                Membership {
+                    ring: ring(&state.backends),
+                    previous: state.previous_backends.as_deref().map(ring),
+                }
+            }
+            _ => Membership {
+                ring: ring(&config.backends),
+                previous: None,
+            },
+        };
+
+        let proxy = Proxy {
+            membership: RwLock::new(membership),
+            changing: Mutex::new(()),
+            moving: Mutex::new(()),
+            state_file: config.state_file.clone(),
+            started_at: Instant::now(),
+            connections_active: AtomicU64::new(0),
+            connections_total: AtomicU64::new(0),
+        };
+        proxy.persist(&proxy.membership.read().expect(RWLOCK_ERROR))?;
+        Ok(proxy)
+    }
+
+    fn is_migrating(&self) -> bool {
+        self.membership
+            .read()
+            .expect(RWLOCK_ERROR)
+            .previous
+            .is_some()
+    }
+
+    // Writes the membership to the state file, replacing it atomically
+    fn persist(&self, membership: &Membership) -> Result<()> {
+        let Some(state_file) = &self.state_file else {
+            return Ok(());
+        };
+        let state = MembershipState {
+            backends: membership.ring.nodes().to_vec(),
+            previous_backends: membership
+                .previous
+                .as_ref()
+                .map(|previous| previous.nodes().to_vec()),
+        };
+
+        let tmp_path = state_file.with_extension(TMP_EXTENSION);
+        let tmp_file = File::create(&tmp_path)?;
+        let mut writer = BufWriter::new(&tmp_file);
+        serde_json::to_writer_pretty(&mut writer, &state)?;
+        writer.flush()?;
+        drop(writer);
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, state_file)?;
+        Ok(())
+    }
+
+    // Backends are only changed by clients of the admin address
+    fn handle_cmd(&self, cmd_str: &str, admin: bool) -> Result<Response> {
+        let mut msg = cmd_str.split(CRLF);
+        match msg.next().unwrap_or_default() {
+            cmd @ ("GET" | "SET" | "RM") => {
+                let key = msg
+                    .next()
+                    .ok_or(HobbesError::CliError(String::from(
+                        "Missing key in command",
+                    )))?
+                    .trim();
+
+                // The membership stays locked while forwarding, so that requests do not race
+                // with the start or end of a migration
+                let membership = self.membership.read().expect(RWLOCK_ERROR);
+                let route = membership.route(key)?;
+                debug!(key = key, backend = route.owner, "Forwarding command");
+                let Some(previous) = route.previous else {
+                    return Client::new(route.owner).send_cmd(cmd_str);
+                };
+
+                let _moving = self.moving.lock().expect(MUTEX_ERROR);
+                match cmd {
+                    // Keys not yet moved are read from their previous owner
+                    "GET" => match Client::new(route.owner).get(key)? {
+                        Some(val) => Ok(Response::ok(val)),
+                        None => Client::new(previous).send_cmd(cmd_str),
+                    },
+                    // Keys written while being moved are moved along with the write
+                    "SET" => {
+                        let resp = Client::new(route.owner).send_cmd(cmd_str)?;
+                        remove_if_present(previous, key)?;
+                        Ok(resp)
+                    }
+                    _ => {
+                        let removed = remove_if_present(route.owner, key)?;
+                        if remove_if_present(previous, key)? || removed {
+                            Ok(Response::ok("Success"))
+                        } else {
+                            Ok(Response::new(Status::NotFound, "Key not found"))
+                        }
+                    }
+                }
+            }
+            "MSET" => {
+                let pairs = decode_pairs(msg.next().unwrap_or_default())?;
+
+                // Pairs are split into a batch per backend
+                let membership = self.membership.read().expect(RWLOCK_ERROR);
+                let mut batches: HashMap<&str, Vec<(String, String)>> = HashMap::new();
+                let mut moving = Vec::new();
+                for (key, val) in pairs {
+                    let route = membership.route(&key)?;
+                    if let Some(previous) = route.previous {
+                        moving.push((previous, key.clone()));
+                    }
+                    batches.entry(route.owner).or_default().push((key, val));
+                }
+
+                let _moving = (!moving.is_empty()).then(|| self.moving.lock().expect(MUTEX_ERROR));
+                for (backend, pairs) in batches {
+                    debug!(pairs = pairs.len(), backend = backend, "Forwarding batch");
+                    Client::new(backend).set_many(&pairs)?;
+                }
+                for (previous, key) in moving {
+                    remove_if_present(previous, &key)?;
+                }
+                Ok(Response::ok("set successful"))
+            }
+            "SCAN" => {
+                let prefix = msg.next().unwrap_or_default().trim();
+                let (limit, after) = parse_scan_page(msg)?;
+
+                // Keys being moved may briefly be held by both owners, in which case the value
+                // held by the new owner is returned
+                let membership = self.membership.read().expect(RWLOCK_ERROR);
+                let _moving = membership
+                    .previous
+                    .is_some()
+                    .then(|| self.moving.lock().expect(MUTEX_ERROR));
+                // A page holds the first keys of every backend's page, as each backend returns
+                // its own first keys
+                let mut pairs = BTreeMap::new();
+                for backend in membership.nodes() {
+                    let client = Client::new(backend);
+                    let backend_pairs = match limit {
+                        Some(limit) => client.scan_page(prefix, after.as_deref(), limit)?,
+                        None => client.scan(prefix)?,
+                    };
+                    for (key, val) in backend_pairs {
+                        if membership.ring.node_for(&key) == Some(backend) {
+                            pairs.insert(key, val);
+                        } else {
+                            pairs.entry(key).or_insert(val);
+                        }
+                    }
+                }
+                Ok(Response::ok(serde_json::to_string(
+                    &pairs
+                        .into_iter()
+                        .take(limit.unwrap_or(usize::MAX))
+                        .collect::<Vec<_>>(),
+                )?))
+            }
+            "COMPACT" => {
+                let membership = self.membership.read().expect(RWLOCK_ERROR);
+                for backend in membership.nodes() {
+                    Client::new(backend).compact()?;
+                }
+                Ok(Response::ok("compaction successful"))
+            }
+            "INFO" => {
+                let membership = self.membership.read().expect(RWLOCK_ERROR);
+                let mut backends = BTreeMap::new();
+                for backend in membership.nodes() {
+                    backends.insert(backend.to_string(), Client::new(backend).info()?);
+                }
+                let server_info = ServerInfo {
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    uptime_secs: self.started_at.elapsed().as_secs(),
+                    connections_active: self.connections_active.load(Ordering::Relaxed),
+                    connections_total: self.connections_total.load(Ordering::Relaxed),
+                    engine: aggregate_stats(backends.values().map(|info| &info.engine)),
+                    replication: ReplicationInfo::Proxy { backends },
+                };
+                Ok(Response::ok(serde_json::to_string(&server_info)?))
+            }
+            "AUTH" => Ok(Response::new(Status::AuthError, AUTH_UNSUPPORTED_RESPONSE)),
+            "NODES" => {
+                let membership = self.membership.read().expect(RWLOCK_ERROR);
+                Ok(Response::ok(serde_json::to_string(
+                    membership.ring.nodes(),
+                )?))
+            }
+            "ADDNODE" | "RMNODE" if !admin => {
+                Ok(Response::new(Status::AuthError, MEMBERSHIP_DENIED_RESPONSE))
+            }
+            "ADDNODE" => {
+                let node = msg
+                    .next()
+                    .ok_or(HobbesError::CliError(String::from(
+                        "Missing node in ADDNODE command",
+                    )))?
+                    .trim();
+                self.add_node(node).map(Response::ok)
+            }
+            "RMNODE" => {
+                let node = msg
+                    .next()
+                    .ok_or(HobbesError::CliError(String::from(
+                        "Missing node in RMNODE command",
+                    )))?
+                    .trim();
+                self.remove_node(node).map(Response::ok)
+            }
+            cmd => {
+                error!(cmd = cmd, "Invalid command");
+                Ok(Response::error("Invalid command"))
+            }
+        }
+    }
+
+    // Adds a backend and moves the keys it now owns from the existing backends
+    fn add_node(&self, node: &str) -> Result<String> {
+        let _changing = self.changing.lock().expect(MUTEX_ERROR);
+        // Finishes a migration interrupted by an earlier failure
+        self.migrate()?;
+
+        {
+            let mut membership = self.membership.write().expect(RWLOCK_ERROR);
+            if membership.ring.contains(node) {
+                return Ok(format!("Node {node} already present"));
+            }
+            let mut ring = membership.ring.clone();
+            ring.add(node);
+            self.start_migration(&mut membership, ring)?;
+        }
+        info!(node = node, "Adding node, migrating keys");
+
+        let moved = self.migrate()?;
+        info!(node = node, migrated = moved, "Node added");
+        Ok(format!("Added node {node}, migrated {moved} keys"))
+    }
+
+    // Removes a backend and moves its keys to their new owners
+    fn remove_node(&self, node: &str) -> Result<String> {
+        let _changing = self.changing.lock().expect(MUTEX_ERROR);
+        self.migrate()?;
+
+        {
+            let mut membership = self.membership.write().expect(RWLOCK_ERROR);
+            if !membership.ring.contains(node) {
+                return Ok(format!("Node {node} not found"));
+            }
+            if membership.ring.nodes().len() == 1 {
+                return Ok(format!(
+                    "Node {node} is the only backend and cannot be removed"
+                ));
+            }
+            let mut ring = membership.ring.clone();
+            ring.remove(node);
+            self.start_migration(&mut membership, ring)?;
+        }
+        info!(node = node, "Removing node, migrating keys");
+
+        let moved = self.migrate()?;
+        info!(node = node, migrated = moved, "Node removed");
+        Ok(format!("Removed node {node}, migrated {moved} keys"))
+    }
+
+    // Routes keys by the new ring, persisting it along with the previous one before any key is
+    // moved, so that a restarted proxy still finds every key
+    fn start_migration(&self, membership: &mut Membership, ring: HashRing) -> Result<()> {
+        let previous = std::mem::replace(&mut membership.ring, ring);
+        membership.previous = Some(previous);
+        if let Err(e) = self.persist(membership) {
+            membership.ring = membership.previous.take().unwrap();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Moves every key whose owner changed to its new owner, without blocking requests for other
+    // keys, and returns the number of keys moved. On failure, the migration stays in progress and
+    // is resumed by the next membership change or restart.
+    fn migrate(&self) -> Result<usize> {
+        let (ring, previous) = {
+            let membership = self.membership.read().expect(RWLOCK_ERROR);
+            match &membership.previous {
+                Some(previous) => (membership.ring.clone(), previous.clone()),
+                None => return Ok(0),
+            }
+        };
+
+        // Keys are read a page at a time, so that the proxy never holds a backend's keyspace
+        let mut moved = 0;
+        for node in previous.nodes() {
+            let client = Client::new(node);
+            let mut after = None;
+            loop {
+                let page = client.scan_page("", after.as_deref(), MIGRATION_PAGE_SIZE)?;
+                let Some((last, _)) = page.last() else {
+                    break;
+                };
+                after = Some(last.clone());
+                for (key, _) in page {
+                    let owner = ring
+                        .node_for(&key)
+                        .ok_or(HobbesError::CliError(String::from("no backends available")))?;
+                    if previous.node_for(&key) != Some(node.as_str()) || owner == node {
+                        continue;
+                    }
+                    if self.move_key(&key, node, owner)? {
+                        moved += 1;
+                    }
+                }
+            }
+        }
+
+        let mut membership = self.membership.write().expect(RWLOCK_ERROR);
+        membership.previous = None;
+        self.persist(&membership)?;
+        Ok(moved)
+    }
+
+    // Copies a key to its new owner before deleting it from its previous one, returning false if
+    // the key was written or removed through the proxy since it was listed. Any response other
+    // than the value or a missing key fails the migration, leaving the key on its previous owner.
+    fn move_key(&self, key: &str, from: &str, to: &str) -> Result<bool> {
+        let _moving = self.moving.lock().expect(MUTEX_ERROR);
+        let Some(val) = Client::new(from).get(key)? else {
+            return Ok(false);
+        };
+        Client::new(to).set_many(&[(key.to_string(), val)])?;
+        if let Err(e) = remove_if_present(from, key) {
+            warn!(
+                node = from,
+                key = key,
+                "Failed to delete migrated key -> {e}"
+            );
+            return Err(e);
+        }
+        Ok(true)
+    }
+}
+
+fn proxy_handler(proxy: &Arc<Proxy>, mut tcp_stream: TcpStream, admin: bool) {
+    let cmd_str = match read_frame(&mut BufReader::new(&mut tcp_stream)) {
+        Ok(cmd_str) => cmd_str,
+        Err(e) => {
+            error!("Error while reading command from client -> {e}");
+            return;
+        }
+    };
+    debug!(request = cmd_str, "Read command from client request");
+
+    if let Some(args) = cmd_str.strip_prefix("WATCH\r\n") {
+        // A missing prefix watches every key
+        let prefix = args
+            .split(CRLF)
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        info!(cmd = "WATCH", prefix = prefix, "Received command");
+        // Watchers hold the connection open, so they are served on a dedicated thread
+        let proxy = proxy.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve_watcher(&proxy, &prefix, tcp_stream) {
+                debug!("Stopped streaming to watcher -> {e}");
+            }
+        });
+        return;
+    }
+
+    // Failures are reported with their own status, as servers do
+    let resp = match proxy.handle_cmd(&cmd_str, admin) {
+        Ok(resp) => resp,
+        Err(HobbesError::AuthError(e)) => Response::new(Status::AuthError, e),
+        Err(e) => {
+            error!("Failed to handle request = {cmd_str}, error = {e}");
+            Response::error(e.to_string())
+        }
+    };
+
+    let mut writer = BufWriter::new(&tcp_stream);
+    if let Err(e) = writer
+        .write_all(resp.encode().as_bytes())
+        .and_then(|_| writer.flush())
+    {
+        error!("Error while writing response to client -> {e}");
+    }
+}
+
+// Removes a key from a backend, returning false if it was absent
+fn remove_if_present(backend: &str, key: &str) -> Result<bool> {
+    match Client::new(backend).remove(key) {
+        Ok(_) => Ok(true),
+        Err(HobbesError::KeyNotFoundError) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Subscribes to the prefix on every backend, streaming their events to the client as a server
+// would. Events from different backends are interleaved, each carrying its backend's sequence
+// number. The stream ends once any backend ends its subscription.
+fn serve_watcher(proxy: &Proxy, prefix: &str, tcp_stream: TcpStream) -> Result<()> {
+    let mut writer = BufWriter::new(tcp_stream);
+    let mut streams = Vec::new();
+    {
+        let membership = proxy.membership.read().expect(RWLOCK_ERROR);
+        for backend in membership.nodes() {
+            match Client::new(backend).watch(prefix) {
+                Ok(stream) => streams.push(stream),
+                Err(e) => {
+                    writer.write_all(Response::error(e.to_string()).encode().as_bytes())?;
+                    writer.flush()?;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    // Heartbeats are forwarded along with events, so that forwarders notice the client
+    // disconnecting even while no keys change
+    let (tx, rx) = channel::unbounded();
+    for mut stream in streams {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            while let Some(Ok(event)) = stream.next_line() {
+                if tx.send(Some(event)).is_err() {
+                    return;
+                }
+            }
+            let _ = tx.send(None);
+        });
+    }
+    drop(tx);
+
+    writer.write_all(Response::ok("").encode().as_bytes())?;
+    writer.flush()?;
+    loop {
+        match rx.recv_timeout(WATCH_HEARTBEAT_INTERVAL) {
+            Ok(Some(Some(event))) => {
+                serde_json::to_writer(&mut writer, &event)?;
+                writer.write_all(b"\n")?;
+                if rx.is_empty() {
+                    writer.flush()?;
+                }
+            }
+            Ok(Some(None)) | Err(RecvTimeoutError::Timeout) => {
+                writer.write_all(b"\n")?;
+                writer.flush()?;
+            }
+            Ok(None) | Err(RecvTimeoutError::Disconnected) => {
+                return Err(HobbesError::WatchError(String::from(
+                    "backend ended the subscription",
+                )))
+            }
+        }
+    }
+}
+
+// Sums the statistics of the backends' engines, weighting the dead bytes ratio by size
+fn aggregate_stats<'a>(backends: impl Iterator<Item = &'a EngineStats>) -> EngineStats {
+    let mut engines: Vec<&str> = Vec::new();
+    let mut stats = EngineStats {
+        engine: String::new(),
+        keys: 0,
+        segments: 0,
+        segments_size: 0,
+        dead_bytes_ratio: 0.0,
+        index_bytes: None,
+        compactions: 0,
+        last_compaction_at: None,
+        last_compaction_ms: None,
+    };
+    let mut dead_bytes = 0.0;
+    for backend in backends {
+        if !engines.contains(&backend.engine.as_str()) {
+            engines.push(&backend.engine);
+        }
+        stats.keys += backend.keys;
+        stats.segments += backend.segments;
+        stats.segments_size += backend.segments_size;
+        dead_bytes += backend.dead_bytes_ratio * backend.segments_size as f64;
+        if let Some(index_bytes) = backend.index_bytes {
+            stats.index_bytes = Some(stats.index_bytes.unwrap_or(0) + index_bytes);
+        }
+        stats.compactions += backend.compactions;
+        if backend.last_compaction_at > stats.last_compaction_at {
+            stats.last_compaction_at = backend.last_compaction_at;
+            stats.last_compaction_ms = backend.last_compaction_ms;
+        }
+    }
+    stats.engine = engines.join(",");
+    if stats.segments_size > 0 {
+        stats.dead_bytes_ratio = dead_bytes / stats.segments_size as f64;
+    }
+    stats
+}
@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// HashRing maps keys to nodes using consistent hashing, placing each node at several points
+/// on the ring so that keys are spread evenly and only move to or from a node when it joins
+/// or leaves
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    virtual_nodes: u32,
+    ring: BTreeMap<u64, String>,
+    nodes: Vec<String>,
+}
+
+impl HashRing {
+    /// Create an empty ring placing each node at virtual_nodes points
+    pub fn new(virtual_nodes: u32) -> HashRing {
+        HashRing {
+            virtual_nodes: virtual_nodes.max(1),
+            ring: BTreeMap::new(),
+            nodes: Vec::new(),
+        }
+    }
+
+    /// Add a node to the ring, returning false if it was already present
+    pub fn add(&mut self, node: &str) -> bool {
+        if self.contains(node) {
+            return false;
+        }
+
+        for vnode in 0..self.virtual_nodes {
+            self.ring
+                .insert(hash(&format!("{node}#{vnode}")), node.to_string());
+        }
+        self.nodes.push(node.to_string());
+        true
+    }
+
+    /// Remove a node from the ring, returning false if it was absent
+    pub fn remove(&mut self, node: &str) -> bool {
+        if !self.contains(node) {
+            return false;
+        }
+
+        self.ring.retain(|_, ring_node| ring_node != node);
+        self.nodes.retain(|ring_node| ring_node != node);
+        true
+    }
+
+    pub fn contains(&self, node: &str) -> bool {
+        self.nodes.iter().any(|ring_node| ring_node == node)
+    }
+
+    /// Return the node owning the key, or None if the ring is empty
+    pub fn node_for(&self, key: &str) -> Option<&str> {
+        let key_hash = hash(key);
+        self.ring
+            .range(key_hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    /// Nodes in the order they were added
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    pub fn virtual_nodes(&self) -> u32 {
+        self.virtual_nodes
+    }
+}
+
+// FNV-1a is used over the standard library's hasher as its output must remain stable across
+// processes and releases, so that every proxy agrees on key ownership. The result is mixed
+// with the MurmurHash3 finalizer, as FNV alone clusters similar inputs such as virtual node
+// labels on the ring.
+fn hash(key: &str) -> u64 {
+    let mut hash = key.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
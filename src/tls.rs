@@ -0,0 +1,255 @@
+//! Optional TLS for connections to and between hobbes servers, using rustls
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    ClientConnection, ConnectionCommon, RootCertStore, ServerConnection, SideData, StreamOwned,
+};
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::ops::DerefMut;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{HobbesError, Result};
+
+// Peers not completing the handshake within this duration, such as plaintext clients, are
+// disconnected instead of occupying a worker
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// PEM files used by a server to accept TLS connections
+#[derive(Debug, Clone)]
+pub struct ServerTlsConfig {
+    /// Certificate chain presented to clients
+    pub cert: PathBuf,
+    /// Private key of the certificate
+    pub key: PathBuf,
+    /// CA verifying client certificates. When set, clients must present a certificate signed
+    /// by it
+    pub client_ca: Option<PathBuf>,
+}
+
+/// TlsAcceptor wraps accepted connections in TLS
+#[derive(Debug, Clone)]
+pub struct TlsAcceptor {
+    config: Arc<rustls::ServerConfig>,
+}
+
+impl TlsAcceptor {
+    pub fn new(tls_config: &ServerTlsConfig) -> Result<TlsAcceptor> {
+        let builder = rustls::ServerConfig::builder();
+        let builder = match &tls_config.client_ca {
+            Some(client_ca) => builder.with_client_cert_verifier(
+                WebPkiClientVerifier::builder(Arc::new(load_roots(client_ca)?))
+                    .build()
+                    .map_err(|e| HobbesError::TlsError(e.to_string()))?,
+            ),
+            None => builder.with_no_client_auth(),
+        };
+        let config =
+            builder.with_single_cert(load_certs(&tls_config.cert)?, load_key(&tls_config.key)?)?;
+
+        Ok(TlsAcceptor {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Perform the TLS handshake on an accepted connection
+    pub fn accept(&self, mut tcp_stream: TcpStream) -> Result<Stream> {
+        let mut conn = ServerConnection::new(self.config.clone())?;
+        handshake(&mut conn, &mut tcp_stream)?;
+        Ok(Stream::TlsServer(Box::new(StreamOwned::new(
+            conn, tcp_stream,
+        ))))
+    }
+}
+
+/// TlsConnector opens TLS connections to servers whose certificates are signed by a CA
+#[derive(Debug, Clone)]
+pub struct TlsConnector {
+    config: Arc<rustls::ClientConfig>,
+}
+
+impl TlsConnector {
+    /// Create a connector trusting the CA, presenting the certificate and key in identity to
+    /// servers which verify clients
+    pub fn new(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<TlsConnector> {
+        let builder = rustls::ClientConfig::builder().with_root_certificates(load_roots(ca)?);
+        let config = match identity {
+            Some((cert, key)) => {
+                builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(TlsConnector {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Perform the TLS handshake on a connection to the address, verifying the server
+    /// certificate against its host
+    pub fn connect(&self, addr: &str, mut tcp_stream: TcpStream) -> Result<Stream> {
+        let host = addr
+            .rsplit_once(':')
+            .map_or(addr, |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|e| HobbesError::TlsError(format!("invalid server name {host}, {e}")))?;
+
+        let mut conn = ClientConnection::new(self.config.clone(), server_name)?;
+        handshake(&mut conn, &mut tcp_stream)?;
+        Ok(Stream::TlsClient(Box::new(StreamOwned::new(
+            conn, tcp_stream,
+        ))))
+    }
+}
+
+/// A connection between a client and a server, encrypted if TLS is enabled
+#[derive(Debug)]
+pub enum Stream {
+    Plain(TcpStream),
+    TlsServer(Box<StreamOwned<ServerConnection, TcpStream>>),
+    TlsClient(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// Connect to the address, over TLS if a connector is provided
+    pub fn connect(addr: &str, tls: Option<&TlsConnector>) -> Result<Stream> {
+        let tcp_stream = TcpStream::connect(addr)?;
+        match tls {
+            Some(connector) => connector.connect(addr, tcp_stream),
+            None => Ok(Stream::Plain(tcp_stream)),
+        }
+    }
+
+    /// Connect to the address, failing if the connection is not established within the timeout
+    pub fn connect_timeout(
+        addr: &str,
+        tls: Option<&TlsConnector>,
+        timeout: Duration,
+    ) -> Result<Stream> {
+        let socket_addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or(HobbesError::NetworkError(format!(
+                "failed to resolve address {addr}"
+            )))?;
+        let tcp_stream = TcpStream::connect_timeout(&socket_addr, timeout)?;
+        // Bounds the TLS handshake by the same timeout
+        tcp_stream.set_read_timeout(Some(timeout))?;
+        match tls {
+            Some(connector) => connector.connect(addr, tcp_stream),
+            None => Ok(Stream::Plain(tcp_stream)),
+        }
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(self.tcp_stream().peer_addr()?)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.tcp_stream().set_read_timeout(timeout)?)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.tcp_stream().set_write_timeout(timeout)?)
+    }
+
+    fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(tcp_stream) => tcp_stream,
+            Stream::TlsServer(tls_stream) => tls_stream.get_ref(),
+            Stream::TlsClient(tls_stream) => tls_stream.get_ref(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp_stream) => tcp_stream.read(buf),
+            Stream::TlsServer(tls_stream) => tls_stream.read(buf),
+            Stream::TlsClient(tls_stream) => tls_stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp_stream) => tcp_stream.write(buf),
+            Stream::TlsServer(tls_stream) => tls_stream.write(buf),
+            Stream::TlsClient(tls_stream) => tls_stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(tcp_stream) => tcp_stream.flush(),
+            Stream::TlsServer(tls_stream) => tls_stream.flush(),
+            Stream::TlsClient(tls_stream) => tls_stream.flush(),
+        }
+    }
+}
+
+impl Drop for Stream {
+    // Responses are delimited by the end of the connection, so TLS connections are closed with
+    // a close_notify alert, letting the peer tell a complete response from a truncated one
+    fn drop(&mut self) {
+        match self {
+            Stream::Plain(_) => return,
+            Stream::TlsServer(tls_stream) => tls_stream.conn.send_close_notify(),
+            Stream::TlsClient(tls_stream) => tls_stream.conn.send_close_notify(),
+        }
+        let _ = self.flush();
+    }
+}
+
+fn handshake<C, S>(conn: &mut C, tcp_stream: &mut TcpStream) -> Result<()>
+where
+    C: DerefMut<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    let read_timeout = tcp_stream.read_timeout()?;
+    tcp_stream.set_read_timeout(Some(
+        read_timeout.map_or(HANDSHAKE_TIMEOUT, |timeout| timeout.min(HANDSHAKE_TIMEOUT)),
+    ))?;
+    while conn.is_handshaking() {
+        conn.complete_io(tcp_stream)?;
+    }
+    tcp_stream.set_read_timeout(read_timeout)?;
+    Ok(())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| {
+            HobbesError::TlsError(format!("failed to read certificates from {path:?}, {e}"))
+        })?;
+    if certs.is_empty() {
+        Err(HobbesError::TlsError(format!(
+            "no certificates found in {path:?}"
+        )))?
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| {
+        HobbesError::TlsError(format!("failed to read private key from {path:?}, {e}"))
+    })
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
@@ -1,8 +1,18 @@
 #![allow(clippy::needless_borrows_for_generic_args)]
 
 use assert_cmd::prelude::*;
+use hobbes::auth::{hash_secret, Authenticator};
+use hobbes::client::Client;
+use hobbes::engine::bitcask::{BitcaskEngine, Keyring, SegmentReader};
+use hobbes::engine::{Engine, ReplicationInfo};
+use hobbes::tls::TlsConnector;
+use hobbes::HobbesError;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
-use std::process::Command;
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -185,6 +195,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server process");
 
         let mut cmd = Command::cargo_bin("hobbes-server").unwrap();
         cmd.args(&["--engine", "bitcask", "--addr", "127.0.0.1:4003"])
@@ -204,6 +215,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server process");
 
         let mut cmd = Command::cargo_bin("hobbes-server").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -225,6 +237,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server process");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -305,6 +318,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server process");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -326,6 +340,1570 @@ fn cli_access_server(engine: &str, addr: &str) {
     handle.join().unwrap();
 }
 
+#[test]
+fn cli_info() {
+    let addr = "127.0.0.1:4006";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("hobbes-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "bitcask", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server process");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    for key in ["key1", "key2"] {
+        Command::cargo_bin("hobbes")
+            .unwrap()
+            .args(&["--addr", addr, "set", key, "value"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", addr, "info"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("engine: bitcask"))
+        .stdout(contains("keys: 2"))
+        .stdout(contains(format!("version: {}", env!("CARGO_PKG_VERSION"))));
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", addr, "info", "--json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"engine\":\"bitcask\""))
+        .stdout(contains("\"keys\":2"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_replication() {
+    let leader_addr = "127.0.0.1:4007";
+    let follower_addr = "127.0.0.1:4008";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+
+    let mut leader = Command::cargo_bin("hobbes-server")
+        .unwrap()
+        .args(&["--addr", leader_addr])
+        .current_dir(&leader_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // Written before the follower connects, received through the snapshot
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", leader_addr, "set", "key1", "value1"])
+        .current_dir(&leader_dir)
+        .assert()
+        .success();
+
+    let mut follower = Command::cargo_bin("hobbes-server")
+        .unwrap()
+        .args(&["--addr", follower_addr, "--replica-of", leader_addr])
+        .current_dir(&follower_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        follower.kill().expect("follower exited before killed");
+        follower.wait().expect("failed to wait on follower process");
+        leader.kill().expect("leader exited before killed");
+        leader.wait().expect("failed to wait on leader process");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", follower_addr, "get", "key1"])
+        .current_dir(&follower_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // Written after the follower connects, received through the stream
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", leader_addr, "set", "key2", "value2"])
+        .current_dir(&leader_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", leader_addr, "rm", "key1"])
+        .current_dir(&leader_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(500));
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", follower_addr, "get", "key2"])
+        .current_dir(&follower_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", follower_addr, "get", "key1"])
+        .current_dir(&follower_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    // Followers reject writes
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", follower_addr, "set", "key3", "value3"])
+        .current_dir(&follower_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Read-only replica"));
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", follower_addr, "info"])
+        .current_dir(&follower_dir)
+        .assert()
+        .success()
+        .stdout(contains("role: follower"))
+        .stdout(contains("connected: true"))
+        .stdout(contains("lag_entries: 0"));
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", leader_addr, "info"])
+        .current_dir(&leader_dir)
+        .assert()
+        .success()
+        .stdout(contains("role: leader"))
+        .stdout(contains("followers: 1"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// A follower reconnecting after a multi-page snapshot should drop the keys removed on the
+// leader while it was disconnected, and keep the others
+#[test]
+fn cli_replication_resync() {
+    let leader_addr = "127.0.0.1:4030";
+    let follower_addr = "127.0.0.1:4031";
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let start_follower = || {
+        Command::cargo_bin("hobbes-server")
+            .unwrap()
+            .args(&["--addr", follower_addr, "--replica-of", leader_addr])
+            .current_dir(&follower_dir)
+            .spawn()
+            .unwrap()
+    };
+    let wait_for_sync = |expected: &[(String, String)]| {
+        let deadline = std::time::Instant::now() + Duration::from_secs(20);
+        while Client::new(follower_addr).scan("").ok().as_deref() != Some(expected) {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "follower did not converge on the leader"
+            );
+            thread::sleep(Duration::from_millis(200));
+        }
+    };
+
+    let mut leader = Command::cargo_bin("hobbes-server")
+        .unwrap()
+        .args(&["--addr", leader_addr])
+        .current_dir(&leader_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let leader_client = Client::new(leader_addr);
+    let pairs = (0..2500)
+        .map(|i| (format!("key{i:04}"), format!("value{i}")))
+        .collect::<Vec<_>>();
+    leader_client.set_many(&pairs).unwrap();
+
+    let mut follower = start_follower();
+    wait_for_sync(&pairs);
+    follower.kill().expect("follower exited before killed");
+    follower.wait().expect("failed to wait on follower process");
+
+    // Removed keys are spread over every page of the snapshot, including the last
+    for (key, _) in pairs.iter().step_by(3) {
+        leader_client.remove(key).unwrap();
+    }
+    leader_client.set("key9999", "added").unwrap();
+
+    let mut follower = start_follower();
+    wait_for_sync(&leader_client.scan("").unwrap());
+
+    follower.kill().expect("follower exited before killed");
+    follower.wait().expect("failed to wait on follower process");
+    leader.kill().expect("leader exited before killed");
+    leader.wait().expect("failed to wait on leader process");
+}
+
+fn cluster_leader(addrs: &[&str]) -> Option<usize> {
+    addrs.iter().position(|addr| {
+        let output = Command::cargo_bin("hobbes")
+            .unwrap()
+            .args(&["--addr", addr, "info"])
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).contains("state: leader")
+    })
+}
+
+#[test]
+fn cli_cluster() {
+    let addrs = ["127.0.0.1:4011", "127.0.0.1:4012", "127.0.0.1:4013"];
+    let cluster = format!("1={},2={},3={}", addrs[0], addrs[1], addrs[2]);
+    let temp_dirs = [
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+    ];
+
+    let mut nodes = Vec::new();
+    for (i, addr) in addrs.iter().enumerate() {
+        let node_id = (i + 1).to_string();
+        let child = Command::cargo_bin("hobbes-server")
+            .unwrap()
+            .args(&["--addr", addr, "--node-id", &node_id, "--cluster", &cluster])
+            .current_dir(&temp_dirs[i])
+            .spawn()
+            .unwrap();
+        nodes.push(child);
+    }
+    thread::sleep(Duration::from_secs(3));
+
+    let leader = cluster_leader(&addrs).expect("no leader elected");
+
+    // Any node accepts commands, redirecting the client to the leader
+    for addr in addrs {
+        Command::cargo_bin("hobbes")
+            .unwrap()
+            .args(&["--addr", addr, "set", "key1", "value1"])
+            .assert()
+            .success()
+            .stdout(is_empty());
+        Command::cargo_bin("hobbes")
+            .unwrap()
+            .args(&["--addr", addr, "get", "key1"])
+            .assert()
+            .success()
+            .stdout("value1\n");
+    }
+
+    // Kill the leader and wait for the remaining nodes to elect a new one
+    nodes[leader].kill().expect("leader exited before killed");
+    nodes[leader]
+        .wait()
+        .expect("failed to wait on leader process");
+    thread::sleep(Duration::from_secs(3));
+
+    let remaining = addrs
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != leader)
+        .map(|(_, addr)| *addr)
+        .collect::<Vec<&str>>();
+    assert!(
+        cluster_leader(&remaining).is_some(),
+        "no new leader elected"
+    );
+
+    for addr in &remaining {
+        Command::cargo_bin("hobbes")
+            .unwrap()
+            .args(&["--addr", addr, "get", "key1"])
+            .assert()
+            .success()
+            .stdout("value1\n");
+    }
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", remaining[0], "set", "key2", "value2"])
+        .assert()
+        .success();
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", remaining[1], "get", "key2"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", remaining[1], "rm", "key1"])
+        .assert()
+        .success();
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", remaining[0], "get", "key1"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    for (i, node) in nodes.iter_mut().enumerate() {
+        if i != leader {
+            node.kill().expect("node exited before killed");
+            node.wait().expect("failed to wait on node process");
+        }
+    }
+}
+
+// A node rejoining after the log was compacted should catch up from a snapshot sent in chunks,
+// dropping the keys removed meanwhile
+#[test]
+fn cli_cluster_snapshot() {
+    let addrs = ["127.0.0.1:4027", "127.0.0.1:4028", "127.0.0.1:4029"];
+    let cluster = format!("1={},2={},3={}", addrs[0], addrs[1], addrs[2]);
+    let temp_dirs = [
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+    ];
+    let start_node = |i: usize| {
+        Command::cargo_bin("hobbes-server")
+            .unwrap()
+            .args(&["--addr", addrs[i], "--node-id", &(i + 1).to_string()])
+            .args(&["--cluster", &cluster])
+            .current_dir(&temp_dirs[i])
+            .spawn()
+            .unwrap()
+    };
+
+    let mut nodes = vec![start_node(0), start_node(1), start_node(2)];
+    thread::sleep(Duration::from_secs(3));
+
+    // Stored by the third node before it stops, and removed while it is down, so that the
+    // snapshot it later receives must remove it
+    let client = Client::new(addrs[0]);
+    client.set("key1500stale", "stale").unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let mut third_node = nodes.pop().unwrap();
+    third_node.kill().expect("node exited before killed");
+    third_node.wait().expect("failed to wait on node process");
+    thread::sleep(Duration::from_secs(3));
+    client.remove("key1500stale").unwrap();
+
+    // Enough pairs for several chunks, then enough single writes to compact the log, as each
+    // MSET batch is a single entry
+    let pairs = (0..3000)
+        .map(|i| (format!("key{i}"), format!("value{i}")))
+        .collect::<Vec<(String, String)>>();
+    for batch in pairs.chunks(500) {
+        client.set_many(batch).unwrap();
+    }
+    for (key, val) in &pairs[..1200] {
+        client.set(key, val).unwrap();
+    }
+    let ReplicationInfo::Cluster(leader_info) = client.info().unwrap().replication else {
+        panic!("expected a cluster node");
+    };
+
+    nodes.push(start_node(2));
+    let deadline = std::time::Instant::now() + Duration::from_secs(20);
+    let cluster_info = loop {
+        if let Ok(info) = Client::new(addrs[2]).info() {
+            match info.replication {
+                ReplicationInfo::Cluster(cluster_info)
+                    if cluster_info.last_applied >= leader_info.last_applied =>
+                {
+                    break cluster_info
+                }
+                _ => {}
+            }
+        }
+        assert!(
+            std::time::Instant::now() < deadline,
+            "joining node did not catch up"
+        );
+        thread::sleep(Duration::from_millis(200));
+    };
+    assert!(cluster_info.snapshot_index > 0);
+
+    for node in nodes.iter_mut() {
+        node.kill().expect("node exited before killed");
+        node.wait().expect("failed to wait on node process");
+    }
+    let store = BitcaskEngine::open(temp_dirs[2].path()).unwrap();
+    assert_eq!(store.scan(String::new()).unwrap(), {
+        let mut pairs = pairs;
+        pairs.sort();
+        pairs
+    });
+}
+
+// Changes to watched keys are streamed to the library subscriber and `hobbes watch`
+#[test]
+fn cli_watch() {
+    let addr = "127.0.0.1:4009";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+
+    let mut server = Command::cargo_bin("hobbes-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        server.kill().expect("server exited before killed");
+        server.wait().expect("failed to wait on server process");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut watch_cmd = Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", addr, "watch", "user:"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut events = Client::new(addr).watch("user:").unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    let client = Client::new(addr);
+    client.set("user:1", "value1").unwrap();
+    client.set("order:1", "value2").unwrap();
+    client.remove("user:1").unwrap();
+
+    let event = events.next().unwrap().unwrap();
+    assert_eq!(
+        (event.seq, event.key.as_str(), event.val.as_deref()),
+        (1, "user:1", Some("value1"))
+    );
+    let event = events.next().unwrap().unwrap();
+    assert_eq!(
+        (event.seq, event.key.as_str(), event.val),
+        (3, "user:1", None)
+    );
+
+    thread::sleep(Duration::from_millis(500));
+    watch_cmd.kill().expect("watch exited before killed");
+    let output = watch_cmd.wait_with_output().unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "1 SET user:1 value1\n3 RM user:1\n"
+    );
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// Writes a CA along with server and client certificates signed by it, returning their paths
+fn generate_certs(dir: &Path) -> [PathBuf; 5] {
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+
+    let server_key = KeyPair::generate().unwrap();
+    let server_cert = CertificateParams::new(vec!["127.0.0.1".to_owned(), "localhost".to_owned()])
+        .unwrap()
+        .signed_by(&server_key, &ca_cert, &ca_key)
+        .unwrap();
+    let client_key = KeyPair::generate().unwrap();
+    let client_cert = CertificateParams::new(vec!["client".to_owned()])
+        .unwrap()
+        .signed_by(&client_key, &ca_cert, &ca_key)
+        .unwrap();
+
+    let files = [
+        ("ca.pem", ca_cert.pem()),
+        ("server.pem", server_cert.pem()),
+        ("server.key", server_key.serialize_pem()),
+        ("client.pem", client_cert.pem()),
+        ("client.key", client_key.serialize_pem()),
+    ];
+    files.map(|(name, contents)| {
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    })
+}
+
+// Clients must use TLS, verifying the server certificate, to access a TLS server
+#[test]
+fn cli_tls() {
+    let addr = "127.0.0.1:4010";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let [ca, server_cert, server_key, ..] = generate_certs(temp_dir.path());
+
+    let mut server = Command::cargo_bin("hobbes-server")
+        .unwrap()
+        .args(&["--addr", addr, "--tls-cert"])
+        .arg(&server_cert)
+        .arg("--tls-key")
+        .arg(&server_key)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        server.kill().expect("server exited before killed");
+        server.wait().expect("failed to wait on server process");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", addr, "--tls-ca"])
+        .arg(&ca)
+        .args(&["set", "key1", "value1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", addr, "--tls-ca"])
+        .arg(&ca)
+        .args(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // Plaintext clients are disconnected without a response
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", addr, "get", "key1"])
+        .assert()
+        .stdout(contains("value1").not());
+
+    // Servers whose certificates are signed by an unknown CA are rejected
+    let other_dir = TempDir::new().unwrap();
+    let [other_ca, ..] = generate_certs(other_dir.path());
+    let client = Client::new(addr).with_tls(TlsConnector::new(&other_ca, None).unwrap());
+    assert!(client.get("key1").is_err());
+
+    let client = Client::new(addr).with_tls(TlsConnector::new(&ca, None).unwrap());
+    assert_eq!(client.get("key1").unwrap(), Some("value1".to_owned()));
+    let mut events = client.watch("key").unwrap();
+    client.set("key2", "value2").unwrap();
+    assert_eq!(events.next().unwrap().unwrap().key, "key2");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// Servers verifying clients reject clients without a certificate signed by the client CA
+#[test]
+fn cli_mutual_tls() {
+    let addr = "127.0.0.1:4014";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let [ca, server_cert, server_key, client_cert, client_key] = generate_certs(temp_dir.path());
+
+    let mut server = Command::cargo_bin("hobbes-server")
+        .unwrap()
+        .args(&["--addr", addr, "--tls-cert"])
+        .arg(&server_cert)
+        .arg("--tls-key")
+        .arg(&server_key)
+        .arg("--tls-client-ca")
+        .arg(&ca)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        server.kill().expect("server exited before killed");
+        server.wait().expect("failed to wait on server process");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", addr, "--tls-ca"])
+        .arg(&ca)
+        .args(&["set", "key1", "value1"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", addr, "--tls-ca"])
+        .arg(&ca)
+        .arg("--tls-cert")
+        .arg(&client_cert)
+        .arg("--tls-key")
+        .arg(&client_key)
+        .args(&["set", "key1", "value1"])
+        .assert()
+        .success();
+
+    let client = Client::new(addr)
+        .with_tls(TlsConnector::new(&ca, Some((&client_cert, &client_key))).unwrap());
+    assert_eq!(client.get("key1").unwrap(), Some("value1".to_owned()));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// Servers with a users file require clients to authenticate, restricting each user to its role
+// and key prefixes
+#[test]
+fn cli_auth() {
+    let addr = "127.0.0.1:4015";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let users = temp_dir.path().join("users.toml");
+    fs::write(
+        &users,
+        format!(
+            r#"
+[[users]]
+name = "admin"
+password_hash = "{}"
+role = "admin"
+
+[[users]]
+name = "reader"
+password_hash = "{}"
+role = "read-only"
+
+[[users]]
+name = "orders"
+password_hash = "{}"
+token_hash = "{}"
+role = "read-write"
+prefixes = ["orders:"]
+"#,
+            hash_secret("admin-pass").unwrap(),
+            hash_secret("reader-pass").unwrap(),
+            hash_secret("orders-pass").unwrap(),
+            hash_secret("orders-token").unwrap(),
+        ),
+    )
+    .unwrap();
+
+    let mut server = Command::cargo_bin("hobbes-server")
+        .unwrap()
+        .args(&["--addr", addr, "--users"])
+        .arg(&users)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        server.kill().expect("server exited before killed");
+        server.wait().expect("failed to wait on server process");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", addr, "get", "orders:1"])
+        .assert()
+        .failure()
+        .stderr(contains("Authentication required"));
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", addr, "--user", "admin", "--password", "wrong"])
+        .args(&["get", "orders:1"])
+        .assert()
+        .failure()
+        .stderr(contains("Authentication failed"));
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", addr, "--user", "admin"])
+        .env("HOBBES_PASSWORD", "admin-pass")
+        .args(&["set", "users:1", "alice"])
+        .assert()
+        .success();
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&[
+            "--addr",
+            addr,
+            "--user",
+            "reader",
+            "--password",
+            "reader-pass",
+        ])
+        .args(&["get", "users:1"])
+        .assert()
+        .success()
+        .stdout("alice\n");
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&[
+            "--addr",
+            addr,
+            "--user",
+            "reader",
+            "--password",
+            "reader-pass",
+        ])
+        .args(&["set", "users:1", "bob"])
+        .assert()
+        .failure()
+        .stderr(contains("Permission denied"));
+
+    let orders = Client::new(addr).with_credentials("orders", "orders-token");
+    orders.set("orders:1", "pending").unwrap();
+    assert_eq!(orders.get("orders:1").unwrap(), Some("pending".to_owned()));
+
+    // Values are never mistaken for redirects or errors, which would send the credentials to
+    // another address
+    for val in ["Redirect 127.0.0.1:1", "Permission denied", "Key not found"] {
+        orders.set("orders:2", val).unwrap();
+        assert_eq!(orders.get("orders:2").unwrap(), Some(val.to_owned()));
+    }
+    orders.set("orders:2", "Redirect 127.0.0.1:1").unwrap();
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&[
+            "--addr",
+            addr,
+            "--user",
+            "orders",
+            "--password",
+            "orders-pass",
+        ])
+        .args(&["get", "orders:2"])
+        .assert()
+        .success()
+        .stdout("Redirect 127.0.0.1:1\n");
+    orders.remove("orders:2").unwrap();
+    assert!(matches!(
+        orders.get("users:1"),
+        Err(HobbesError::AuthError(_))
+    ));
+    assert!(matches!(orders.scan(""), Err(HobbesError::AuthError(_))));
+    assert_eq!(
+        orders.scan("orders:").unwrap(),
+        vec![("orders:1".to_owned(), "pending".to_owned())]
+    );
+    assert!(matches!(orders.watch(""), Err(HobbesError::AuthError(_))));
+
+    // Imports are sent as batches, whose keys must all be within the user's prefixes
+    let pairs_path = temp_dir.path().join("orders.jsonl");
+    fs::write(
+        &pairs_path,
+        "{\"key\":\"orders:2\",\"value\":\"shipped\"}\n{\"key\":\"orders:3\",\"value\":\"pending\"}\n",
+    )
+    .unwrap();
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&[
+            "--addr",
+            addr,
+            "--user",
+            "orders",
+            "--password",
+            "orders-pass",
+        ])
+        .arg("import")
+        .arg(&pairs_path)
+        .assert()
+        .success()
+        .stderr(contains("imported 2 pairs in total"));
+    assert_eq!(orders.get("orders:3").unwrap(), Some("pending".to_owned()));
+
+    fs::write(
+        &pairs_path,
+        "{\"key\":\"orders:4\",\"value\":\"pending\"}\n{\"key\":\"users:2\",\"value\":\"bob\"}\n",
+    )
+    .unwrap();
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&[
+            "--addr",
+            addr,
+            "--user",
+            "orders",
+            "--password",
+            "orders-pass",
+        ])
+        .arg("import")
+        .arg(&pairs_path)
+        .assert()
+        .failure()
+        .stderr(contains("Permission denied"));
+    assert_eq!(orders.get("orders:4").unwrap(), None);
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    // Secrets are hashed from stdin, keeping them out of the process arguments
+    let output = assert_cmd::Command::cargo_bin("hobbes")
+        .unwrap()
+        .arg("hash-password")
+        .write_stdin("new-pass\n")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    fs::write(
+        &users,
+        format!(
+            "[[users]]\nname = \"new\"\npassword_hash = \"{}\"\nrole = \"admin\"\n",
+            String::from_utf8(output.stdout).unwrap().trim()
+        ),
+    )
+    .unwrap();
+    let authenticator = Authenticator::load(&users).unwrap();
+    assert!(authenticator.authenticate("new", "new-pass").is_some());
+    assert!(authenticator.authenticate("new", "new-pass\n").is_none());
+    assert_cmd::Command::cargo_bin("hobbes")
+        .unwrap()
+        .arg("hash-password")
+        .write_stdin("")
+        .assert()
+        .failure();
+}
+
+// `hobbes.toml` in the working directory configures the server, with flags taking precedence
+#[test]
+fn cli_config_file() {
+    let addr = "127.0.0.1:4016";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("hobbes.toml"),
+        r#"
+addr = "127.0.0.1:4017"
+data_dir = "data"
+threads = 2
+log_level = "debug"
+
+[bitcask]
+segment_size = 4096
+compaction_threshold = 4096
+fsync = "always"
+compression = "lz4"
+"#,
+    )
+    .unwrap();
+
+    let mut server = Command::cargo_bin("hobbes-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        server.kill().expect("server exited before killed");
+        server.wait().expect("failed to wait on server process");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", addr, "set", "key1", "value1"])
+        .assert()
+        .success();
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", addr, "get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    assert!(temp_dir.path().join("data").join("bitcask-store").exists());
+    assert!(!temp_dir.path().join("bitcask-store").exists());
+
+    // Unknown settings are rejected
+    fs::write(
+        temp_dir.path().join("invalid.toml"),
+        "adress = \"127.0.0.1:4017\"\n",
+    )
+    .unwrap();
+    Command::cargo_bin("hobbes-server")
+        .unwrap()
+        .args(&["--config", "invalid.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown field"));
+}
+
+// A second server using the same data directory should exit while the first holds its lock
+#[test]
+fn cli_data_dir_lock() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+
+    let mut first = Command::cargo_bin("hobbes-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4018", "--data-dir"])
+        .arg(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("hobbes-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4019", "--data-dir"])
+        .arg(&data_dir)
+        .assert()
+        .failure()
+        .stderr(contains("DirectoryLockedError"))
+        .stderr(contains(first.id().to_string()));
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4018", "set", "key1", "value1"])
+        .assert()
+        .success();
+
+    // The lock is released when the server exits
+    first.kill().expect("server exited before killed");
+    first.wait().expect("failed to wait on server process");
+    let mut second = Command::cargo_bin("hobbes-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4019", "--data-dir"])
+        .arg(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4019", "get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    second.kill().expect("server exited before killed");
+    second.wait().expect("failed to wait on server process");
+}
+
+// `hobbes-admin check` should report a corrupt tail and fail until repaired
+#[test]
+fn cli_admin_check() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = BitcaskEngine::open(temp_dir.path()).unwrap();
+    for i in 0..10 {
+        store.set(format!("key{i}"), format!("value{i}")).unwrap();
+    }
+    drop(store);
+
+    Command::cargo_bin("hobbes-admin")
+        .unwrap()
+        .arg("check")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("records: 10 (live: 10, dead: 0, tombstones: 0)"))
+        .stdout(contains("store is healthy"));
+
+    let log_path = temp_dir.path().join("bitcask-store/logs/1.db");
+    let mut log = fs::read(&log_path).unwrap();
+    let size = log.len();
+    log.extend_from_slice(&[0xc1; 8]);
+    fs::write(&log_path, &log).unwrap();
+
+    Command::cargo_bin("hobbes-admin")
+        .unwrap()
+        .arg("check")
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stdout(contains(format!(
+            "corrupt record at offset {size} leaving 8 bytes unreadable"
+        )))
+        .stderr(contains("--repair"));
+
+    Command::cargo_bin("hobbes-admin")
+        .unwrap()
+        .args(&["check", "--repair"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("repaired"));
+    assert_eq!(fs::metadata(&log_path).unwrap().len(), size as u64);
+}
+
+// `hobbes-admin dump` should print every record of a segment, filtered by prefix and time
+#[test]
+fn cli_admin_dump() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = BitcaskEngine::open(temp_dir.path()).unwrap();
+    store.set("user:1".to_owned(), "alice".to_owned()).unwrap();
+    store.set("order:1".to_owned(), "book".to_owned()).unwrap();
+    store.remove("user:1".to_owned()).unwrap();
+    drop(store);
+    let segment = temp_dir.path().join("bitcask-store/logs/1.db");
+    // The first record follows the segment header
+    let first_offset = SegmentReader::open(&segment, None).unwrap().offset();
+
+    Command::cargo_bin("hobbes-admin")
+        .unwrap()
+        .arg("dump")
+        .arg(&segment)
+        .assert()
+        .success()
+        .stdout(contains(format!("offset={first_offset} size=")))
+        .stdout(contains("key=\"user:1\" value=\"alice\""))
+        .stdout(contains("key=\"order:1\" value=\"book\""))
+        .stdout(contains("key=\"user:1\" tombstone"));
+
+    Command::cargo_bin("hobbes-admin")
+        .unwrap()
+        .args(&["dump", "--json", "--prefix", "user:"])
+        .arg(&segment)
+        .assert()
+        .success()
+        .stdout(contains("\"key\":\"user:1\",\"value\":\"alice\""))
+        .stdout(contains("\"key\":\"user:1\",\"value\":null"))
+        .stdout(contains("order:1").not());
+
+    Command::cargo_bin("hobbes-admin")
+        .unwrap()
+        .args(&["dump", "--since", "2999-01-01T00:00:00Z"])
+        .arg(&segment)
+        .assert()
+        .success()
+        .stdout(is_empty());
+}
+
+// `hobbes export` should write pairs that `hobbes import` stores on another server
+#[test]
+fn cli_export_import() {
+    let (src_addr, dst_addr) = ("127.0.0.1:4024", "127.0.0.1:4025");
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+
+    let mut servers = Vec::new();
+    for addr in [src_addr, dst_addr] {
+        let server_dir = temp_dir.path().join(addr.replace(':', "_"));
+        fs::create_dir_all(&server_dir).unwrap();
+        servers.push(
+            Command::cargo_bin("hobbes-server")
+                .unwrap()
+                .args(&["--addr", addr])
+                .current_dir(&server_dir)
+                .spawn()
+                .unwrap(),
+        );
+    }
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        for mut server in servers {
+            server.kill().expect("server exited before killed");
+            server.wait().expect("failed to wait on server process");
+        }
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let src = Client::new(src_addr);
+    for i in 0..25 {
+        src.set(&format!("user:{i}"), &format!("value {i}, \"quoted\""))
+            .unwrap();
+    }
+    src.set("order:1", "book").unwrap();
+
+    for file in ["users.jsonl", "users.csv"] {
+        let path = temp_dir.path().join(file);
+        Command::cargo_bin("hobbes")
+            .unwrap()
+            .args(&[
+                "--addr", src_addr, "export", "--prefix", "user:", "--base64",
+            ])
+            .arg(&path)
+            .assert()
+            .success()
+            .stderr(contains("exported 25 pairs"));
+        Command::cargo_bin("hobbes")
+            .unwrap()
+            .args(&["--addr", dst_addr, "import", "--batch-size", "10"])
+            .arg(&path)
+            .assert()
+            .success()
+            .stderr(contains("imported 25 pairs in total"));
+    }
+
+    let dst = Client::new(dst_addr);
+    assert_eq!(dst.scan("").unwrap().len(), 25);
+    assert_eq!(
+        dst.get("user:7").unwrap(),
+        Some(String::from("value 7, \"quoted\""))
+    );
+
+    // Values holding CRLF or surrounding whitespace should survive the round trip unchanged
+    let binary_pairs = vec![
+        (String::from("binary:1"), String::from("a\r\nb")),
+        (String::from("binary:2"), String::from("  padded\t")),
+        (String::from("binary:3"), String::from("\r\n")),
+    ];
+    let binary_path = temp_dir.path().join("binary.jsonl");
+    fs::write(
+        &binary_path,
+        "{\"key\":\"binary:1\",\"value_base64\":\"YQ0KYg==\"}\n\
+         {\"key\":\"binary:2\",\"value\":\"  padded\\t\"}\n\
+         {\"key\":\"binary:3\",\"value_base64\":\"DQo=\"}\n",
+    )
+    .unwrap();
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", src_addr, "import"])
+        .arg(&binary_path)
+        .assert()
+        .success()
+        .stderr(contains("imported 3 pairs in total"));
+    assert_eq!(src.scan("binary:").unwrap(), binary_pairs);
+
+    for file in ["binary-export.jsonl", "binary-export.csv"] {
+        let path = temp_dir.path().join(file);
+        Command::cargo_bin("hobbes")
+            .unwrap()
+            .args(&["--addr", src_addr, "export", "--prefix", "binary:"])
+            .arg(&path)
+            .assert()
+            .success();
+        Command::cargo_bin("hobbes")
+            .unwrap()
+            .args(&["--addr", dst_addr, "import"])
+            .arg(&path)
+            .assert()
+            .success()
+            .stderr(contains("imported 3 pairs in total"));
+        assert_eq!(dst.scan("binary:").unwrap(), binary_pairs);
+        assert_eq!(dst.get("binary:1").unwrap(), Some(String::from("a\r\nb")));
+    }
+
+    // Exports larger than one page should be fetched a page at a time without losing pairs
+    let bulk_pairs = (0..2500)
+        .map(|i| (format!("bulk:{i:04}"), format!("value {i}")))
+        .collect::<Vec<_>>();
+    src.set_many(&bulk_pairs).unwrap();
+    let bulk_path = temp_dir.path().join("bulk.jsonl");
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", src_addr, "export", "--prefix", "bulk:"])
+        .arg(&bulk_path)
+        .assert()
+        .success()
+        .stderr(contains("exported 2500 pairs"));
+    assert_eq!(
+        fs::read_to_string(&bulk_path).unwrap().lines().count(),
+        2500
+    );
+
+    // SET and MSET should both trim keys and keep values as sent, so an import overwrites
+    // the key written by SET instead of storing a second, padded one
+    src.set("  spaced  ", "  set value  ").unwrap();
+    assert_eq!(
+        src.get("spaced").unwrap(),
+        Some(String::from("  set value  "))
+    );
+    let spaced_path = temp_dir.path().join("spaced.jsonl");
+    fs::write(
+        &spaced_path,
+        "{\"key\":\"  spaced  \",\"value\":\"  imported  \"}\n",
+    )
+    .unwrap();
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", src_addr, "import"])
+        .arg(&spaced_path)
+        .assert()
+        .success();
+    assert_eq!(
+        src.scan("spaced").unwrap(),
+        vec![(String::from("spaced"), String::from("  imported  "))]
+    );
+    assert!(src
+        .scan("")
+        .unwrap()
+        .iter()
+        .all(|(key, _)| key.trim() == key));
+
+    let malformed_path = temp_dir.path().join("malformed.txt");
+    fs::write(&malformed_path, "key,value\nuser:1\n").unwrap();
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", dst_addr, "import", "--format", "csv"])
+        .arg(&malformed_path)
+        .assert()
+        .failure()
+        .stderr(contains("line 2"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `hobbes compact` should merge every sealed log of a server whose compaction window keeps
+// merges from starting on their own
+#[test]
+fn cli_compact() {
+    let addr = "127.0.0.1:4026";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+
+    // A window of a minute starting an hour from now
+    let start = chrono::Local::now().time() + chrono::Duration::hours(1);
+    let window = format!(
+        "{}-{}",
+        start.format("%H:%M"),
+        (start + chrono::Duration::minutes(1)).format("%H:%M")
+    );
+    let mut server = Command::cargo_bin("hobbes-server")
+        .unwrap()
+        .args(&["--addr", addr, "--segment-size", "1024"])
+        .args(&[
+            "--compaction-threshold",
+            "1024",
+            "--compaction-window",
+            &window,
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        server.kill().expect("server exited before killed");
+        server.wait().expect("failed to wait on server process");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let client = Client::new(addr);
+    for i in 0..200 {
+        client.set("key", &format!("value{i}")).unwrap();
+    }
+    client.set("removed", "value").unwrap();
+    client.remove("removed").unwrap();
+    let stats = client.info().unwrap().engine;
+    assert_eq!(stats.compactions, 0);
+    assert!(stats.segments > 5);
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", addr, "compact"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    let stats = client.info().unwrap().engine;
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.segments, 1);
+    assert_eq!(client.get("key").unwrap(), Some(String::from("value199")));
+    assert_eq!(client.get("removed").unwrap(), None);
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `hobbes-admin import` should store every pair of a file in a store on disk
+#[test]
+fn cli_admin_import() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let pairs_path = temp_dir.path().join("pairs.jsonl");
+    fs::write(
+        &pairs_path,
+        "{\"key\":\"user:1\",\"value\":\"alice\"}\n\n{\"key\":\"user:2\",\"value_base64\":\"Ym9i\"}\n",
+    )
+    .unwrap();
+
+    Command::cargo_bin("hobbes-admin")
+        .unwrap()
+        .args(&["import", "--dir"])
+        .arg(&data_dir)
+        .arg(&pairs_path)
+        .assert()
+        .success()
+        .stdout(contains("imported 2 pairs"));
+
+    let store = BitcaskEngine::open(&data_dir).unwrap();
+    assert_eq!(
+        store.get("user:1".to_owned()).unwrap(),
+        Some("alice".to_owned())
+    );
+    assert_eq!(
+        store.get("user:2".to_owned()).unwrap(),
+        Some("bob".to_owned())
+    );
+    drop(store);
+
+    fs::write(&pairs_path, "{\"key\":\"user:3\"}\n").unwrap();
+    Command::cargo_bin("hobbes-admin")
+        .unwrap()
+        .args(&["import", "--dir"])
+        .arg(&data_dir)
+        .arg(&pairs_path)
+        .assert()
+        .failure()
+        .stderr(contains("Import Error: line 1"));
+
+    // Stores configured in hobbes.toml are written with its compression and encryption key
+    let key = Keyring::generate_key();
+    fs::write(temp_dir.path().join("keys"), &key).unwrap();
+    fs::write(
+        temp_dir.path().join("hobbes.toml"),
+        "[bitcask]\ncompression = \"lz4\"\nencryption_key_file = \"keys\"\n",
+    )
+    .unwrap();
+    fs::write(&pairs_path, "{\"key\":\"user:1\",\"value\":\"alice\"}\n").unwrap();
+    Command::cargo_bin("hobbes-admin")
+        .unwrap()
+        .args(&["import", "--dir", "encrypted", "pairs.jsonl"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("imported 1 pairs"));
+
+    let encrypted_dir = temp_dir.path().join("encrypted");
+    let segment = fs::read(encrypted_dir.join("bitcask-store/logs/1.db")).unwrap();
+    assert!(!segment.windows(5).any(|window| window == b"alice"));
+    let store =
+        BitcaskEngine::open_encrypted(&encrypted_dir, Keyring::parse(&key).unwrap()).unwrap();
+    assert_eq!(
+        store.get("user:1".to_owned()).unwrap(),
+        Some("alice".to_owned())
+    );
+    drop(store);
+
+    Command::cargo_bin("hobbes-admin")
+        .unwrap()
+        .args(&["check", "encrypted"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+}
+
+// Keys set through the proxy are spread across the backends and migrated when backends change
+#[test]
+fn cli_proxy() {
+    let proxy_addr = "127.0.0.1:4020";
+    let admin_addr = "127.0.0.1:4032";
+    let backend_addrs = ["127.0.0.1:4021", "127.0.0.1:4022", "127.0.0.1:4023"];
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+
+    let mut children = Vec::new();
+    for (idx, backend_addr) in backend_addrs.iter().enumerate() {
+        let backend_dir = temp_dir.path().join(format!("backend{idx}"));
+        std::fs::create_dir_all(&backend_dir).unwrap();
+        children.push(
+            Command::cargo_bin("hobbes-server")
+                .unwrap()
+                .args(&["--addr", backend_addr])
+                .current_dir(&backend_dir)
+                .spawn()
+                .unwrap(),
+        );
+    }
+    let state_file = temp_dir.path().join("proxy-state.json");
+    let start_proxy = || {
+        Command::cargo_bin("hobbes-proxy")
+            .unwrap()
+            .args(&[
+                "--addr",
+                proxy_addr,
+                "--backends",
+                &backend_addrs[..2].join(","),
+                "--admin-addr",
+                admin_addr,
+                "--state-file",
+            ])
+            .arg(&state_file)
+            .spawn()
+            .unwrap()
+    };
+    let mut proxy_child = start_proxy();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        for mut child in children {
+            child.kill().expect("server exited before killed");
+            child.wait().expect("failed to wait on server process");
+        }
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let proxy = Client::new(proxy_addr);
+    for i in 0..100 {
+        proxy.set(&format!("key{i}"), &format!("value{i}")).unwrap();
+    }
+    let backend_keys = |backend_addr: &str| Client::new(backend_addr).scan("key").unwrap().len();
+    assert!(backend_keys(backend_addrs[0]) > 0);
+    assert!(backend_keys(backend_addrs[1]) > 0);
+    assert_eq!(
+        backend_keys(backend_addrs[0]) + backend_keys(backend_addrs[1]),
+        100
+    );
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", proxy_addr, "get", "key42"])
+        .assert()
+        .success()
+        .stdout("value42\n");
+    assert_eq!(proxy.scan("key").unwrap().len(), 100);
+
+    // INFO sums the backends' statistics, listing each backend
+    let info = proxy.info().unwrap();
+    assert_eq!(info.engine.keys, 100);
+    match info.replication {
+        ReplicationInfo::Proxy { backends } => {
+            assert_eq!(backends.len(), 2);
+            assert_eq!(
+                backends.values().map(|info| info.engine.keys).sum::<u64>(),
+                100
+            );
+        }
+        replication => panic!("unexpected replication info {replication:?}"),
+    }
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", proxy_addr, "info"])
+        .assert()
+        .success()
+        .stdout(contains("role: proxy"))
+        .stdout(contains("keys: 100"));
+
+    // Pages merge the first keys of every backend
+    let mut pages = Vec::new();
+    let mut after = None;
+    loop {
+        let page = proxy.scan_page("key", after.as_deref(), 30).unwrap();
+        let Some((last, _)) = page.last() else {
+            break;
+        };
+        after = Some(last.clone());
+        pages.push(page);
+    }
+    assert_eq!(pages.len(), 4);
+    assert_eq!(pages.concat(), proxy.scan("key").unwrap());
+
+    // Values which read like responses are migrated as any other value
+    proxy.set("tricky:1", "Key not found").unwrap();
+    proxy.set("tricky:2", "").unwrap();
+
+    // Credentials are not forwarded to the backends, so authentication is rejected
+    assert!(matches!(
+        Client::new(proxy_addr)
+            .with_credentials("user", "secret")
+            .get("key1"),
+        Err(HobbesError::AuthError(_))
+    ));
+
+    // Backends are only changed through the admin address
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", proxy_addr, "node", "add", backend_addrs[2]])
+        .assert()
+        .failure()
+        .stderr(contains("admin address"));
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", admin_addr, "node", "add", backend_addrs[2]])
+        .assert()
+        .success()
+        .stdout(contains(format!("Added node {}", backend_addrs[2])));
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", proxy_addr, "node", "ls"])
+        .assert()
+        .success()
+        .stdout(contains(backend_addrs[2]));
+
+    // Migrated keys are removed from their previous owners
+    assert!(backend_keys(backend_addrs[2]) > 0);
+    assert_eq!(
+        backend_addrs
+            .iter()
+            .map(|addr| backend_keys(addr))
+            .sum::<usize>(),
+        100
+    );
+    for i in 0..100 {
+        assert_eq!(
+            proxy.get(&format!("key{i}")).unwrap(),
+            Some(format!("value{i}"))
+        );
+    }
+
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", admin_addr, "node", "rm", backend_addrs[0]])
+        .assert()
+        .success()
+        .stdout(contains(format!("Removed node {}", backend_addrs[0])));
+    for i in 0..100 {
+        assert_eq!(
+            proxy.get(&format!("key{i}")).unwrap(),
+            Some(format!("value{i}"))
+        );
+    }
+    assert_eq!(
+        proxy.scan("tricky:").unwrap(),
+        vec![
+            ("tricky:1".to_owned(), "Key not found".to_owned()),
+            ("tricky:2".to_owned(), String::new())
+        ]
+    );
+    proxy.remove("key42").unwrap();
+    assert_eq!(proxy.get("key42").unwrap(), None);
+
+    // Watches are subscribed on every backend, streaming the changes of each
+    let watch = proxy.watch("watched:").unwrap();
+    for i in 0..10 {
+        proxy.set(&format!("watched:{i}"), "value").unwrap();
+    }
+    let mut keys = watch
+        .take(10)
+        .map(|event| event.unwrap().key)
+        .collect::<Vec<String>>();
+    keys.sort();
+    assert_eq!(
+        keys,
+        (0..10)
+            .map(|i| format!("watched:{i}"))
+            .collect::<Vec<String>>()
+    );
+
+    // The backends are restored from the state file, taking precedence over --backends
+    proxy_child.kill().expect("proxy exited before killed");
+    proxy_child.wait().expect("failed to wait on proxy process");
+    let mut proxy_child = start_proxy();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("hobbes")
+        .unwrap()
+        .args(&["--addr", proxy_addr, "node", "ls"])
+        .assert()
+        .success()
+        .stdout(contains(backend_addrs[2]))
+        .stdout(contains(backend_addrs[0]).not());
+    for i in (0..100).filter(|i| *i != 42) {
+        assert_eq!(
+            proxy.get(&format!("key{i}")).unwrap(),
+            Some(format!("value{i}"))
+        );
+    }
+    proxy_child.kill().expect("proxy exited before killed");
+    proxy_child.wait().expect("failed to wait on proxy process");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
 #[test]
 fn cli_access_server_hobbes_engine() {
     cli_access_server("bitcask", "127.0.0.1:4004");
@@ -1,9 +1,18 @@
-use hobbes::engine::bitcask::BitcaskEngine;
-use hobbes::engine::Engine;
-use hobbes::Result;
+use chrono::NaiveTime;
+use hobbes::engine::bitcask::{
+    self, BitcaskEngine, BitcaskOptions, BulkLoader, Codec, CompactionWindow, Compression, Keyring,
+    SegmentReader,
+};
+use hobbes::engine::sled_engine::SledEngine;
+use hobbes::engine::{Engine, WatchEvent};
+use hobbes::{HobbesError, Result};
 
-use std::sync::{Arc, Barrier};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -84,6 +93,785 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Paging through a scan should visit every matching pair once, in key order
+#[test]
+fn scan_pages() -> Result<()> {
+    fn check_pages(store: &impl Engine) -> Result<()> {
+        for i in 0..25 {
+            store.set(format!("key{i:02}"), format!("value{i}"))?;
+        }
+        store.set("other".to_owned(), "value".to_owned())?;
+        store.remove("key07".to_owned())?;
+
+        let mut pages = Vec::new();
+        let mut after = None;
+        loop {
+            let page = store.scan_page("key".to_owned(), after, 10)?;
+            let Some((last, _)) = page.last() else {
+                break;
+            };
+            after = Some(last.clone());
+            pages.push(page);
+        }
+        assert_eq!(
+            pages.iter().map(Vec::len).collect::<Vec<usize>>(),
+            vec![10, 10, 4]
+        );
+        assert_eq!(pages.concat(), store.scan("key".to_owned())?);
+
+        // Scans starting before the prefix start at the prefix
+        assert_eq!(
+            store.scan_page("other".to_owned(), Some("key".to_owned()), 10)?,
+            vec![("other".to_owned(), "value".to_owned())]
+        );
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_pages(&BitcaskEngine::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_pages(&SledEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.engine, "bitcask");
+    assert_eq!(stats.keys, 1);
+    assert_eq!(stats.segments, 1);
+    assert!(stats.segments_size > 0);
+    assert!(stats.dead_bytes_ratio > 0.5 && stats.dead_bytes_ratio < 1.0);
+    assert_eq!(stats.compactions, 0);
+
+    // Open from disk again and check the rebuilt statistics
+    drop(store);
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    let reopened_stats = store.stats()?;
+    assert_eq!(reopened_stats.keys, 1);
+    assert_eq!(reopened_stats.segments_size, stats.segments_size);
+    assert_eq!(reopened_stats.dead_bytes_ratio, stats.dead_bytes_ratio);
+
+    Ok(())
+}
+
+// The index should hold keys compactly and keep finding them once removed keys are dropped from
+// its memory
+#[test]
+fn keydir_memory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set_many((0..100_000).map(|i| (format!("key{i}"), format!("value{i}"))))?;
+    let stats = store.stats()?;
+    let index_bytes = stats
+        .index_bytes
+        .expect("bitcask should report its index size");
+    assert!(index_bytes / stats.keys < 64);
+
+    let long_key = |i: u32| format!("{i}:{}", "k".repeat(1000));
+    store.set_many((0..2000).map(|i| (long_key(i), i.to_string())))?;
+    let index_bytes = store.stats()?.index_bytes.unwrap();
+    for i in 0..1500 {
+        store.remove(long_key(i))?;
+    }
+    assert!(store.stats()?.index_bytes.unwrap() < index_bytes);
+    for i in 0..2000 {
+        assert_eq!(store.get(long_key(i))?, (i >= 1500).then(|| i.to_string()));
+    }
+    for i in (0..100_000).step_by(997) {
+        assert_eq!(store.get(format!("key{i}"))?, Some(format!("value{i}")));
+    }
+    drop(store);
+
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.keys, 100_500);
+    assert_eq!(store.get(long_key(1999))?, Some("1999".to_owned()));
+
+    Ok(())
+}
+
+// Watchers receive the changes to keys with their prefix, in the order they were applied
+#[test]
+fn watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("user:0".to_owned(), "before".to_owned())?;
+
+    let watcher = store.watch("user:".to_owned())?;
+    store.set("user:1".to_owned(), "value1".to_owned())?;
+    store.set("order:1".to_owned(), "value2".to_owned())?;
+    store.remove("user:0".to_owned())?;
+    // Failed writes are not published
+    assert!(store.remove("user:2".to_owned()).is_err());
+    drop(store);
+
+    let events = watcher.collect::<Vec<WatchEvent>>();
+    assert_eq!(
+        events,
+        vec![
+            WatchEvent {
+                seq: 2,
+                key: "user:1".to_owned(),
+                val: Some("value1".to_owned()),
+            },
+            WatchEvent {
+                seq: 4,
+                key: "user:0".to_owned(),
+                val: None,
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+// Pairs stored in a batch should be readable by the time watchers are told about them
+#[test]
+fn watch_set_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskOptions::new()
+        .segment_size(4096)
+        .open(temp_dir.path())?;
+    let watcher = store.watch("key".to_owned())?;
+
+    let reader = store.clone();
+    let handle = thread::spawn(move || {
+        for event in watcher.take(2000) {
+            assert_eq!(reader.get(event.key).unwrap(), event.val);
+        }
+    });
+    store.set_many((0..2000).map(|i| (format!("key{i}"), format!("value{i}"))))?;
+    handle.join().unwrap();
+
+    Ok(())
+}
+
+// Concatenated contents of every log in the store
+fn logs_contents(dir: &Path) -> Vec<u8> {
+    WalkDir::new(dir)
+        .into_iter()
+        .map(|entry| entry.expect("failed to walk store directory"))
+        .filter(|entry| entry.file_type().is_file())
+        .flat_map(|entry| fs::read(entry.path()).expect("failed to read log"))
+        .collect()
+}
+
+fn dir_listing(dir: &Path) -> Vec<PathBuf> {
+    WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .map(|entry| entry.expect("failed to walk store directory").into_path())
+        .collect()
+}
+
+fn contains_bytes(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+// Values of an encrypted store should not be readable on disk, and should only be readable
+// with the key
+#[test]
+fn encryption_at_rest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = Keyring::generate_key();
+    let store = BitcaskEngine::open_encrypted(temp_dir.path(), Keyring::parse(&key)?)?;
+    store.set("key1".to_owned(), "secret-value1".to_owned())?;
+    store.set("key2".to_owned(), "secret-value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("secret-value1".to_owned())
+    );
+    drop(store);
+
+    let contents = logs_contents(temp_dir.path());
+    assert!(!contents.is_empty());
+    assert!(!contains_bytes(&contents, b"secret-value"));
+    assert!(!contains_bytes(&contents, b"key1"));
+
+    assert!(matches!(
+        BitcaskEngine::open(temp_dir.path()),
+        Err(HobbesError::EncryptionError(_))
+    ));
+    assert!(matches!(
+        BitcaskEngine::open_encrypted(temp_dir.path(), Keyring::parse(&Keyring::generate_key())?),
+        Err(HobbesError::EncryptionError(_))
+    ));
+
+    let store = BitcaskEngine::open_encrypted(temp_dir.path(), Keyring::parse(&key)?)?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("secret-value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Opening a store with a new key, keeping the old key as a retired key, should re-encrypt every
+// record so that the old key is no longer needed
+#[test]
+fn encryption_key_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // Records written before encryption was enabled are encrypted as well
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("plain".to_owned(), "plain-value".to_owned())?;
+    drop(store);
+
+    let old_key = Keyring::generate_key();
+    let store = BitcaskEngine::open_encrypted(temp_dir.path(), Keyring::parse(&old_key)?)?;
+    assert!(!contains_bytes(
+        &logs_contents(temp_dir.path()),
+        b"plain-value"
+    ));
+    for i in 0..100 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    drop(store);
+
+    let new_key = Keyring::generate_key();
+    let store = BitcaskEngine::open_encrypted(
+        temp_dir.path(),
+        Keyring::parse(&format!("{new_key}\n{old_key}"))?,
+    )?;
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+    drop(store);
+
+    let store = BitcaskEngine::open_encrypted(temp_dir.path(), Keyring::parse(&new_key)?)?;
+    assert_eq!(
+        store.get("plain".to_owned())?,
+        Some("plain-value".to_owned())
+    );
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{i}"))?, Some(format!("value{i}")));
+    }
+
+    Ok(())
+}
+
+// Compressed records should take less space on disk, be readable without the codec configured,
+// and be recompressed with another codec by compaction
+#[test]
+fn compression() -> Result<()> {
+    let value = |i: u32| {
+        format!(
+            r#"{{"id":{i},"name":"user{i}","roles":["reader","writer"],"active":true,"bio":"{}"}}"#,
+            "lorem ipsum ".repeat(40)
+        )
+    };
+
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(plain_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{i}"), value(i))?;
+    }
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        BitcaskEngine::open(temp_dir.path())?.with_compression(Compression::new(Codec::Lz4));
+    store.set("small".to_owned(), "value".to_owned())?;
+    for i in 0..100 {
+        store.set(format!("key{i}"), value(i))?;
+    }
+    assert_eq!(store.get("key7".to_owned())?, Some(value(7)));
+    drop(store);
+
+    // Measured over the segments only, as the store metadata is not compressed
+    let compressed_size = logs_contents(&temp_dir.path().join("bitcask-store/logs")).len();
+    assert!(
+        compressed_size * 4 < logs_contents(&plain_dir.path().join("bitcask-store/logs")).len()
+    );
+
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{i}"))?, Some(value(i)));
+    }
+    drop(store);
+
+    // Compaction rewrites every record with the configured codec
+    let store =
+        BitcaskEngine::open(temp_dir.path())?.with_compression(Compression::new(Codec::Zstd));
+    store.compact()?;
+    drop(store);
+    let zstd_magic = [0x28, 0xb5, 0x2f, 0xfd];
+    assert!(contains_bytes(&logs_contents(temp_dir.path()), &zstd_magic));
+
+    // Compression composes with encryption
+    let keyring = Keyring::parse(&Keyring::generate_key())?;
+    let store = BitcaskEngine::open_encrypted(temp_dir.path(), keyring.clone())?
+        .with_compression(Compression::new(Codec::Lz4));
+    store.compact()?;
+    drop(store);
+    let store = BitcaskEngine::open_encrypted(temp_dir.path(), keyring)?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{i}"))?, Some(value(i)));
+    }
+
+    Ok(())
+}
+
+// Values equal to the legacy tombstone should survive a restart, and stores written before
+// segments had headers should be readable as is and rewritten in the current format on open
+#[test]
+fn record_types() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "!tomb!".to_owned())?;
+    drop(store);
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("!tomb!".to_owned()));
+    drop(store);
+
+    // Legacy records are arrays of key, value and timestamp, removing the key if the value is
+    // the tombstone
+    let legacy_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logs_dir = legacy_dir.path().join("bitcask-store/logs");
+    fs::create_dir_all(&logs_dir).expect("failed to create logs directory");
+    let mut legacy_segment = Vec::new();
+    for (key, val) in [("key1", "value1"), ("key2", "value2"), ("key2", "!tomb!")] {
+        legacy_segment.extend(
+            rmp_serde::to_vec(&(key, val, chrono::Local::now())).expect("failed to encode"),
+        );
+    }
+    fs::write(logs_dir.join("1.db"), &legacy_segment).expect("failed to write segment");
+
+    let store = BitcaskEngine::open_read_only(legacy_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+    assert_eq!(
+        fs::read(logs_dir.join("1.db")).expect("failed to read segment"),
+        legacy_segment
+    );
+
+    let store = BitcaskEngine::open(legacy_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "!tomb!".to_owned())?;
+    drop(store);
+    assert!(!logs_dir.join("1.db").exists());
+    for entry in fs::read_dir(&logs_dir)? {
+        let segment = fs::read(entry?.path()).expect("failed to read segment");
+        assert!(segment.starts_with(b"HBSG"));
+    }
+
+    let store = BitcaskEngine::open(legacy_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("!tomb!".to_owned()));
+
+    Ok(())
+}
+
+// Stores should record their format version, keep reading segments of previous versions and
+// refuse segments or stores written in a newer format
+#[test]
+fn format_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db_dir = temp_dir.path().join("bitcask-store");
+    let segment = db_dir.join("logs/1.db");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let metadata: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(db_dir.join("META"))?)?;
+    assert_eq!(metadata["format_version"], 3);
+    let reader = SegmentReader::open(&segment, None)?;
+    assert_eq!(reader.version(), 3);
+    let info = reader.info().expect("segment should hold creation info");
+    assert!(info.created_by.starts_with("hobbes "));
+
+    // Version 2 segments only hold the magic and the version before their records, and stores
+    // of that format have no metadata file
+    let records = fs::read(&segment)?.split_off(reader.offset() as usize);
+    let mut v2_segment = b"HBSG\x02\x00".to_vec();
+    v2_segment.extend(&records);
+    fs::write(&segment, &v2_segment)?;
+    fs::remove_file(db_dir.join("META"))?;
+
+    let store = BitcaskEngine::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert!(!db_dir.join("META").exists());
+
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    assert!(db_dir.join("META").is_file());
+    // Readable segments of previous versions are not rewritten, nor appended to
+    assert_eq!(fs::read(&segment)?, v2_segment);
+    assert_eq!(
+        SegmentReader::open(&db_dir.join("logs/2.db"), None)?.version(),
+        3
+    );
+
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // A segment of a newer version is refused without being modified
+    let mut future_segment = v2_segment.clone();
+    future_segment[4] = 4;
+    fs::write(&segment, &future_segment)?;
+    assert!(matches!(
+        BitcaskEngine::open(temp_dir.path()),
+        Err(HobbesError::UnsupportedFormatError(_))
+    ));
+    assert_eq!(fs::read(&segment)?, future_segment);
+    fs::write(&segment, &v2_segment)?;
+
+    // So is a store whose metadata records a newer version
+    let mut metadata: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(db_dir.join("META"))?)?;
+    metadata["format_version"] = 4.into();
+    fs::write(db_dir.join("META"), metadata.to_string())?;
+    for result in [
+        BitcaskEngine::open(temp_dir.path()),
+        BitcaskEngine::open_read_only(temp_dir.path()),
+    ] {
+        assert!(matches!(
+            result,
+            Err(HobbesError::UnsupportedFormatError(_))
+        ));
+    }
+
+    Ok(())
+}
+
+// A store should not be opened again until every handle to it is dropped
+#[test]
+fn exclusive_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert!(matches!(
+        BitcaskEngine::open(temp_dir.path()),
+        Err(HobbesError::DirectoryLockedError(_))
+    ));
+
+    let handle = store.clone();
+    drop(store);
+    assert!(matches!(
+        BitcaskEngine::open(temp_dir.path()),
+        Err(HobbesError::DirectoryLockedError(_))
+    ));
+    assert_eq!(handle.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(handle);
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A store opened read-only should serve reads, reject writes, and leave its directory untouched
+#[test]
+fn read_only_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+    let contents = logs_contents(temp_dir.path());
+    let listing = dir_listing(temp_dir.path());
+
+    let store = BitcaskEngine::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(
+        store.scan("key".to_owned())?,
+        vec![("key1".to_owned(), "value1".to_owned())]
+    );
+    assert!(matches!(
+        store.set("key3".to_owned(), "value3".to_owned()),
+        Err(HobbesError::ReadOnlyError)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(HobbesError::ReadOnlyError)
+    ));
+    assert!(matches!(store.compact(), Err(HobbesError::ReadOnlyError)));
+
+    // Readers share the store, keeping writers out
+    let reader = BitcaskEngine::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        BitcaskEngine::open(temp_dir.path()),
+        Err(HobbesError::DirectoryLockedError(_))
+    ));
+    drop(store);
+    drop(reader);
+    assert_eq!(logs_contents(temp_dir.path()), contents);
+    assert_eq!(dir_listing(temp_dir.path()), listing);
+
+    // Stores without a lock file are opened without one, leaving the directory unchanged
+    fs::remove_file(temp_dir.path().join("bitcask-store/LOCK"))?;
+    let listing = dir_listing(temp_dir.path());
+    let reader = BitcaskEngine::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(reader);
+    assert_eq!(dir_listing(temp_dir.path()), listing);
+
+    // Missing stores are not created
+    let missing = temp_dir.path().join("missing");
+    assert!(BitcaskEngine::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+
+    Ok(())
+}
+
+// Checking should count records, report a truncated tail with its offset, and repairing should
+// truncate it so the store reopens with every complete record
+#[test]
+fn check_and_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let report = bitcask::check(temp_dir.path(), None, false)?;
+    assert!(report.is_healthy());
+    assert_eq!((report.live, report.dead, report.tombstones), (1, 2, 1));
+
+    // Simulate a crash partway through appending a record
+    let log_path = report.segments[0].path.clone();
+    let size = report.segments[0].size;
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    drop(store);
+    let log = fs::read(&log_path).expect("failed to read log");
+    fs::write(&log_path, &log[..log.len() - 3]).expect("failed to truncate log");
+
+    let report = bitcask::check(temp_dir.path(), None, false)?;
+    assert!(!report.is_healthy());
+    let problem = report.segments[0].problem.clone().unwrap();
+    assert!(problem.truncated);
+    assert_eq!(problem.offset, size);
+    assert_eq!(fs::metadata(&log_path)?.len(), log.len() as u64 - 3);
+
+    let report = bitcask::check(temp_dir.path(), None, true)?;
+    assert!(report.repaired);
+    assert_eq!(fs::metadata(&log_path)?.len(), size);
+    assert!(bitcask::check(temp_dir.path(), None, false)?.is_healthy());
+
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// A bulk load should write hinted segments which open into a store holding the last value of
+// each key, and should refuse directories already holding a store
+#[test]
+fn bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut loader = BulkLoader::create(
+        temp_dir.path(),
+        BitcaskOptions::new().segment_size(4 * 1024),
+    )?;
+    loader.add_all(
+        (0..1000)
+            .rev()
+            .map(|i| (format!("key{i}"), format!("value{i}"))),
+    )?;
+    loader.add("key7".to_owned(), "updated".to_owned())?;
+    let report = loader.finish()?;
+    assert_eq!((report.pairs, report.keys), (1001, 1000));
+    assert!(report.segments > 1);
+
+    let logs_dir = temp_dir.path().join("bitcask-store/logs");
+    for log_id in 1..=report.segments {
+        assert!(logs_dir.join(format!("{log_id}.hint")).is_file());
+    }
+    let active_log = logs_dir.join(format!("{}.db", report.segments + 1));
+    // The active log holds only its segment header
+    let mut active_reader = SegmentReader::open(&active_log, None)?;
+    assert_eq!(active_reader.offset(), fs::metadata(&active_log)?.len());
+    assert!(active_reader.next_record()?.is_none());
+    assert!(temp_dir.path().join("bitcask-store/META").is_file());
+
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.segments, report.segments + 1);
+    assert_eq!(store.get("key7".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(store.scan("key".to_owned())?.len(), 1000);
+    store.set("key1000".to_owned(), "value1000".to_owned())?;
+    drop(store);
+
+    // Unusable hints fall back to replaying their segment
+    fs::write(logs_dir.join("1.hint"), b"not a hint").expect("failed to overwrite hint");
+    let store = BitcaskEngine::open(temp_dir.path())?;
+    for i in 0..=1000 {
+        let expected = if i == 7 {
+            "updated".to_owned()
+        } else {
+            format!("value{i}")
+        };
+        assert_eq!(store.get(format!("key{i}"))?, Some(expected));
+    }
+    drop(store);
+    assert!(bitcask::check(temp_dir.path(), None, false)?.is_healthy());
+
+    assert!(matches!(
+        BulkLoader::create(temp_dir.path(), BitcaskOptions::new()),
+        Err(HobbesError::CliError(_))
+    ));
+
+    Ok(())
+}
+
+// The active log should be sealed at the segment size without compacting the store
+#[test]
+fn segment_rollover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions::new().segment_size(1024);
+    let store = options.open(temp_dir.path())?;
+    for i in 0..200 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    store.set_many((200..400).map(|i| (format!("key{i}"), format!("value{i}"))))?;
+
+    let stats = store.stats()?;
+    assert!(stats.segments > 10);
+    assert_eq!(stats.compactions, 0);
+    for entry in fs::read_dir(temp_dir.path().join("bitcask-store/logs"))? {
+        // A segment is sealed by the record taking it past the segment size
+        assert!(entry?.metadata()?.len() < 2 * 1024);
+    }
+    drop(store);
+
+    let store = options.open(temp_dir.path())?;
+    for i in 0..400 {
+        assert_eq!(store.get(format!("key{i}"))?, Some(format!("value{i}")));
+    }
+
+    Ok(())
+}
+
+// Merges should rewrite only segments that are mostly dead records, keeping the tombstones of
+// removed keys whose older records remain in unmerged segments
+#[test]
+fn merge_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BitcaskOptions::new()
+        .segment_size(2 * 1024)
+        .compaction_threshold(1024)
+        .merge_dead_ratio(0.5);
+    let store = options.open(temp_dir.path())?;
+
+    // Fill the first segment with keys which stay live
+    let mut i = 0;
+    while store.stats()?.segments < 2 {
+        store.set(format!("cold{i}"), format!("value{i}"))?;
+        i += 1;
+    }
+    let cold_keys = i;
+    let cold_segment = temp_dir.path().join("bitcask-store/logs/1.db");
+    let cold_contents = fs::read(&cold_segment).expect("failed to read segment");
+
+    for i in 0..10 {
+        store.remove(format!("cold{i}"))?;
+    }
+    for i in 0..1000 {
+        store.set("hot".to_owned(), format!("value{i}"))?;
+    }
+
+    let stats = store.stats()?;
+    assert!(stats.compactions > 0);
+    assert!(stats.segments < 10);
+    assert_eq!(
+        fs::read(&cold_segment).expect("failed to read segment"),
+        cold_contents
+    );
+    drop(store);
+
+    let store = options.open(temp_dir.path())?;
+    for i in 0..cold_keys {
+        let expected = (i >= 10).then(|| format!("value{i}"));
+        assert_eq!(store.get(format!("cold{i}"))?, expected);
+    }
+    assert_eq!(store.get("hot".to_owned())?, Some("value999".to_owned()));
+    drop(store);
+    assert!(bitcask::check(temp_dir.path(), None, false)?.is_healthy());
+
+    Ok(())
+}
+
+// Sealing segments outside the compaction window should not start a merge, while compacting
+// on demand should merge every sealed segment at the rate limit and drop the tombstones
+#[test]
+fn compaction_window() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let start = chrono::Local::now().time() + chrono::Duration::hours(1);
+    let window = CompactionWindow::new(start, start + chrono::Duration::minutes(1));
+    let options = BitcaskOptions::new()
+        .segment_size(1024)
+        .compaction_threshold(1024)
+        .compaction_window(window)
+        .compaction_rate_limit(10_000);
+    let store = options.open(temp_dir.path())?;
+
+    for i in 0..200 {
+        store.set("hot".to_owned(), format!("value{i}"))?;
+    }
+    for i in 0..100 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    for i in 0..50 {
+        store.remove(format!("key{i}"))?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 0);
+    assert!(stats.segments > 10);
+
+    let compaction_start = Instant::now();
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 1);
+    assert!(stats.segments < 5);
+    assert_eq!(stats.dead_bytes_ratio, 0.0);
+    // Copying the live records is paced to the rate limit
+    assert!(
+        compaction_start.elapsed()
+            >= Duration::from_millis(stats.segments_size * 1000 / 10_000 * 9 / 10)
+    );
+    drop(store);
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("hot".to_owned())?, Some("value199".to_owned()));
+    for i in 0..100 {
+        let expected = (i >= 50).then(|| format!("value{i}"));
+        assert_eq!(store.get(format!("key{i}"))?, expected);
+    }
+
+    let overnight = "22:00-02:00".parse::<CompactionWindow>()?;
+    assert!(overnight.contains(NaiveTime::from_hms_opt(0, 30, 0).unwrap()));
+    assert!(!overnight.contains(NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+    assert!("01:00".parse::<CompactionWindow>().is_err());
+
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -130,22 +918,108 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Reads should keep returning the values of keys while the segments holding them are sealed,
+// mapped and merged away by concurrent writes
+#[test]
+fn concurrent_get_during_merges() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskOptions::new()
+        .segment_size(1024)
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+    for i in 0..200 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..2000 {
+                let key_id = (i * 7 + thread_id) % 200;
+                assert_eq!(
+                    store.get(format!("key{key_id}")).unwrap(),
+                    Some(format!("value{key_id}"))
+                );
+            }
+        }));
+    }
+    for i in 0..2000 {
+        store.set("hot".to_owned(), format!("value{i}"))?;
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert!(store.stats()?.compactions > 0);
+    assert_eq!(store.get("hot".to_owned())?, Some("value1999".to_owned()));
+
+    Ok(())
+}
+
+// Reads should not wait for writes, and should only see pairs of a batch once their records can
+// be read back
+#[test]
+fn concurrent_get_during_set_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskOptions::new()
+        .segment_size(64 * 1024)
+        .open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let mut handles = Vec::new();
+    for thread_id in 0..16 {
+        let store = store.clone();
+        let done = done.clone();
+        handles.push(thread::spawn(move || {
+            let mut i = thread_id;
+            while !done.load(Ordering::Relaxed) {
+                let key_id = i % 100;
+                assert_eq!(
+                    store.get(format!("key{key_id}")).unwrap(),
+                    Some(format!("value{key_id}"))
+                );
+                let batch_id = i % 20_000;
+                if let Some(val) = store.get(format!("batch{batch_id}")).unwrap() {
+                    assert_eq!(val, format!("value{batch_id}"));
+                }
+                i += 7;
+            }
+        }));
+    }
+    for chunk in 0..4 {
+        store.set_many(
+            (chunk * 5000..(chunk + 1) * 5000).map(|i| (format!("batch{i}"), format!("value{i}"))),
+        )?;
+    }
+    done.store(true, Ordering::Relaxed);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.stats()?.keys, 20_100);
+
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = BitcaskEngine::open(temp_dir.path())?;
-    let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
-        let barrier = barrier.clone();
-        thread::spawn(move || {
+        handles.push(thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
-            barrier.wait();
-        });
+        }));
+    }
+    // Joined rather than waited on, so that every handle is dropped before the store is reopened
+    for handle in handles {
+        handle.join().unwrap();
     }
-    barrier.wait();
 
     for i in 0..1000 {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
@@ -212,3 +1086,55 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Reads racing a merge should follow records to the merged segments, as a writer keeps moving
+// them to the active log and compactions keep merging the segments they were read from
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitcaskOptions::new()
+        .segment_size(2048)
+        .open(temp_dir.path())?;
+    store.set_many((0..500).map(|i| (format!("key{i}"), format!("value{i}"))))?;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let mut handles = Vec::new();
+    for thread_id in 0..64 {
+        let store = store.clone();
+        let done = done.clone();
+        handles.push(thread::spawn(move || {
+            let mut i = thread_id;
+            while !done.load(Ordering::Relaxed) {
+                let key_id = i % 500;
+                assert_eq!(
+                    store.get(format!("key{key_id}")).unwrap(),
+                    Some(format!("value{key_id}"))
+                );
+                i += 13;
+            }
+        }));
+    }
+    // Rewriting the same values moves the records without changing what reads return
+    let writer = {
+        let store = store.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut i = 0;
+            while !done.load(Ordering::Relaxed) {
+                store.set(format!("key{i}"), format!("value{i}")).unwrap();
+                i = (i + 7) % 500;
+            }
+        })
+    };
+    for _ in 0..50 {
+        store.compact()?;
+    }
+    done.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.stats()?.keys, 500);
+
+    Ok(())
+}